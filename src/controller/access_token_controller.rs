@@ -0,0 +1,155 @@
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::{
+        access_token_model::PersonalAccessToken,
+        audit_model::{AuditAction, AuditEvent},
+        user_model::Permission,
+    },
+    service::{
+        access_token_service::{AccessTokenService, MAX_TOKEN_LIFETIME_DAYS},
+        audit_service::AuditService,
+    },
+    utils::error::CustomError,
+    utils::request::RequestContext,
+};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use mongodb::bson::oid::ObjectId;
+
+#[derive(serde::Deserialize)]
+pub struct CreateAccessTokenRequest {
+    name: String,
+    scopes: Vec<Permission>,
+    expires_in_days: Option<i64>,
+}
+
+fn token_json(access_token: &PersonalAccessToken) -> serde_json::Value {
+    serde_json::json!({
+        "id": access_token.id.map(|id| id.to_hex()),
+        "name": access_token.name,
+        "scopes": access_token.scopes,
+        "expires_at": access_token.expires_at.map(|d| d.try_to_rfc3339_string().unwrap_or_default()),
+        "created_at": access_token.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        "last_used_at": access_token.last_used_at.map(|d| d.try_to_rfc3339_string().unwrap_or_default()),
+    })
+}
+
+pub async fn create_token(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    access_token_service: web::Data<AccessTokenService>,
+    audit_service: web::Data<AuditService>,
+    token_info: web::Json<CreateAccessTokenRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let token_info = token_info.into_inner();
+
+    if token_info.name.trim().is_empty() {
+        return CustomError::ValidationError("Token name is required".to_string()).error_response();
+    }
+    if token_info.scopes.is_empty() {
+        return CustomError::ValidationError("At least one scope is required".to_string())
+            .error_response();
+    }
+    let lifetime_days = 1..=MAX_TOKEN_LIFETIME_DAYS;
+    if matches!(token_info.expires_in_days, Some(days) if !lifetime_days.contains(&days)) {
+        return CustomError::ValidationError(format!(
+            "expires_in_days must be between 1 and {}",
+            MAX_TOKEN_LIFETIME_DAYS
+        ))
+        .error_response();
+    }
+    if let Some(scope) = token_info
+        .scopes
+        .iter()
+        .find(|scope| !user.has_permission(**scope))
+    {
+        return CustomError::ForbiddenError(format!(
+            "You cannot grant a scope you do not hold: {}",
+            serde_json::to_string(scope).unwrap_or_default()
+        ))
+        .error_response();
+    }
+
+    let result = access_token_service
+        .create_token(
+            user.id,
+            token_info.name,
+            token_info.scopes,
+            token_info.expires_in_days,
+        )
+        .await;
+
+    let event = AuditEvent::new(AuditAction::AccessTokenCreated, &ctx.with_actor(user.id))
+        .target(Some(user.id));
+    audit_service.record(event.outcome(&result)).await;
+
+    match result {
+        Ok((access_token, plaintext)) => {
+            let mut body = token_json(&access_token);
+            body["token"] = serde_json::Value::String(plaintext);
+            HttpResponse::Created().json(body)
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn list_tokens(
+    user: AuthenticatedUser,
+    access_token_service: web::Data<AccessTokenService>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match access_token_service.list_tokens(user.id).await {
+        Ok(tokens) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "tokens": tokens.iter().map(token_json).collect::<Vec<_>>()
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn revoke_token(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    access_token_service: web::Data<AccessTokenService>,
+    audit_service: web::Data<AuditService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => {
+            return CustomError::BadRequestError("Invalid token ID format".to_string())
+                .error_response()
+        }
+    };
+
+    let result = access_token_service.revoke_token(user.id, id).await;
+
+    let event = AuditEvent::new(AuditAction::AccessTokenRevoked, &ctx.with_actor(user.id))
+        .target(Some(user.id))
+        .subject(&id.to_hex());
+    let event = match &result {
+        Ok(true) => event,
+        Ok(false) => event.failure("Token not found".to_string()),
+        Err(e) => event.failure(e.to_string()),
+    };
+    audit_service.record(event).await;
+
+    match result {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Token revoked successfully"
+        })),
+        Ok(false) => CustomError::NotFoundError("Token not found".to_string()).error_response(),
+        Err(e) => e.error_response(),
+    }
+}
@@ -0,0 +1,527 @@
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::{
+        audit_model::{AuditAction, AuditEvent, AuditOutcome},
+        login_attempt_model::LockoutEvent,
+        user_model::{Role, User, UserProfile},
+    },
+    service::{
+        audit_service::{AuditFilter, AuditService},
+        user_service::UserService,
+    },
+    utils::error::CustomError,
+    utils::request::RequestContext,
+};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use futures::{stream, StreamExt};
+use mongodb::bson::{oid::ObjectId, DateTime};
+
+#[derive(serde::Deserialize)]
+pub struct SetRolesRequest {
+    roles: Vec<Role>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct LockoutQuery {
+    key: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnlockRequest {
+    key: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ListUsersQuery {
+    q: Option<String>,
+    suspended: Option<bool>,
+    page: Option<u64>,
+    per_page: Option<i64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SuspendRequest {
+    reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AuditQuery {
+    action: Option<AuditAction>,
+    outcome: Option<AuditOutcome>,
+    actor_id: Option<String>,
+    target_id: Option<String>,
+    ip: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<u64>,
+    per_page: Option<i64>,
+    format: Option<String>,
+}
+
+impl AuditQuery {
+    fn to_filter(&self) -> Result<AuditFilter, CustomError> {
+        let object_id = |id: &Option<String>| {
+            id.as_deref()
+                .map(ObjectId::parse_str)
+                .transpose()
+                .map_err(|_| CustomError::BadRequestError("Invalid ID format".to_string()))
+        };
+        let timestamp = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(DateTime::parse_rfc3339_str)
+                .transpose()
+                .map_err(|_| {
+                    CustomError::BadRequestError("Timestamps must be RFC 3339".to_string())
+                })
+        };
+
+        Ok(AuditFilter {
+            action: self.action,
+            outcome: self.outcome,
+            actor_id: object_id(&self.actor_id)?,
+            target_id: object_id(&self.target_id)?,
+            ip: self.ip.clone(),
+            from: timestamp(&self.from)?,
+            to: timestamp(&self.to)?,
+        })
+    }
+}
+
+fn parse_user_id(id: &str) -> Result<ObjectId, CustomError> {
+    ObjectId::parse_str(id)
+        .map_err(|_| CustomError::BadRequestError("Invalid user ID format".to_string()))
+}
+
+// Guards against an administrator locking themselves out. Since nobody can
+// demote themselves, the last administrator always keeps the role.
+fn reject_self(admin: &AuthenticatedUser, user_id: ObjectId) -> Result<(), CustomError> {
+    if admin.id == user_id {
+        return Err(CustomError::BadRequestError(
+            "Administrators cannot do this to their own account".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn admin_user_json(user: &User) -> serde_json::Value {
+    let mut json = serde_json::json!(UserProfile::from(user));
+    json["suspended"] = serde_json::json!(user.suspended);
+    json["suspended_reason"] = serde_json::json!(user.suspended_reason);
+    json["password_reset_required"] = serde_json::json!(user.password_reset_required);
+    json["deleted_at"] = serde_json::json!(user
+        .deleted_at
+        .map(|d| d.try_to_rfc3339_string().unwrap_or_default()));
+    json
+}
+
+fn lockout_json(event: &LockoutEvent) -> serde_json::Value {
+    serde_json::json!({
+        "key": event.key,
+        "action": event.action,
+        "failures": event.failures,
+        "locked_until": event.locked_until.map(|d| d.try_to_rfc3339_string().unwrap_or_default()),
+        "actor_id": event.actor_id.map(|id| id.to_hex()),
+        "created_at": event.created_at.try_to_rfc3339_string().unwrap_or_default(),
+    })
+}
+
+fn audit_json(event: &AuditEvent) -> serde_json::Value {
+    serde_json::json!({
+        "id": event.id.map(|id| id.to_hex()),
+        "action": event.action,
+        "outcome": event.outcome,
+        "actor_id": event.actor_id.map(|id| id.to_hex()),
+        "target_id": event.target_id.map(|id| id.to_hex()),
+        "subject": event.subject,
+        "ip": event.ip,
+        "user_agent": event.user_agent,
+        "reason": event.reason,
+        "created_at": event.created_at.try_to_rfc3339_string().unwrap_or_default(),
+    })
+}
+
+const AUDIT_CSV_COLUMNS: [&str; 10] = [
+    "id",
+    "action",
+    "outcome",
+    "actor_id",
+    "target_id",
+    "subject",
+    "ip",
+    "user_agent",
+    "reason",
+    "created_at",
+];
+
+// Quotes a value for CSV. Text that a spreadsheet would evaluate as a
+// formula is prefixed with `'`, since most fields are attacker-controlled.
+fn csv_field(value: &serde_json::Value) -> String {
+    let text = match value {
+        serde_json::Value::Null => return String::new(),
+        serde_json::Value::String(s) if s.starts_with(['=', '+', '-', '@', '\t', '\r']) => {
+            format!("'{}", s)
+        }
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn audit_csv_row(event: &AuditEvent) -> String {
+    let json = audit_json(event);
+    let fields: Vec<String> = AUDIT_CSV_COLUMNS
+        .iter()
+        .map(|column| csv_field(&json[column]))
+        .collect();
+    format!("{}\n", fields.join(","))
+}
+
+pub async fn set_user_roles(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+    roles_info: web::Json<SetRolesRequest>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path).and_then(|id| reject_self(&admin, id).map(|_| id)) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    if roles_info.roles.is_empty() {
+        return CustomError::ValidationError("A user needs at least one role".to_string())
+            .error_response();
+    }
+
+    match user_service
+        .set_roles(
+            user_id,
+            roles_info.into_inner().roles,
+            &ctx.with_actor(admin.id),
+        )
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Roles updated successfully"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn list_lockouts(
+    user_service: web::Data<UserService>,
+    query: web::Query<LockoutQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
+
+    match user_service
+        .lockout_events(query.key.as_deref(), limit)
+        .await
+    {
+        Ok(events) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "events": events.iter().map(lockout_json).collect::<Vec<_>>()
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn unlock_login(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    unlock_info: web::Json<UnlockRequest>,
+) -> impl Responder {
+    match user_service
+        .unlock_login(&unlock_info.key, &ctx.with_actor(admin.id))
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Login lockout lifted"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn list_users(
+    user_service: web::Data<UserService>,
+    query: web::Query<ListUsersQuery>,
+) -> impl Responder {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(20).clamp(1, 100);
+
+    match user_service
+        .list_users(query.q.as_deref(), query.suspended, page, per_page)
+        .await
+    {
+        Ok((users, total)) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "users": users.iter().map(admin_user_json).collect::<Vec<_>>(),
+            "page": page,
+            "per_page": per_page,
+            "total": total
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn get_user(
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match user_service.find_by_id(user_id).await {
+        Ok(user) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "user": admin_user_json(&user)
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn suspend_user(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+    suspend_info: Option<web::Json<SuspendRequest>>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path).and_then(|id| reject_self(&admin, id).map(|_| id)) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+    let reason = suspend_info.and_then(|info| info.into_inner().reason);
+
+    match user_service
+        .set_suspended(user_id, true, reason, &ctx.with_actor(admin.id))
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "User suspended"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn unsuspend_user(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match user_service
+        .set_suspended(user_id, false, None, &ctx.with_actor(admin.id))
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "User unsuspended"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn force_password_reset(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path).and_then(|id| reject_self(&admin, id).map(|_| id)) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match user_service
+        .force_password_reset(user_id, &ctx.with_actor(admin.id))
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Password reset required and email sent"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn revoke_user_sessions(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match user_service
+        .logout_all_fn(user_id, &ctx.with_actor(admin.id))
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "All sessions revoked"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn delete_user(
+    admin: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match parse_user_id(&path).and_then(|id| reject_self(&admin, id).map(|_| id)) {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match user_service
+        .delete_user(user_id, &ctx.with_actor(admin.id))
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "User deleted"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn list_audit_events(
+    audit_service: web::Data<AuditService>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let filter = match query.to_filter() {
+        Ok(filter) => filter,
+        Err(e) => return e.error_response(),
+    };
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 500);
+
+    match audit_service.query(&filter, page, per_page).await {
+        Ok((events, total)) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "events": events.iter().map(audit_json).collect::<Vec<_>>(),
+            "page": page,
+            "per_page": per_page,
+            "total": total
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Streams every matching event, oldest first, as NDJSON (default) or CSV.
+pub async fn export_audit_events(
+    audit_service: web::Data<AuditService>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let filter = match query.to_filter() {
+        Ok(filter) => filter,
+        Err(e) => return e.error_response(),
+    };
+    let csv = match query.format.as_deref() {
+        None | Some("ndjson") => false,
+        Some("csv") => true,
+        Some(_) => {
+            return CustomError::BadRequestError("format must be ndjson or csv".to_string())
+                .error_response()
+        }
+    };
+
+    let cursor = match audit_service.export(&filter).await {
+        Ok(cursor) => cursor,
+        Err(e) => return e.error_response(),
+    };
+
+    let rows = cursor.map(move |event| {
+        let event = event
+            .map_err(|e| actix_web::Error::from(CustomError::InternalServerError(e.to_string())))?;
+        let line = if csv {
+            audit_csv_row(&event)
+        } else {
+            format!("{}\n", audit_json(&event))
+        };
+        Ok::<_, actix_web::Error>(web::Bytes::from(line))
+    });
+
+    if csv {
+        let header = web::Bytes::from(format!("{}\n", AUDIT_CSV_COLUMNS.join(",")));
+        HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header(("Content-Disposition", "attachment; filename=\"audit.csv\""))
+            .streaming(stream::once(async { Ok(header) }).chain(rows))
+    } else {
+        HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"audit.ndjson\"",
+            ))
+            .streaming(rows)
+    }
+}
+
+pub async fn verify_audit_chain(audit_service: web::Data<AuditService>) -> impl Responder {
+    match audit_service.verify_chain().await {
+        Ok(report) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "intact": report.broken.is_none(),
+            "report": report
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn csv_field_quotes_separators_and_quotes() {
+        assert_eq!(csv_field(&json!(null)), "");
+        assert_eq!(csv_field(&json!("plain")), "plain");
+        assert_eq!(csv_field(&json!(42)), "42");
+        assert_eq!(csv_field(&json!("a,b")), "\"a,b\"");
+        assert_eq!(csv_field(&json!("say \"hi\"")), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field(&json!("two\nlines")), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn csv_field_defuses_formulas() {
+        for value in ["=1+1", "+1", "-1", "@SUM(A1)", "\tcmd", "\rcmd"] {
+            let field = csv_field(&json!(value));
+            assert!(
+                field.trim_start_matches('"').starts_with('\''),
+                "{:?}",
+                field
+            );
+        }
+        assert_eq!(
+            csv_field(&json!("=HYPERLINK(\"http://x\",\"y\")")),
+            "\"'=HYPERLINK(\"\"http://x\"\",\"\"y\"\")\""
+        );
+        assert_eq!(csv_field(&json!("\rcmd")), "\"'\rcmd\"");
+    }
+}
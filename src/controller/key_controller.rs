@@ -0,0 +1,11 @@
+use crate::utils::jwt;
+use actix_web::{http::header, HttpResponse, Responder, ResponseError};
+
+pub async fn jwks() -> impl Responder {
+    match jwt::jwks() {
+        Ok(jwks) => HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+            .json(jwks),
+        Err(e) => e.error_response(),
+    }
+}
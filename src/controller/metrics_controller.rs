@@ -0,0 +1,8 @@
+use crate::utils::hash_pool;
+use actix_web::{HttpResponse, Responder};
+
+pub async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(hash_pool::metrics_text())
+}
@@ -0,0 +1,118 @@
+use crate::{
+    controller::user_controller::tokens_response, middleware::auth::AuthenticatedUser,
+    service::user_service::UserService, utils::dpop::DpopKey, utils::request::RequestContext,
+};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+
+#[derive(serde::Deserialize)]
+pub struct MfaLoginRequest {
+    mfa_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EnableTotpRequest {
+    code: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DisableTotpRequest {
+    password: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+pub async fn login_mfa(
+    ctx: RequestContext,
+    dpop_key: DpopKey,
+    user_service: web::Data<UserService>,
+    mfa_info: web::Json<MfaLoginRequest>,
+) -> impl Responder {
+    let ctx = ctx.with_dpop_key(dpop_key.0);
+    match user_service
+        .complete_mfa_login(
+            &mfa_info.mfa_token,
+            mfa_info.code.as_deref(),
+            mfa_info.recovery_code.as_deref(),
+            &ctx,
+        )
+        .await
+    {
+        Ok(tokens) => tokens_response(tokens),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn setup_totp(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match user_service
+        .setup_totp(user.id, &ctx.with_actor(user.id))
+        .await
+    {
+        Ok(setup) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "secret": setup.secret,
+            "otpauth_uri": setup.otpauth_uri
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn enable_totp(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    enable_info: web::Json<EnableTotpRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match user_service
+        .enable_totp(user.id, &enable_info.code, &ctx.with_actor(user.id))
+        .await
+    {
+        Ok(recovery_codes) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Two-factor authentication enabled",
+            "recovery_codes": recovery_codes
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn disable_totp(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    disable_info: web::Json<DisableTotpRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match user_service
+        .disable_totp(
+            user.id,
+            &disable_info.password,
+            disable_info.code.as_deref(),
+            disable_info.recovery_code.as_deref(),
+            &ctx.with_actor(user.id),
+        )
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Two-factor authentication disabled"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
@@ -1,2 +1,8 @@
 pub mod user_controller;
-pub mod todo_controller;
\ No newline at end of file
+pub mod todo_controller;
+pub mod key_controller;
+pub mod admin_controller;
+pub mod access_token_controller;
+pub mod mfa_controller;
+pub mod metrics_controller;
+pub mod passkey_controller;
\ No newline at end of file
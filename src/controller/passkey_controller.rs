@@ -0,0 +1,345 @@
+use crate::{
+    controller::user_controller::tokens_response,
+    middleware::auth::AuthenticatedUser,
+    model::{
+        audit_model::{AuditAction, AuditEvent},
+        passkey_model::Passkey,
+    },
+    service::{
+        audit_service::AuditService, passkey_service::PasskeyService, user_service::UserService,
+    },
+    utils::dpop::DpopKey,
+    utils::error::CustomError,
+    utils::request::RequestContext,
+    utils::webauthn::{AuthenticationCredential, RegistrationCredential},
+};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use mongodb::bson::oid::ObjectId;
+
+// Registering a passkey adds a way to sign in, so the caller proves who they
+// are again first
+#[derive(serde::Deserialize)]
+pub struct RegistrationOptionsRequest {
+    password: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RegisterPasskeyRequest {
+    name: Option<String>,
+    credential: RegistrationCredential,
+}
+
+fn passkey_json(passkey: &Passkey) -> serde_json::Value {
+    serde_json::json!({
+        "id": passkey.id.map(|id| id.to_hex()),
+        "name": passkey.name,
+        "credential_id": passkey.credential_id,
+        "created_at": passkey.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        "last_used_at": passkey.last_used_at.map(|d| d.try_to_rfc3339_string().unwrap_or_default()),
+    })
+}
+
+pub async fn registration_options(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    passkey_service: web::Data<PasskeyService>,
+    options_info: web::Json<RegistrationOptionsRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let account = match user_service
+        .reauthenticate(
+            user.id,
+            &options_info.password,
+            options_info.code.as_deref(),
+            options_info.recovery_code.as_deref(),
+            &ctx,
+        )
+        .await
+    {
+        Ok(account) => account,
+        Err(e) => return e.error_response(),
+    };
+
+    match passkey_service.begin_registration(&account).await {
+        Ok(options) => HttpResponse::Ok().json(options),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn register_passkey(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    passkey_service: web::Data<PasskeyService>,
+    audit_service: web::Data<AuditService>,
+    passkey_info: web::Json<RegisterPasskeyRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let passkey_info = passkey_info.into_inner();
+    let name = passkey_info
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "Passkey".to_string());
+
+    let result = passkey_service
+        .finish_registration(user.id, name, &passkey_info.credential)
+        .await;
+
+    let event = AuditEvent::new(AuditAction::PasskeyRegistered, &ctx.with_actor(user.id))
+        .target(Some(user.id));
+    audit_service.record(event.outcome(&result)).await;
+
+    match result {
+        Ok(passkey) => HttpResponse::Created().json(passkey_json(&passkey)),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn list_passkeys(
+    user: AuthenticatedUser,
+    passkey_service: web::Data<PasskeyService>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match passkey_service.list_passkeys(user.id).await {
+        Ok(passkeys) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "passkeys": passkeys.iter().map(passkey_json).collect::<Vec<_>>()
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn remove_passkey(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    passkey_service: web::Data<PasskeyService>,
+    audit_service: web::Data<AuditService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => {
+            return CustomError::BadRequestError("Invalid passkey ID format".to_string())
+                .error_response()
+        }
+    };
+
+    let result = passkey_service.remove_passkey(user.id, id).await;
+
+    let event = AuditEvent::new(AuditAction::PasskeyRemoved, &ctx.with_actor(user.id))
+        .target(Some(user.id))
+        .subject(&id.to_hex());
+    let event = match &result {
+        Ok(true) => event,
+        Ok(false) => event.failure("Passkey not found".to_string()),
+        Err(e) => event.failure(e.to_string()),
+    };
+    audit_service.record(event).await;
+
+    match result {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Passkey removed successfully"
+        })),
+        Ok(false) => CustomError::NotFoundError("Passkey not found".to_string()).error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn login_options(passkey_service: web::Data<PasskeyService>) -> impl Responder {
+    match passkey_service.begin_authentication().await {
+        Ok(options) => HttpResponse::Ok().json(options),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn login_passkey(
+    ctx: RequestContext,
+    dpop_key: DpopKey,
+    user_service: web::Data<UserService>,
+    credential: web::Json<AuthenticationCredential>,
+) -> impl Responder {
+    let ctx = ctx.with_dpop_key(dpop_key.0);
+    match user_service.login_with_passkey(&credential, &ctx).await {
+        Ok(tokens) => tokens_response(tokens),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mail::OutboxTransport;
+    use crate::middleware::rate_limit::{MemoryStore, RateLimitStore};
+    use crate::model::user_model::User;
+    use crate::service::{
+        access_token_service::AccessTokenService, audit_service::AuditService,
+        passkey_service::PasskeyService, revocation_service::RevocationService,
+        session_service::SessionService, user_service::UserService,
+    };
+    use crate::utils::webauthn::testing::SoftAuthenticator;
+    use crate::utils::webauthn::{self, RelyingParty};
+    use crate::utils::{hashing, jwt};
+    use actix_web::{http::StatusCode, test, web, App};
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::Client;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    fn post(uri: &str, token: Option<&str>, body: Value) -> test::TestRequest {
+        let request = test::TestRequest::post()
+            .uri(uri)
+            .peer_addr("127.0.0.1:40000".parse().unwrap())
+            .set_json(body);
+        match token {
+            Some(token) => request.insert_header(("Authorization", format!("Bearer {}", token))),
+            None => request,
+        }
+    }
+
+    fn challenge(options: &Value) -> &str {
+        options["publicKey"]["challenge"].as_str().unwrap()
+    }
+
+    // Needs a MongoDB server: MONGODB_URI=... cargo test -- --ignored
+    #[actix_web::test]
+    #[ignore]
+    async fn registered_passkey_can_log_in() {
+        jwt::init_test_keys();
+        // Already set up by another test in this run is fine
+        let _ = hashing::init_hasher();
+        let _ = crate::service::user_service::init_access_token_ttl();
+
+        let uri = std::env::var("MONGODB_URI").expect("MONGODB_URI must be set");
+        let client = Client::with_uri_str(&uri).await.unwrap();
+        let mailer = Arc::new(OutboxTransport::new(std::env::temp_dir().join("outbox")));
+        let rate_limit_store: web::Data<dyn RateLimitStore> =
+            web::Data::from(Arc::new(MemoryStore::new()) as Arc<dyn RateLimitStore>);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(UserService::new(&client, mailer)))
+                .app_data(web::Data::new(RevocationService::new(&client)))
+                .app_data(web::Data::new(AccessTokenService::new(&client)))
+                .app_data(web::Data::new(AuditService::new(&client)))
+                .app_data(web::Data::new(PasskeyService::new(&client)))
+                .app_data(web::Data::new(SessionService::new(&client)))
+                .app_data(rate_limit_store)
+                .configure(crate::routes::router::config),
+        )
+        .await;
+
+        let username = format!("passkey-{}", ObjectId::new().to_hex());
+        let mut user = User::new(
+            username.clone(),
+            format!("{}@example.com", username),
+            PASSWORD.to_string(),
+        );
+        user.email_verified = true;
+        let user_id = client
+            .database("Rust_PRo")
+            .collection::<User>("users")
+            .insert_one(&user, None)
+            .await
+            .unwrap()
+            .inserted_id
+            .as_object_id()
+            .unwrap();
+
+        let login: Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/v1/users/login",
+                None,
+                json!({ "identifier": username, "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let token = login["token"].as_str().unwrap();
+
+        let rp = RelyingParty::from_env();
+        let mut authenticator = SoftAuthenticator::new();
+        // Credential IDs are unique across runs against the same database
+        authenticator.credential_id = ObjectId::new().bytes().to_vec();
+
+        let options: Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/v1/users/passkeys/register/options",
+                Some(token),
+                json!({ "password": PASSWORD }),
+            )
+            .to_request(),
+        )
+        .await;
+        let credential = authenticator.register(&rp, challenge(&options));
+        let response = test::call_service(
+            &app,
+            post(
+                "/v1/users/passkeys/register",
+                Some(token),
+                json!({
+                    "name": "Soft key",
+                    "credential": {
+                        "id": credential.id,
+                        "response": {
+                            "clientDataJSON": credential.response.client_data_json,
+                            "attestationObject": credential.response.attestation_object,
+                        },
+                    },
+                }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let options: Value = test::call_and_read_body_json(
+            &app,
+            post("/v1/users/passkeys/login/options", None, json!({})).to_request(),
+        )
+        .await;
+        let credential = authenticator.assert(&rp, challenge(&options));
+        let assertion = json!({
+            "id": credential.id,
+            "response": {
+                "clientDataJSON": credential.response.client_data_json,
+                "authenticatorData": credential.response.authenticator_data,
+                "signature": credential.response.signature,
+                "userHandle": webauthn::encode(&user_id.bytes()),
+            },
+        });
+        let login: Value = test::call_and_read_body_json(
+            &app,
+            post("/v1/users/passkeys/login", None, assertion.clone()).to_request(),
+        )
+        .await;
+        assert_eq!(login["success"], true);
+        assert!(login["token"].is_string());
+
+        // The challenge was used up by the first login
+        let response = test::call_service(
+            &app,
+            post("/v1/users/passkeys/login", None, assertion).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{model::todo_model::Todo, utils::error::CustomError};
-use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
-use log::debug;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
 use mongodb::bson::oid::ObjectId;
 
-use crate::{middleware::auth::verify_token, service::todo_service::TodoService};
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::audit_model::{AuditAction, AuditEvent},
+    service::{audit_service::AuditService, todo_service::TodoService},
+    utils::request::RequestContext,
+};
 #[derive(serde::Deserialize)]
 pub struct CreateTodoRequest {
     title: String,
@@ -17,41 +21,36 @@ pub struct UpdateTodoRequest {
     completed: Option<bool>,     // Optional field for completion status
 }
 
+fn parse_todo_id(id: &str) -> Result<ObjectId, CustomError> {
+    ObjectId::parse_str(id)
+        .map_err(|_| CustomError::BadRequestError("Invalid todo ID format".to_string()))
+}
+
 pub async fn create_todo(
-    req: HttpRequest,
+    user: AuthenticatedUser,
+    ctx: RequestContext,
     todo_service: web::Data<TodoService>,
+    audit_service: web::Data<AuditService>,
     todo_info: web::Json<CreateTodoRequest>,
 ) -> impl Responder {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        debug!("Authorization header: {:?}", auth_header);
-    } else {
-        debug!("No Authorization header found");
-    }
-    // Extract user_id from token
-    let user_id = match verify_token(&req).await {
-        Ok(id) => match ObjectId::parse_str(&id) {
-            Ok(object_id) => object_id,
-            Err(_) => {
-                return CustomError::BadRequestError("Invalid user ID format in token".to_string())
-                    .error_response()
-            }
-        },
-        Err(_) => {
-            return CustomError::UnauthorizedError("Invalid or missing token".to_string())
-                .error_response()
-        }
-    };
-    println!("{}", user_id);
-
     // Create todo
-    match todo_service
+    let result = todo_service
         .create_todo(
             todo_info.title.clone(),
             todo_info.description.clone(),
-            user_id,
+            user.id,
         )
-        .await
-    {
+        .await;
+
+    let event =
+        AuditEvent::new(AuditAction::TodoCreated, &ctx.with_actor(user.id)).target(Some(user.id));
+    let event = match &result {
+        Ok(todo_id) => event.subject(&todo_id.to_hex()),
+        Err(e) => event.failure(e.clone()),
+    };
+    audit_service.record(event).await;
+
+    match result {
         Ok(todo_id) => HttpResponse::Ok().json(serde_json::json!({
             "message": "Todo created successfully",
             "todo_id": todo_id.to_hex()
@@ -63,120 +62,51 @@ pub async fn create_todo(
     }
 }
 
-pub async fn list_all(req: HttpRequest, todo_service: web::Data<TodoService>) -> impl Responder {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        debug!("Authorization header: {:?}", auth_header);
-    } else {
-        debug!("No Authorization header found");
-    }
-    // Extract user_id from token
-    let user_id = match verify_token(&req).await {
-        Ok(id) => match ObjectId::parse_str(&id) {
-            Ok(object_id) => object_id,
-            Err(_) => {
-                return CustomError::BadRequestError("Invalid user ID format in token".to_string())
-                    .error_response()
-            }
-        },
-        Err(_) => {
-            return CustomError::UnauthorizedError("Invalid or missing token".to_string())
-                .error_response()
-        }
-    };
-    println!("{}", user_id);
-
+pub async fn list_all(user: AuthenticatedUser, todo_service: web::Data<TodoService>) -> impl Responder {
     // Fetch todos for the user
-    match todo_service.list_todos(user_id).await {
+    match todo_service.list_todos(user.id).await {
         Ok(todos) => HttpResponse::Ok().json(todos),
-        Err(e) => HttpResponse::InternalServerError().body(e),
+        Err(e) => CustomError::InternalServerError(e).error_response(),
     }
 }
 
-pub async fn list_one(req: HttpRequest, todo_service: web::Data<TodoService>) -> impl Responder {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        debug!("Authorization header: {:?}", auth_header);
-    } else {
-        debug!("No Authorization header found");
-    }
-    // Extract user_id from token
-    let user_id = match verify_token(&req).await {
-        Ok(id) => match ObjectId::parse_str(&id) {
-            Ok(object_id) => object_id,
-            Err(_) => {
-                return CustomError::BadRequestError("Invalid user ID format in token".to_string())
-                    .error_response()
-            }
-        },
-        Err(_) => {
-            return CustomError::UnauthorizedError("Invalid or missing token".to_string())
-                .error_response()
-        }
-    };
-    println!("{}", user_id);
-
-    let id = match req.match_info().get("id") {
-        Some(id_str) => match ObjectId::parse_str(id_str) {
-            Ok(object_id) => object_id,
-            Err(_) => {
-                return CustomError::BadRequestError("Invalid todo ID format".to_string())
-                    .error_response()
-            }
-        },
-        None => {
-            return CustomError::BadRequestError("Todo ID not provided".to_string())
-                .error_response()
-        }
+pub async fn list_one(
+    user: AuthenticatedUser,
+    todo_service: web::Data<TodoService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let id = match parse_todo_id(&path) {
+        Ok(id) => id,
+        Err(e) => return e.error_response(),
     };
 
     // Fetch the todo item
-    match todo_service.get_todo(id, user_id).await {
+    match todo_service.get_todo(id, user.id).await {
         Ok(Some(todo)) => HttpResponse::Ok().json(todo), // Return the todo item as JSON
-        Ok(None) => HttpResponse::NotFound().body("Todo not found"), // Handle case where todo does not exist
-        Err(e) => HttpResponse::InternalServerError().body(e),       // Handle any other
+        Ok(None) => CustomError::NotFoundError("Todo not found".to_string()).error_response(), // Handle case where todo does not exist
+        Err(e) => CustomError::InternalServerError(e).error_response(), // Handle any other
     }
 }
 
 pub async fn update_todo(
-    req: HttpRequest,
+    user: AuthenticatedUser,
+    ctx: RequestContext,
     todo_service: web::Data<TodoService>,
+    audit_service: web::Data<AuditService>,
+    path: web::Path<String>,
     todo_update: web::Json<UpdateTodoRequest>,
 ) -> impl Responder {
-    // Check for the Authorization header
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        debug!("Authorization header: {:?}", auth_header);
-    } else {
-        debug!("No Authorization header found");
-        return HttpResponse::Unauthorized().body("No Authorization header found");
-    }
-
-    // Extract user_id from token
-    let user_id = match verify_token(&req).await {
-        Ok(id) => match ObjectId::parse_str(&id) {
-            Ok(object_id) => object_id,
-            Err(_) => {
-                return HttpResponse::BadRequest().body("Invalid user ID format in token");
-            }
-        },
-        Err(_) => {
-            return HttpResponse::Unauthorized().body("Invalid or missing token");
-        }
-    };
-
     // Extract the todo ID from the request path
-    let id = match req.match_info().get("id") {
-        Some(id_str) => match ObjectId::parse_str(id_str) {
-            Ok(object_id) => object_id,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid todo ID format"),
-        },
-        None => return HttpResponse::BadRequest().body("Todo ID not provided"),
+    let id = match parse_todo_id(&path) {
+        Ok(id) => id,
+        Err(e) => return e.error_response(),
     };
 
-    // Create a Todo struct from the UpdateTodoRequest
     // Fetch the existing todo item
-    let existing_todo = match todo_service.get_todo(id.clone(), user_id.clone()).await {
+    let existing_todo = match todo_service.get_todo(id, user.id).await {
         Ok(Some(todo)) => todo,
-        Ok(None) => return HttpResponse::NotFound().body("Todo not found"),
-        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(None) => return CustomError::NotFoundError("Todo not found".to_string()).error_response(),
+        Err(e) => return CustomError::InternalServerError(e).error_response(),
     };
 
     // Create a new Todo object based on the existing data and the update request
@@ -192,14 +122,29 @@ pub async fn update_todo(
     };
 
     // Call the service to update the todo
-    match todo_service.update_todo(id, user_id, updated_todo).await {
+    let result = todo_service.update_todo(id, user.id, updated_todo).await;
+
+    let event = AuditEvent::new(AuditAction::TodoUpdated, &ctx.with_actor(user.id))
+        .target(Some(user.id))
+        .subject(&id.to_hex());
+    let event = match &result {
+        Ok(true) => event,
+        Ok(false) => event.failure("Todo not found or not updated".to_string()),
+        Err(e) => event.failure(e.clone()),
+    };
+    audit_service.record(event).await;
+
+    match result {
         Ok(updated) => {
             if updated {
-                HttpResponse::Ok().body("Todo updated successfully")
+                HttpResponse::Ok().json(serde_json::json!({
+                    "message": "Todo updated successfully"
+                }))
             } else {
-                HttpResponse::NotFound().body("Todo not found or not updated")
+                CustomError::NotFoundError("Todo not found or not updated".to_string())
+                    .error_response()
             }
         }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => CustomError::InternalServerError(e).error_response(),
     }
 }
@@ -1,5 +1,21 @@
-use crate::{service::user_service::UserService, utils::model::LoginRequests};
-use actix_web::{web, HttpResponse, Responder};
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::{session_model::Session, user_model::UserProfile},
+    service::session_service::SessionService,
+    service::user_service::{self, AccountDeletion, LoginOutcome, UserService},
+    utils::dpop::DpopKey,
+    utils::error::CustomError,
+    utils::model::{
+        ChangePasswordRequest, DeleteAccountRequest, ForgotPasswordRequest, LoginRequests,
+        LogoutRequest, MagicLinkLoginRequest, MagicLinkRequest, RefreshRequest,
+        ResendVerificationRequest, ResetPasswordRequest, RestoreAccountRequest,
+        UpdateProfileRequest, VerifyEmailQuery,
+    },
+    utils::request::RequestContext,
+    utils::session_cookie,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use mongodb::bson::oid::ObjectId;
 use serde::Serialize;
 
 
@@ -11,6 +27,7 @@ pub struct CreateUserRequest {
 }
 
 pub async fn register_user(
+    ctx: RequestContext,
     user_service: web::Data<UserService>,
     user_info: web::Json<CreateUserRequest>,
 ) -> impl Responder {
@@ -19,6 +36,7 @@ pub async fn register_user(
             user_info.username.clone(),
             user_info.email.clone(),
             user_info.password.clone(),
+            &ctx,
         )
         .await
     {
@@ -26,10 +44,7 @@ pub async fn register_user(
             "message": "User created successfully",
             "user_id": user_id.to_hex()
         })),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "message": "Failed to create user",
-            "error": e.to_string()
-        })),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -45,21 +60,397 @@ struct Claims {
     exp: usize,
 }
 
+/// Returns freshly issued tokens both in the body, for API clients, and as
+/// session cookies, for browsers.
+/// DPoP-bound tokens are useless without the client's key, so they are not
+/// set as cookies.
+pub(crate) fn tokens_response(tokens: user_service::LoginResponse) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if tokens.token_type != "DPoP" {
+        session_cookie::set_session_cookies(&mut response, &tokens);
+    }
+    response.json(serde_json::json!({
+        "success": true,
+        "token": tokens.token,
+        "refresh_token": tokens.refresh_token,
+        "token_type": tokens.token_type,
+        "expires_in": tokens.expires_in,
+        "csrf_token": tokens.csrf_token
+    }))
+}
+
 pub async fn login_user(
+    ctx: RequestContext,
+    dpop_key: DpopKey,
     user_service: web::Data<UserService>,
     login_info: web::Json<LoginRequests>,
 ) -> impl Responder {
+    let ctx = ctx.with_dpop_key(dpop_key.0);
+    login_response(user_service.login_fn(login_info.into_inner(), &ctx).await)
+}
+
+// Shared by every first-factor login so clients handle a single shape
+fn login_response(result: Result<LoginOutcome, CustomError>) -> HttpResponse {
+    match result {
+        Ok(LoginOutcome::Tokens(tokens)) => tokens_response(tokens),
+        Ok(LoginOutcome::MfaRequired {
+            mfa_token,
+            expires_in,
+        }) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "mfa_required": true,
+            "mfa_token": mfa_token,
+            "expires_in": expires_in
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn refresh_token(
+    req: HttpRequest,
+    ctx: RequestContext,
+    dpop_key: DpopKey,
+    user_service: web::Data<UserService>,
+    refresh_info: Option<web::Json<RefreshRequest>>,
+) -> impl Responder {
+    let ctx = ctx.with_dpop_key(dpop_key.0);
+    let from_body = refresh_info.and_then(|info| info.into_inner().refresh_token);
+    let refresh_token = match session_cookie::refresh_token(&req, from_body) {
+        Ok(refresh_token) => refresh_token,
+        Err(e) => return e.error_response(),
+    };
+
+    match user_service.refresh_fn(&refresh_token, &ctx).await {
+        Ok(tokens) => tokens_response(tokens),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn logout_user(
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    logout_info: Option<web::Json<LogoutRequest>>,
+) -> impl Responder {
+    // The session itself was CSRF-checked, so the refresh cookie can be used
+    let refresh_token = logout_info
+        .and_then(|info| info.into_inner().refresh_token)
+        .or_else(|| {
+            req.cookie(session_cookie::REFRESH_COOKIE)
+                .map(|cookie| cookie.value().to_string())
+        });
+
+    match user_service
+        .logout_fn(
+            &user.claims,
+            refresh_token.as_deref(),
+            &ctx.with_actor(user.id),
+        )
+        .await
+    {
+        Ok(()) => {
+            let mut response = HttpResponse::Ok();
+            session_cookie::clear_session_cookies(&mut response);
+            response.json(serde_json::json!({
+                "success": true,
+                "message": "Logged out successfully"
+            }))
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn logout_all(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+) -> impl Responder {
+    match user_service
+        .logout_all_fn(user.id, &ctx.with_actor(user.id))
+        .await
+    {
+        Ok(()) => {
+            let mut response = HttpResponse::Ok();
+            session_cookie::clear_session_cookies(&mut response);
+            response.json(serde_json::json!({
+                "success": true,
+                "message": "Logged out of all sessions"
+            }))
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn verify_email(
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    query: web::Query<VerifyEmailQuery>,
+) -> impl Responder {
+    match user_service.verify_email(&query.token, &ctx).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Email verified successfully"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn resend_verification(
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    resend_info: web::Json<ResendVerificationRequest>,
+) -> impl Responder {
+    match user_service
+        .resend_verification_email(&resend_info.email, &ctx)
+        .await
+    {
+        Ok(()) => HttpResponse::Accepted().json(serde_json::json!({
+            "success": true,
+            "message": "If the address belongs to an unverified account, a new link has been sent"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn forgot_password(
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    forgot_info: web::Json<ForgotPasswordRequest>,
+) -> impl Responder {
+    match user_service.forgot_password(&forgot_info.email, &ctx).await {
+        Ok(()) => HttpResponse::Accepted().json(serde_json::json!({
+            "success": true,
+            "message": "If the address belongs to an account, a reset link has been sent"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn request_magic_link(
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    magic_link_info: web::Json<MagicLinkRequest>,
+) -> impl Responder {
+    match user_service
+        .request_magic_link(&magic_link_info.email, &ctx)
+        .await
+    {
+        Ok(()) => HttpResponse::Accepted().json(serde_json::json!({
+            "success": true,
+            "message": "If the address belongs to an account, a login link has been sent"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn login_magic_link(
+    ctx: RequestContext,
+    dpop_key: DpopKey,
+    user_service: web::Data<UserService>,
+    magic_link_info: web::Json<MagicLinkLoginRequest>,
+) -> impl Responder {
+    let ctx = ctx.with_dpop_key(dpop_key.0);
+    login_response(
+        user_service
+            .login_with_magic_link(&magic_link_info.token, &ctx)
+            .await,
+    )
+}
+
+pub async fn reset_password(
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    reset_info: web::Json<ResetPasswordRequest>,
+) -> impl Responder {
+    match user_service
+        .reset_password(&reset_info.token, &reset_info.password, &ctx)
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Password reset successfully"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn get_me(
+    user: AuthenticatedUser,
+    user_service: web::Data<UserService>,
+) -> impl Responder {
+    match user_service.find_by_id(user.id).await {
+        Ok(found) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "user": UserProfile::from(&found)
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn update_me(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    profile_info: web::Json<UpdateProfileRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let profile_info = profile_info.into_inner();
+    match user_service
+        .update_profile(
+            user.id,
+            profile_info.username,
+            profile_info.email,
+            &ctx.with_actor(user.id),
+        )
+        .await
+    {
+        Ok(updated) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "user": UserProfile::from(&updated)
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn change_password(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    password_info: web::Json<ChangePasswordRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match user_service
+        .change_password(
+            user.id,
+            &password_info.current_password,
+            &password_info.new_password,
+            &ctx.with_actor(user.id),
+        )
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Password changed, please log in again"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn delete_me(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    delete_info: web::Json<DeleteAccountRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
     match user_service
-        .login_fn(login_info.into_inner())
+        .delete_account(user.id, &delete_info.password, &ctx.with_actor(user.id))
         .await
     {
-        Ok(token) => HttpResponse::Ok().json(serde_json::json!({
+        Ok(AccountDeletion::Deleted) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Account deleted"
+        })),
+        Ok(AccountDeletion::Scheduled { purge_after }) => {
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": "Account scheduled for deletion",
+                "restore_before": purge_after.try_to_rfc3339_string().unwrap_or_default()
+            }))
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn restore_account(
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    restore_info: web::Json<RestoreAccountRequest>,
+) -> impl Responder {
+    match user_service
+        .restore_account(&restore_info.identifier, &restore_info.password, &ctx)
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Account restored"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+fn session_json(session: &Session, current: bool) -> serde_json::Value {
+    serde_json::json!({
+        "id": session.id.map(|id| id.to_hex()),
+        "device_name": session.device_name,
+        "user_agent": session.user_agent,
+        "ip": session.ip,
+        "created_at": session.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        "last_used_at": session.last_used_at.try_to_rfc3339_string().unwrap_or_default(),
+        "current": current,
+    })
+}
+
+pub async fn list_sessions(
+    user: AuthenticatedUser,
+    session_service: web::Data<SessionService>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    match session_service.list_sessions(user.id).await {
+        Ok(sessions) => HttpResponse::Ok().json(serde_json::json!({
             "success": true,
-            "token": token
+            "sessions": sessions
+                .iter()
+                .map(|session| {
+                    let current = session.id.map(|id| id.to_hex()) == user.claims.sid;
+                    session_json(session, current)
+                })
+                .collect::<Vec<_>>()
         })),
-        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
-            "success": false,
-            "error": e.to_string()
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn revoke_session(
+    user: AuthenticatedUser,
+    ctx: RequestContext,
+    user_service: web::Data<UserService>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = user.require_session() {
+        return e.error_response();
+    }
+
+    let session_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(session_id) => session_id,
+        Err(_) => {
+            return CustomError::BadRequestError("Invalid session ID format".to_string())
+                .error_response()
+        }
+    };
+
+    match user_service
+        .revoke_session(user.id, session_id, &ctx.with_actor(user.id))
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "Session revoked"
         })),
+        Ok(false) => CustomError::NotFoundError("Session not found".to_string()).error_response(),
+        Err(e) => e.error_response(),
     }
 }
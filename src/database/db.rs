@@ -1,7 +1,10 @@
 use mongodb::{Client, options::ClientOptions};
 use mongodb::bson::doc;
+use mongodb::error::{ErrorKind, WriteFailure};
 use std::error::Error;
 
+use crate::utils::error::CustomError;
+
 pub struct Database {
     pub client: Client,
 }
@@ -30,6 +33,24 @@ impl Database {
     // You can add more database-related methods here
 }
 
+/// Whether `e` is a unique index violation.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+/// Documents to skip to reach `page` (1-based) of `per_page` results.
+/// Pages too far out to address are rejected rather than overflowing.
+pub fn page_offset(page: u64, per_page: i64) -> Result<u64, CustomError> {
+    u64::try_from(per_page)
+        .ok()
+        .and_then(|per_page| page.saturating_sub(1).checked_mul(per_page))
+        .filter(|offset| i64::try_from(*offset).is_ok())
+        .ok_or_else(|| CustomError::ValidationError("page is out of range".to_string()))
+}
+
 // This function is a convenience wrapper around Database::init()
 pub async fn connect_to_mongo() -> Result<Client, Box<dyn Error>> {
      let database = Database::init().await.map_err(|e| {
@@ -37,4 +58,23 @@ pub async fn connect_to_mongo() -> Result<Client, Box<dyn Error>> {
           e
       })?;
       Ok(database.client)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_offset_skips_whole_pages() {
+        assert_eq!(page_offset(1, 20).unwrap(), 0);
+        assert_eq!(page_offset(3, 20).unwrap(), 40);
+        // Page 0 is treated as the first page
+        assert_eq!(page_offset(0, 20).unwrap(), 0);
+    }
+
+    #[test]
+    fn page_offset_rejects_unaddressable_pages() {
+        assert!(page_offset(u64::MAX, 100).is_err());
+        assert!(page_offset(u64::MAX / 50, 100).is_err());
+        assert!(page_offset(2, -1).is_err());
+    }
+}
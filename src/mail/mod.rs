@@ -0,0 +1,47 @@
+mod outbox;
+mod smtp;
+
+pub use outbox::OutboxTransport;
+pub use smtp::SmtpTransport;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MailError {
+    #[error("Invalid email: {0}")]
+    InvalidMessage(String),
+
+    #[error("Failed to send email: {0}")]
+    Transport(String),
+}
+
+/// Delivers outgoing email. Implementations must be cheap to share between
+/// workers.
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+/// Builds the transport selected by `MAIL_TRANSPORT` (`smtp` or `outbox`,
+/// the default).
+pub fn transport_from_env() -> Result<Arc<dyn MailTransport>, MailError> {
+    let transport = std::env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "outbox".to_string());
+
+    match transport.as_str() {
+        "smtp" => Ok(Arc::new(SmtpTransport::from_env()?)),
+        "outbox" => Ok(Arc::new(OutboxTransport::from_env())),
+        other => Err(MailError::Transport(format!(
+            "Unsupported MAIL_TRANSPORT: {}",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,38 @@
+use async_trait::async_trait;
+use log::info;
+use mongodb::bson::oid::ObjectId;
+use std::path::PathBuf;
+
+use super::{Email, MailError, MailTransport};
+
+/// Writes each email as a JSON file instead of sending it. Meant for local
+/// development and tests, where the files can be read back.
+pub struct OutboxTransport {
+    dir: PathBuf,
+}
+
+impl OutboxTransport {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        OutboxTransport { dir: dir.into() }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("MAIL_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string()))
+    }
+}
+
+#[async_trait]
+impl MailTransport for OutboxTransport {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| MailError::Transport(e.to_string()))?;
+
+        // ObjectIds sort by creation time, which keeps the outbox in send order
+        let path = self.dir.join(format!("{}.json", ObjectId::new().to_hex()));
+        let contents =
+            serde_json::to_vec_pretty(&email).map_err(|e| MailError::Transport(e.to_string()))?;
+        std::fs::write(&path, contents).map_err(|e| MailError::Transport(e.to_string()))?;
+
+        info!("Wrote email to {} into {}", email.to, path.display());
+        Ok(())
+    }
+}
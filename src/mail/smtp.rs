@@ -0,0 +1,66 @@
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use super::{Email, MailError, MailTransport};
+
+pub struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpTransport {
+    /// Reads `SMTP_HOST`, `SMTP_PORT`, `SMTP_USERNAME`, `SMTP_PASSWORD` and
+    /// `MAIL_FROM`. Connections always use STARTTLS.
+    pub fn from_env() -> Result<Self, MailError> {
+        let host = std::env::var("SMTP_HOST")
+            .map_err(|_| MailError::Transport("SMTP_HOST must be set".to_string()))?;
+        let from = std::env::var("MAIL_FROM")
+            .map_err(|_| MailError::Transport("MAIL_FROM must be set".to_string()))?
+            .parse::<Mailbox>()
+            .map_err(|e| MailError::InvalidMessage(e.to_string()))?;
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+            .map_err(|e| MailError::Transport(e.to_string()))?;
+
+        if let Some(port) = std::env::var("SMTP_PORT").ok().and_then(|p| p.parse().ok()) {
+            builder = builder.port(port);
+        }
+
+        if let (Ok(username), Ok(password)) =
+            (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpTransport {
+            mailer: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let to = email
+            .to
+            .parse::<Mailbox>()
+            .map_err(|e| MailError::InvalidMessage(e.to_string()))?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| MailError::InvalidMessage(e.to_string()))?;
+
+        self.mailer
+            .send(message)
+            .await
+            .map_err(|e| MailError::Transport(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -3,16 +3,25 @@ use actix_web::middleware::{ErrorHandlers, Logger};
 use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
 use dotenv::dotenv;
 use env_logger::Env;
-use log::info;
+use log::{error, info};
+use std::time::Duration;
 
 mod middleware;
 use middleware::not_found::not_found;
+use middleware::rate_limit::RateLimitStore;
 use serde_json::json;
+use service::access_token_service::AccessTokenService;
+use service::audit_service::AuditService;
+use service::dpop_service::DpopService;
+use service::passkey_service::PasskeyService;
+use service::revocation_service::RevocationService;
+use service::session_service::SessionService;
 use service::todo_service::TodoService;
 use service::user_service::UserService;
 
 mod controller;
 mod database;
+mod mail;
 mod model;
 mod routes;
 mod service;
@@ -39,13 +48,80 @@ async fn main() -> std::io::Result<()> {
     // Log the server start
     info!("Starting server on http://localhost:5001");
 
+    utils::jwt::init_keys().expect("Failed to load JWT signing keys");
+    utils::password_validation::init_policy().expect("Failed to load password policy");
+    utils::hashing::init_hasher().expect("Failed to load Argon2 parameters");
+    service::user_service::init_access_token_ttl().expect("Failed to load access token lifetime");
+
     let mongo_client = database::connect_to_mongo()
         .await
         .expect("Failed to connect to MongoDB");
 
+    // `verify-audit` checks the audit chain and exits instead of serving
+    if std::env::args().nth(1).as_deref() == Some("verify-audit") {
+        return verify_audit(&AuditService::new(&mongo_client)).await;
+    }
+
+    let mailer = mail::transport_from_env().expect("Failed to configure mail transport");
+
     // Create UserService
-    let user_service = web::Data::new(UserService::new(&mongo_client));
+    let user_service = web::Data::new(UserService::new(&mongo_client, mailer));
     let todo_service = web::Data::new(TodoService::new(&mongo_client));
+    let revocation_service = web::Data::new(RevocationService::new(&mongo_client));
+    let access_token_service = web::Data::new(AccessTokenService::new(&mongo_client));
+    let audit_service = web::Data::new(AuditService::new(&mongo_client));
+    let passkey_service = web::Data::new(PasskeyService::new(&mongo_client));
+    let session_service = web::Data::new(SessionService::new(&mongo_client));
+    let dpop_service = web::Data::new(DpopService::new(&mongo_client));
+    let rate_limit_store: web::Data<dyn RateLimitStore> = web::Data::from(
+        middleware::rate_limit::store_from_env().expect("Failed to configure rate limit store"),
+    );
+    audit_service
+        .ensure_indexes()
+        .await
+        .expect("Failed to create audit log indexes");
+    passkey_service
+        .ensure_indexes()
+        .await
+        .expect("Failed to create passkey indexes");
+    dpop_service
+        .ensure_indexes()
+        .await
+        .expect("Failed to create DPoP proof indexes");
+    // Duplicates left over from case-sensitive matching need manual cleanup
+    if let Err(e) = user_service.migrate_identities().await {
+        error!("Failed to enforce unique usernames and emails: {}", e);
+    }
+
+    // Purge accounts whose deletion grace period has run out
+    let purge_service = user_service.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            match purge_service.purge_deleted_accounts().await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} deleted accounts", purged),
+                Err(e) => error!("Failed to purge deleted accounts: {}", e),
+            }
+        }
+    });
+
+    // Sign the audit chain head so later tampering can be proven
+    let checkpoint_service = audit_service.clone();
+    let checkpoint_interval = std::env::var("AUDIT_CHECKPOINT_INTERVAL_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3600);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(checkpoint_interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = checkpoint_service.create_checkpoint().await {
+                error!("Failed to create audit checkpoint: {}", e);
+            }
+        }
+    });
 
     // Start the HTTP server
     HttpServer::new(move || {
@@ -55,6 +131,13 @@ async fn main() -> std::io::Result<()> {
             .app_data(web::Data::new(mongo_client.clone()))
             .app_data(user_service.clone())
             .app_data(todo_service.clone())
+            .app_data(revocation_service.clone())
+            .app_data(access_token_service.clone())
+            .app_data(audit_service.clone())
+            .app_data(passkey_service.clone())
+            .app_data(session_service.clone())
+            .app_data(dpop_service.clone())
+            .app_data(rate_limit_store.clone())
             .configure(routes::router::config)
             .wrap(
                 ErrorHandlers::new()
@@ -73,3 +156,34 @@ async fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+async fn verify_audit(audit_service: &AuditService) -> std::io::Result<()> {
+    let report = audit_service
+        .verify_chain()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    println!(
+        "Verified {} audit entries and {} checkpoints, head at entry {}",
+        report.verified, report.checkpoints_verified, report.head_seq
+    );
+    if report.unchained > 0 {
+        println!(
+            "{} entries predate hash chaining and were skipped",
+            report.unchained
+        );
+    }
+
+    match report.broken {
+        None => Ok(()),
+        Some(link) => {
+            eprintln!(
+                "Audit chain broken at entry {} ({}): {}",
+                link.seq,
+                link.id.as_deref().unwrap_or("no id"),
+                link.reason
+            );
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,96 +1,310 @@
+use crate::model::access_token_model::ACCESS_TOKEN_PREFIX;
+use crate::model::audit_model::{AuditAction, AuditEvent};
+use crate::model::user_model::{Permission, Role};
+use crate::service::access_token_service::AccessTokenService;
+use crate::service::audit_service::AuditService;
+use crate::service::revocation_service::RevocationService;
+use crate::service::session_service::SessionService;
+use crate::service::user_service::{Claims, UserService};
+use crate::utils::dpop;
 use crate::utils::error::CustomError;
-use actix_web::{Error, HttpRequest};
-use jsonwebtoken::{decode, DecodingKey, Validation};
-use log::debug;
-use serde::{Deserialize, Serialize};
-use std::env;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    id: String,
-    exp: usize,
-}
+use crate::utils::jwt;
+use crate::utils::request::{client_ip, RequestContext};
+use crate::utils::session_cookie::{self, SESSION_COOKIE};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use mongodb::bson::oid::ObjectId;
+use std::rc::Rc;
 
-pub async fn verify_token(req: &HttpRequest) -> Result<String, Error> {
-    let token = req
+/// Decodes the bearer token and checks it against the revocation store.
+/// Tokens of revoked sessions, and of users who could no longer log in, are
+/// rejected.
+///
+/// Personal access tokens (`pat_...`) are accepted as well and come back as
+/// claims carrying the token's `scopes`. Without an `Authorization` header
+/// the session cookie is used instead; state-changing requests authenticated
+/// that way must echo the session's CSRF token in `X-CSRF-Token`.
+///
+/// Tokens bound to a DPoP key are presented as `Authorization: DPoP ...`
+/// together with a fresh proof from that key in the `DPoP` header. They are
+/// rejected under any other scheme.
+///
+/// A presented token that fails any check is recorded in the audit log.
+pub async fn verify_token(req: &HttpRequest) -> Result<Claims, Error> {
+    let authorization = req
         .headers()
         .get("Authorization")
-        .and_then(|header| header.to_str().ok())
-        .and_then(|auth_header| auth_header.strip_prefix("Bearer "));
-
-        debug!("Extracted token: {:?}", token);
-
-        
-    match token {
-        Some(token) => {
-            let secret = env::var("JWT_SECRET").map_err(|_| {
-                CustomError::UnauthorizedError("JWT_SECRET must be set".to_string())
-            })?;
-            let key = DecodingKey::from_secret(secret.as_bytes());
-            let validation = Validation::default();
-
-            match decode::<Claims>(token, &key, &validation) {
-                Ok(token_data) => Ok(token_data.claims.id),
-                Err(_) => Err(CustomError::UnauthorizedError("Invalid token".to_string()).into()),
-            }
+        .and_then(|header| header.to_str().ok());
+    let token = authorization.and_then(|auth_header| auth_header.strip_prefix("Bearer "));
+    let dpop_token = authorization.and_then(|auth_header| auth_header.strip_prefix("DPoP "));
+
+    let result = match (token, dpop_token, req.cookie(SESSION_COOKIE)) {
+        (_, Some(token), _) => verify_dpop_token(req, token).await,
+        (Some(token), _, _) if token.starts_with(ACCESS_TOKEN_PREFIX) => {
+            verify_access_token(req, token).await
         }
-        None => Err(CustomError::UnauthorizedError(
-            "Authorization header is missing or invalid".to_string(),
+        (Some(token), _, _) => verify_bearer_token(req, token).await,
+        (None, None, Some(cookie)) => verify_session_cookie(req, cookie.value()).await,
+        // Not audited: anonymous requests say nothing about a credential
+        (None, None, None) => {
+            return Err(CustomError::UnauthorizedError(
+                "Authorization header is missing or invalid".to_string(),
+            )
+            .into())
+        }
+    };
+
+    if let (Err(e), Some(audit_service)) = (&result, req.app_data::<web::Data<AuditService>>()) {
+        let event = AuditEvent::new(
+            AuditAction::TokenRejected,
+            &RequestContext::from_request(req),
         )
-        .into()),
+        .failure(e.to_string());
+        audit_service.record(event).await;
+    }
+
+    result
+}
+
+async fn verify_bearer_token(req: &HttpRequest, token: &str) -> Result<Claims, Error> {
+    let claims = verify_jwt(req, token).await?;
+
+    // Whoever holds a bound token also needs the key to use it
+    if claims.cnf.is_some() {
+        return Err(CustomError::UnauthorizedError(
+            "DPoP-bound tokens must be sent with the DPoP scheme".to_string(),
+        )
+        .into());
+    }
+
+    Ok(claims)
+}
+
+async fn verify_dpop_token(req: &HttpRequest, token: &str) -> Result<Claims, Error> {
+    let claims = verify_jwt(req, token).await?;
+
+    let bound_jkt = match &claims.cnf {
+        Some(cnf) => &cnf.jkt,
+        None => {
+            return Err(
+                CustomError::UnauthorizedError("Token is not DPoP-bound".to_string()).into(),
+            )
+        }
+    };
+    let jkt = dpop::check_request(req, Some(token))
+        .await?
+        .ok_or_else(|| CustomError::DpopProofError("a proof is required".to_string(), None))?;
+    if &jkt != bound_jkt {
+        return Err(CustomError::DpopProofError(
+            "proof is not signed by the token's key".to_string(),
+            None,
+        )
+        .into());
+    }
+
+    Ok(claims)
+}
+
+async fn verify_jwt(req: &HttpRequest, token: &str) -> Result<Claims, Error> {
+    let claims = jwt::decode_token::<Claims>(token)?;
+
+    let revocation_service = req
+        .app_data::<web::Data<RevocationService>>()
+        .ok_or_else(|| {
+            CustomError::InternalServerError("Revocation store is not configured".to_string())
+        })?;
+
+    if revocation_service.is_revoked(&claims).await? {
+        return Err(CustomError::UnauthorizedError("Token has been revoked".to_string()).into());
+    }
+
+    let user_service = req.app_data::<web::Data<UserService>>().ok_or_else(|| {
+        CustomError::InternalServerError("User service is not configured".to_string())
+    })?;
+    let user_id = ObjectId::parse_str(&claims.id)
+        .map_err(|_| CustomError::UnauthorizedError("Invalid token".to_string()))?;
+    user_service.ensure_active(user_id).await?;
+
+    // Tokens issued before sessions were recorded carry no `sid`
+    if let Some(sid) = &claims.sid {
+        let session_id = ObjectId::parse_str(sid)
+            .map_err(|_| CustomError::UnauthorizedError("Invalid token".to_string()))?;
+        let session_service = req.app_data::<web::Data<SessionService>>().ok_or_else(|| {
+            CustomError::InternalServerError("Session store is not configured".to_string())
+        })?;
+        session_service
+            .check_active(session_id, user_id, &client_ip(req))
+            .await?;
+    }
+
+    Ok(claims)
+}
+
+async fn verify_session_cookie(req: &HttpRequest, token: &str) -> Result<Claims, Error> {
+    let claims = verify_bearer_token(req, token).await?;
+
+    if !req.method().is_safe() {
+        session_cookie::check_csrf(req, claims.csrf.as_deref())?;
+    }
+
+    Ok(claims)
+}
+
+async fn verify_access_token(req: &HttpRequest, token: &str) -> Result<Claims, Error> {
+    let access_token_service = req
+        .app_data::<web::Data<AccessTokenService>>()
+        .ok_or_else(|| {
+            CustomError::InternalServerError("Access token store is not configured".to_string())
+        })?;
+    let user_service = req.app_data::<web::Data<UserService>>().ok_or_else(|| {
+        CustomError::InternalServerError("User service is not configured".to_string())
+    })?;
+
+    let access_token = access_token_service.verify_token(token).await?;
+
+    // Roles are looked up on every use so a demoted user loses access at once
+    let user = user_service.ensure_active(access_token.user_id).await?;
+
+    Ok(Claims {
+        id: access_token.user_id.to_hex(),
+        exp: access_token
+            .expires_at
+            .map(|expires_at| (expires_at.timestamp_millis() / 1000) as usize)
+            .unwrap_or(usize::MAX),
+        iat: (access_token.created_at.timestamp_millis() / 1000) as usize,
+        jti: access_token.id.map(|id| id.to_hex()).unwrap_or_default(),
+        roles: user.roles,
+        scopes: Some(access_token.scopes),
+        csrf: None,
+        sid: None,
+        cnf: None,
+    })
+}
+
+/// The caller of a request, resolved from its bearer token.
+///
+/// Declare it as a handler argument to require authentication. Failures are
+/// returned as the usual JSON `CustomError` response.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: ObjectId,
+    pub claims: Claims,
+}
+
+impl AuthenticatedUser {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.claims.roles.contains(&role)
+    }
+
+    /// Granted by one of the user's roles and, for personal access tokens,
+    /// also by the token's scopes.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        let granted = self
+            .claims
+            .roles
+            .iter()
+            .any(|role| role.permissions().contains(&permission));
+
+        match &self.claims.scopes {
+            Some(scopes) => granted && scopes.contains(&permission),
+            None => granted,
+        }
+    }
+
+    pub fn is_access_token(&self) -> bool {
+        self.claims.scopes.is_some()
+    }
+
+    /// Rejects personal access tokens. Credentials and account settings are
+    /// managed from a signed-in session only, so a leaked token cannot be
+    /// used to take over the account.
+    pub fn require_session(&self) -> Result<(), CustomError> {
+        if self.is_access_token() {
+            return Err(CustomError::ForbiddenError(
+                "Access tokens cannot be used for this action, sign in instead".to_string(),
+            ));
+        }
+        Ok(())
     }
 }
 
-// pub async fn verify_token(req: ServiceRequest, _credentials: BearerAuth) -> Result<ServiceRequest, Error> {
-//     let token = req
-//         .headers()
-//         .get("Authorization")
-//         .and_then(|header| header.to_str().ok())
-//         .and_then(|auth_header| auth_header.strip_prefix("Bearer "));
-
-//     match token {
-//         Some(token) => {
-//             let secret = env::var("JWT_SECRET").map_err(|_| CustomError::UnauthorizedError("JWT_SECRET must be set".to_string()))?;
-//             let key = DecodingKey::from_secret(secret.as_bytes());
-//             let validation = Validation::default();
-
-//             match decode::<Claims>(token, &key, &validation) {
-//                 Ok(token_data) => {
-//                     req.extensions_mut().insert(token_data.claims.id);
-//                     Ok(req)
-//                 }
-//                 Err(_) => Err(CustomError::UnauthorizedError("Invalid token".to_string()).into()),
-//             }
-//         }
-//         None => Err(CustomError::UnauthorizedError("Authorization header is missing or invalid".to_string()).into()),
-//     }
-// }
-
-// ... rest of your code
-
-// pub fn verify_token(token: &str, expected_id: &str) -> Result<Claims, actix_web::Error> {
-//      let secret = std::env::var("ACCESS_TOKEN_SECRET")
-//          .expect("ACCESS_TOKEN_SECRET must be set");
-
-//      match decode::<Claims>(
-//          token,
-//          &DecodingKey::from_secret(secret.as_bytes()),
-//          &Validation::default(),
-//      ) {
-//          Ok(token_data) => {
-//              if token_data.claims.id == expected_id {
-//                  Ok(token_data.claims)
-//              } else {
-//                  Err(ErrorUnauthorized("Invalid token: ID mismatch"))
-//              }
-//          }
-//          Err(err) => {
-//              match err.kind() {
-//                  jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
-//                      Err(ErrorUnauthorized("Token has expired"))
-//                  }
-//                  _ => Err(ErrorUnauthorized("Invalid token"))
-//              }
-//          }
-//      }
-//  }
+pub(crate) async fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, Error> {
+    let claims = verify_token(req).await?;
+    let id = ObjectId::parse_str(&claims.id)
+        .map_err(|_| CustomError::BadRequestError("Invalid user ID format in token".to_string()))?;
+
+    Ok(AuthenticatedUser { id, claims })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Already resolved by `RequireAuth` for this request
+        if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+            let user = user.clone();
+            return Box::pin(async move { Ok(user) });
+        }
+
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+/// Guard middleware that rejects unauthenticated requests for a whole scope,
+/// e.g. `web::scope("/todos").wrap(RequireAuth)`.
+pub struct RequireAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for RequireAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireAuthMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequireAuthMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            match authenticate(req.request()).await {
+                Ok(user) => {
+                    req.extensions_mut().insert(user);
+                    service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body)
+                }
+                Err(e) => Ok(req.error_response(e).map_into_right_body()),
+            }
+        })
+    }
+}
@@ -1,3 +1,5 @@
 pub mod not_found;
 pub mod error_handler;
 pub mod auth;
+pub mod rbac;
+pub mod rate_limit;
\ No newline at end of file
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use super::{Bucket, BucketState, RateLimitPolicy, RateLimitStore};
+use crate::utils::error::CustomError;
+
+// Full buckets are dropped at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+// The oldest bucket is dropped to make room beyond this many keys
+const MAX_KEYS: usize = 100_000;
+
+/// Keeps buckets in process memory. Limits are per server instance.
+///
+/// Memory is bounded: once `MAX_KEYS` callers hold a bucket, a new caller
+/// replaces the oldest one, which at worst resets that caller's limit.
+pub struct MemoryStore {
+    state: Mutex<State>,
+    max_keys: usize,
+    prune_interval: Duration,
+}
+
+struct State {
+    buckets: HashMap<String, Bucket>,
+    // Keys of `buckets`, oldest first
+    order: VecDeque<String>,
+    pruned_at: Instant,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::with_limits(MAX_KEYS, PRUNE_INTERVAL)
+    }
+
+    fn with_limits(max_keys: usize, prune_interval: Duration) -> Self {
+        MemoryStore {
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                order: VecDeque::new(),
+                pruned_at: Instant::now(),
+            }),
+            max_keys,
+            prune_interval,
+        }
+    }
+
+    fn acquire_at(
+        &self,
+        key: &str,
+        policy: &RateLimitPolicy,
+        now: Instant,
+    ) -> Result<BucketState, CustomError> {
+        let mut state = self.state.lock().map_err(|_| {
+            CustomError::InternalServerError("Rate limit store is poisoned".to_string())
+        })?;
+
+        let State {
+            buckets,
+            order,
+            pruned_at,
+        } = &mut *state;
+
+        if now.saturating_duration_since(*pruned_at) >= self.prune_interval {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+            order.retain(|key| buckets.contains_key(key));
+            *pruned_at = now;
+        }
+
+        if let Some(bucket) = buckets.get_mut(key) {
+            return Ok(bucket.take(policy, now));
+        }
+        while buckets.len() >= self.max_keys {
+            match order.pop_front() {
+                Some(oldest) => buckets.remove(&oldest),
+                None => break,
+            };
+        }
+
+        let mut bucket = Bucket::full(policy, now);
+        let result = bucket.take(policy, now);
+        buckets.insert(key.to_string(), bucket);
+        order.push_back(key.to_string());
+        Ok(result)
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn acquire(
+        &self,
+        key: &str,
+        policy: &RateLimitPolicy,
+    ) -> Result<BucketState, CustomError> {
+        self.acquire_at(key, policy, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::rate_limit::KeyBy;
+
+    fn policy() -> RateLimitPolicy {
+        RateLimitPolicy::new("memory_test", 2, 60, KeyBy::Ip)
+    }
+
+    fn key_count(store: &MemoryStore) -> usize {
+        store.state.lock().unwrap().buckets.len()
+    }
+
+    #[test]
+    fn keeps_one_bucket_per_key() {
+        let store = MemoryStore::new();
+        let (policy, now) = (policy(), Instant::now());
+
+        assert!(store.acquire_at("a", &policy, now).unwrap().allowed);
+        assert!(store.acquire_at("a", &policy, now).unwrap().allowed);
+        assert!(!store.acquire_at("a", &policy, now).unwrap().allowed);
+        assert!(store.acquire_at("b", &policy, now).unwrap().allowed);
+        assert_eq!(key_count(&store), 2);
+    }
+
+    #[test]
+    fn new_callers_get_through_at_capacity() {
+        let store = MemoryStore::with_limits(2, Duration::from_secs(10));
+        let (policy, now) = (policy(), Instant::now());
+
+        store.acquire_at("a", &policy, now).unwrap();
+        store.acquire_at("b", &policy, now).unwrap();
+        assert!(store.acquire_at("c", &policy, now).unwrap().allowed);
+
+        // The oldest bucket made room; the others are kept as they were
+        assert_eq!(key_count(&store), 2);
+        assert!(!store.state.lock().unwrap().buckets.contains_key("a"));
+        assert!(store.acquire_at("b", &policy, now).unwrap().allowed);
+        assert!(!store.acquire_at("b", &policy, now).unwrap().allowed);
+    }
+
+    #[test]
+    fn prunes_full_buckets_once_per_interval() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        let (policy, start) = (policy(), Instant::now());
+
+        store.acquire_at("a", &policy, start).unwrap();
+        store.acquire_at("b", &policy, start).unwrap();
+
+        // Both buckets are full again after 30 seconds, but are only dropped
+        // once the interval has passed
+        store
+            .acquire_at("c", &policy, start + Duration::from_secs(40))
+            .unwrap();
+        assert_eq!(key_count(&store), 3);
+
+        store
+            .acquire_at("d", &policy, start + Duration::from_secs(100))
+            .unwrap();
+        assert_eq!(key_count(&store), 1);
+    }
+}
@@ -0,0 +1,381 @@
+mod memory;
+
+pub use memory::MemoryStore;
+
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{web, Error, HttpMessage, HttpRequest, ResponseError};
+use async_trait::async_trait;
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::middleware::auth::AuthenticatedUser;
+use crate::model::access_token_model::ACCESS_TOKEN_PREFIX;
+use crate::service::user_service::Claims;
+use crate::utils::error::CustomError;
+use crate::utils::session_cookie::SESSION_COOKIE;
+use crate::utils::{jwt, request};
+
+/// What a bucket is shared by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyBy {
+    /// The client IP address.
+    Ip,
+    /// The user making the request, falling back to the IP for anonymous
+    /// callers and for access tokens that have not been verified yet.
+    Caller,
+}
+
+/// A token bucket: up to `capacity` requests at once, refilled evenly over
+/// `period`.
+#[derive(Debug, Clone)]
+pub struct RateLimitPolicy {
+    name: String,
+    capacity: u32,
+    period: Duration,
+    key_by: KeyBy,
+}
+
+impl RateLimitPolicy {
+    /// Builds a policy, letting `RATE_LIMIT_<NAME>` override the defaults as
+    /// `<capacity>/<seconds>`, e.g. `RATE_LIMIT_LOGIN=10/60`.
+    pub fn new(name: &str, capacity: u32, period_secs: u64, key_by: KeyBy) -> Self {
+        let (capacity, period_secs) = std::env::var(format!("RATE_LIMIT_{}", name.to_uppercase()))
+            .ok()
+            .and_then(|value| {
+                let (capacity, period) = value.split_once('/')?;
+                Some((capacity.trim().parse().ok()?, period.trim().parse().ok()?))
+            })
+            .unwrap_or((capacity, period_secs));
+
+        RateLimitPolicy {
+            name: name.to_string(),
+            capacity: capacity.max(1),
+            period: Duration::from_secs(period_secs.max(1)),
+            key_by,
+        }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.capacity) / self.period.as_secs_f64()
+    }
+}
+
+/// Outcome of taking a token, used for the `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketState {
+    pub allowed: bool,
+    pub remaining: u32,
+    // Seconds until the bucket is full again
+    pub reset_secs: u64,
+    // Seconds until the next token, when `allowed` is false
+    pub retry_after_secs: u64,
+}
+
+/// Token bucket arithmetic, shared by every store.
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+impl Bucket {
+    pub fn full(policy: &RateLimitPolicy, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(policy.capacity),
+            updated_at: now,
+            full_at: now,
+        }
+    }
+
+    pub fn take(&mut self, policy: &RateLimitPolicy, now: Instant) -> BucketState {
+        let rate = policy.refill_per_sec();
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(policy.capacity));
+        self.updated_at = now;
+
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+
+        let until_full = (f64::from(policy.capacity) - self.tokens) / rate;
+        self.full_at = now + Duration::from_secs_f64(until_full);
+
+        BucketState {
+            allowed,
+            remaining: self.tokens.floor() as u32,
+            reset_secs: until_full.ceil() as u64,
+            retry_after_secs: ((1.0 - self.tokens).max(0.0) / rate).ceil() as u64,
+        }
+    }
+
+    /// A full bucket is the same as no bucket, so stores may drop it.
+    pub fn is_full(&self, now: Instant) -> bool {
+        now >= self.full_at
+    }
+}
+
+/// Storage for buckets. Implement it over a shared backend to enforce limits
+/// across several server instances.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Refills the bucket `key` and takes one token from it.
+    async fn acquire(
+        &self,
+        key: &str,
+        policy: &RateLimitPolicy,
+    ) -> Result<BucketState, CustomError>;
+}
+
+/// Builds the store selected by `RATE_LIMIT_STORE` (`memory`, the default).
+pub fn store_from_env() -> Result<Arc<dyn RateLimitStore>, String> {
+    let store = std::env::var("RATE_LIMIT_STORE").unwrap_or_else(|_| "memory".to_string());
+
+    match store.as_str() {
+        "memory" => Ok(Arc::new(MemoryStore::new())),
+        other => Err(format!("Unsupported RATE_LIMIT_STORE: {}", other)),
+    }
+}
+
+/// Middleware that throttles requests with a token bucket per caller.
+///
+/// Wrap a scope, resource or route with a policy, e.g.
+/// `web::post().to(handler).wrap(RateLimit::new(RateLimitPolicy::new("login", 10, 60, KeyBy::Ip)))`.
+/// Buckets live in the `RateLimitStore` registered as app data. Every response
+/// carries `RateLimit-*` headers; a caller out of tokens gets a 429.
+#[derive(Clone)]
+pub struct RateLimit {
+    policy: Rc<RateLimitPolicy>,
+}
+
+impl RateLimit {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        RateLimit {
+            policy: Rc::new(policy),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            policy: Rc::clone(&self.policy),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    policy: Rc<RateLimitPolicy>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let policy = Rc::clone(&self.policy);
+
+        Box::pin(async move {
+            let store = match req.app_data::<web::Data<dyn RateLimitStore>>() {
+                Some(store) => store.clone(),
+                None => {
+                    let e = CustomError::InternalServerError(
+                        "Rate limit store is not configured".to_string(),
+                    );
+                    return Ok(req.error_response(e).map_into_right_body());
+                }
+            };
+
+            let key = format!("{}:{}", policy.name, bucket_key(&req, policy.key_by));
+            let state = match store.acquire(&key, &policy).await {
+                Ok(state) => state,
+                Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+            };
+
+            if !state.allowed {
+                let mut response = CustomError::TooManyRequestsError(
+                    "Rate limit exceeded, try again later".to_string(),
+                    state.retry_after_secs.max(1),
+                )
+                .error_response();
+                insert_headers(response.headers_mut(), &policy, &state);
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut response = service.call(req).await?;
+            insert_headers(response.headers_mut(), &policy, &state);
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+fn bucket_key(req: &ServiceRequest, key_by: KeyBy) -> String {
+    let ip = || format!("ip:{}", request::client_ip(req.request()));
+
+    match key_by {
+        KeyBy::Ip => ip(),
+        KeyBy::Caller => caller_key(req).unwrap_or_else(ip),
+    }
+}
+
+// Identifies the caller without hitting the database. Only signed tokens are
+// trusted here: an access token is just a string until it has been looked
+// up, and keying by it would hand out a fresh bucket per made-up token.
+fn caller_key(req: &ServiceRequest) -> Option<String> {
+    if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+        return Some(format!("user:{}", user.id));
+    }
+
+    let http_request: &HttpRequest = req.request();
+    let bearer = http_request
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| {
+            header
+                .strip_prefix("Bearer ")
+                .or_else(|| header.strip_prefix("DPoP "))
+        })
+        .map(str::to_string);
+
+    match bearer {
+        Some(token) if token.starts_with(ACCESS_TOKEN_PREFIX) => None,
+        Some(token) => user_key(&token),
+        None => user_key(http_request.cookie(SESSION_COOKIE)?.value()),
+    }
+}
+
+fn user_key(token: &str) -> Option<String> {
+    jwt::decode_token::<Claims>(token)
+        .ok()
+        .map(|claims| format!("user:{}", claims.id))
+}
+
+fn insert_headers(headers: &mut HeaderMap, policy: &RateLimitPolicy, state: &BucketState) {
+    let values = [
+        ("ratelimit-limit", policy.capacity.to_string()),
+        ("ratelimit-remaining", state.remaining.to_string()),
+        ("ratelimit-reset", state.reset_secs.to_string()),
+        (
+            "ratelimit-policy",
+            format!("{};w={}", policy.capacity, policy.period.as_secs()),
+        ),
+    ];
+
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 requests per 60 seconds: one token every 6 seconds
+    fn policy() -> RateLimitPolicy {
+        RateLimitPolicy::new("bucket_test", 10, 60, KeyBy::Ip)
+    }
+
+    #[test]
+    fn full_bucket_allows_a_burst_of_capacity() {
+        let (policy, now) = (policy(), Instant::now());
+        let mut bucket = Bucket::full(&policy, now);
+
+        for remaining in (0..10).rev() {
+            let state = bucket.take(&policy, now);
+            assert!(state.allowed);
+            assert_eq!(state.remaining, remaining);
+        }
+
+        let state = bucket.take(&policy, now);
+        assert!(!state.allowed);
+        assert_eq!(state.remaining, 0);
+        assert_eq!(state.retry_after_secs, 6);
+        assert_eq!(state.reset_secs, 60);
+    }
+
+    #[test]
+    fn refills_evenly_over_the_period() {
+        let (policy, start) = (policy(), Instant::now());
+        let mut bucket = Bucket::full(&policy, start);
+        for _ in 0..10 {
+            bucket.take(&policy, start);
+        }
+
+        assert!(!bucket.take(&policy, start + Duration::from_secs(5)).allowed);
+        let state = bucket.take(&policy, start + Duration::from_secs(7));
+        assert!(state.allowed);
+        assert_eq!(state.remaining, 0);
+    }
+
+    #[test]
+    fn refill_stops_at_capacity() {
+        let (policy, start) = (policy(), Instant::now());
+        let mut bucket = Bucket::full(&policy, start);
+        bucket.take(&policy, start);
+
+        let state = bucket.take(&policy, start + Duration::from_secs(3600));
+        assert_eq!(state.remaining, 9);
+    }
+
+    #[test]
+    fn bucket_is_full_once_every_token_is_back() {
+        let (policy, start) = (policy(), Instant::now());
+        let mut bucket = Bucket::full(&policy, start);
+        assert!(bucket.is_full(start));
+
+        bucket.take(&policy, start);
+        bucket.take(&policy, start);
+        assert!(!bucket.is_full(start + Duration::from_secs(11)));
+        assert!(bucket.is_full(start + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn policy_never_has_zero_capacity_or_period() {
+        let policy = RateLimitPolicy::new("zero_test", 0, 0, KeyBy::Ip);
+        assert_eq!(policy.capacity, 1);
+        assert_eq!(policy.period, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unverified_access_tokens_are_keyed_by_ip() {
+        let request = |token: &str| {
+            actix_web::test::TestRequest::default()
+                .peer_addr("203.0.113.7:4000".parse().unwrap())
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_srv_request()
+        };
+
+        for token in ["pat_first", "pat_second"] {
+            assert_eq!(bucket_key(&request(token), KeyBy::Caller), "ip:203.0.113.7");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::middleware::auth::{authenticate, AuthenticatedUser};
+use crate::model::user_model::{Permission, Role};
+use crate::utils::error::CustomError;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy)]
+enum Requirement {
+    Role(Role),
+    Permission(Permission),
+}
+
+/// Middleware that requires the caller to hold a role or permission.
+///
+/// Works on a scope, resource or single route:
+/// `web::scope("/admin").wrap(Authorize::role(Role::Admin))` or
+/// `web::post().to(handler).wrap(Authorize::permission(Permission::WriteTodos))`.
+/// Unauthenticated callers get a 401, authenticated ones without the
+/// requirement a 403.
+#[derive(Debug, Clone, Copy)]
+pub struct Authorize {
+    requirement: Requirement,
+}
+
+impl Authorize {
+    pub fn role(role: Role) -> Self {
+        Authorize {
+            requirement: Requirement::Role(role),
+        }
+    }
+
+    pub fn permission(permission: Permission) -> Self {
+        Authorize {
+            requirement: Requirement::Permission(permission),
+        }
+    }
+
+    fn check(&self, user: &AuthenticatedUser) -> Result<(), CustomError> {
+        let allowed = match self.requirement {
+            Requirement::Role(role) => user.has_role(role),
+            Requirement::Permission(permission) => user.has_permission(permission),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(CustomError::ForbiddenError(
+                "You do not have permission to perform this action".to_string(),
+            ))
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Authorize
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizeMiddleware {
+            service: Rc::new(service),
+            authorize: *self,
+        }))
+    }
+}
+
+pub struct AuthorizeMiddleware<S> {
+    service: Rc<S>,
+    authorize: Authorize,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorizeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let authorize = self.authorize;
+
+        Box::pin(async move {
+            // Reuse the user resolved by an outer `RequireAuth` or `Authorize`
+            let existing = req.extensions().get::<AuthenticatedUser>().cloned();
+            let user = match existing {
+                Some(user) => user,
+                None => match authenticate(req.request()).await {
+                    Ok(user) => {
+                        req.extensions_mut().insert(user.clone());
+                        user
+                    }
+                    Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+                },
+            };
+
+            if let Err(e) = authorize.check(&user) {
+                return Ok(req.error_response(e).map_into_right_body());
+            }
+
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
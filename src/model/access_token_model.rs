@@ -0,0 +1,21 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::model::user_model::Permission;
+
+/// Prefix that tells personal access tokens apart from JWTs.
+pub const ACCESS_TOKEN_PREFIX: &str = "pat_";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PersonalAccessToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<Permission>,
+    pub expires_at: Option<DateTime>,
+    pub revoked: bool,
+    pub created_at: DateTime,
+    pub last_used_at: Option<DateTime>,
+}
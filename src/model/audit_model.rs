@@ -0,0 +1,269 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::error::CustomError;
+use crate::utils::request::RequestContext;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditAction {
+    #[serde(rename = "user.registered")]
+    UserRegistered,
+    #[serde(rename = "user.email_verified")]
+    EmailVerified,
+    #[serde(rename = "user.verification_resent")]
+    VerificationResent,
+    #[serde(rename = "user.password_reset_requested")]
+    PasswordResetRequested,
+    #[serde(rename = "user.password_reset")]
+    PasswordReset,
+    #[serde(rename = "user.password_changed")]
+    PasswordChanged,
+    #[serde(rename = "user.profile_updated")]
+    ProfileUpdated,
+    #[serde(rename = "user.account_deleted")]
+    AccountDeleted,
+    #[serde(rename = "user.account_restored")]
+    AccountRestored,
+    #[serde(rename = "user.account_purged")]
+    AccountPurged,
+    #[serde(rename = "auth.login")]
+    Login,
+    #[serde(rename = "auth.magic_link_requested")]
+    MagicLinkRequested,
+    #[serde(rename = "auth.magic_link")]
+    MagicLinkLogin,
+    #[serde(rename = "auth.passkey")]
+    PasskeyLogin,
+    #[serde(rename = "auth.mfa")]
+    MfaLogin,
+    #[serde(rename = "auth.refresh")]
+    TokenRefreshed,
+    #[serde(rename = "auth.logout")]
+    Logout,
+    #[serde(rename = "auth.logout_all")]
+    LogoutAll,
+    #[serde(rename = "auth.session_revoked")]
+    SessionRevoked,
+    #[serde(rename = "auth.token_rejected")]
+    TokenRejected,
+    #[serde(rename = "mfa.setup")]
+    TotpSetup,
+    #[serde(rename = "mfa.enabled")]
+    TotpEnabled,
+    #[serde(rename = "mfa.disabled")]
+    TotpDisabled,
+    #[serde(rename = "passkey.registered")]
+    PasskeyRegistered,
+    #[serde(rename = "passkey.removed")]
+    PasskeyRemoved,
+    #[serde(rename = "token.created")]
+    AccessTokenCreated,
+    #[serde(rename = "token.revoked")]
+    AccessTokenRevoked,
+    #[serde(rename = "admin.roles_changed")]
+    RolesChanged,
+    #[serde(rename = "admin.user_suspended")]
+    UserSuspended,
+    #[serde(rename = "admin.user_unsuspended")]
+    UserUnsuspended,
+    #[serde(rename = "admin.password_reset_forced")]
+    PasswordResetForced,
+    #[serde(rename = "admin.user_deleted")]
+    UserDeleted,
+    #[serde(rename = "admin.login_unlocked")]
+    LoginUnlocked,
+    #[serde(rename = "todo.created")]
+    TodoCreated,
+    #[serde(rename = "todo.updated")]
+    TodoUpdated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// `prev_hash` of the first entry in the chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One entry of the append-only audit log.
+///
+/// Entries form a hash chain: `hash` covers the entry's content and the
+/// previous entry's hash, so editing or removing any entry breaks every link
+/// after it. Entries written before chaining was introduced have `seq` 0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub action: AuditAction,
+    pub outcome: AuditOutcome,
+    // The authenticated caller, if any
+    pub actor_id: Option<ObjectId>,
+    // The account the action was applied to
+    pub target_id: Option<ObjectId>,
+    // Username or email the caller supplied when no account was resolved
+    pub subject: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    // Error message for failures
+    pub reason: Option<String>,
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub seq: i64,
+    #[serde(default)]
+    pub prev_hash: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, ctx: &RequestContext) -> Self {
+        AuditEvent {
+            id: None,
+            action,
+            outcome: AuditOutcome::Success,
+            actor_id: ctx.actor_id,
+            target_id: None,
+            subject: None,
+            ip: Some(ctx.ip.clone()),
+            user_agent: ctx.user_agent.clone(),
+            reason: None,
+            created_at: DateTime::now(),
+            seq: 0,
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    pub fn target(mut self, target_id: Option<ObjectId>) -> Self {
+        self.target_id = target_id;
+        self
+    }
+
+    pub fn subject(mut self, subject: &str) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    pub fn outcome<T>(self, result: &Result<T, CustomError>) -> Self {
+        match result {
+            Ok(_) => self,
+            Err(e) => self.failure(e.to_string()),
+        }
+    }
+
+    pub fn failure(mut self, reason: String) -> Self {
+        self.outcome = AuditOutcome::Failure;
+        self.reason = Some(reason);
+        self
+    }
+
+    /// SHA-256 over `prev_hash` and every recorded field, hex encoded.
+    pub fn chain_hash(&self) -> String {
+        let content = serde_json::json!([
+            self.seq,
+            self.prev_hash,
+            self.action,
+            self.outcome,
+            self.actor_id.map(|id| id.to_hex()),
+            self.target_id.map(|id| id.to_hex()),
+            self.subject,
+            self.ip,
+            self.user_agent,
+            self.reason,
+            self.created_at.timestamp_millis(),
+        ]);
+        hex::encode(Sha256::digest(content.to_string().as_bytes()))
+    }
+}
+
+/// The newest entry of the chain, kept in a single document so that appends
+/// find it with a point read and advance it with a compare-and-set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditHead {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub seq: i64,
+    pub hash: String,
+}
+
+/// Signed statement of the chain head at a point in time. Exported outside
+/// the database so that rewriting the whole chain is detectable too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditCheckpoint {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub seq: i64,
+    pub hash: String,
+    // JWT over `CheckpointClaims`, signed with the active JWT key
+    pub signature: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckpointClaims {
+    pub seq: i64,
+    pub hash: String,
+    pub iat: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> AuditEvent {
+        let mut event = AuditEvent::new(AuditAction::Login, &RequestContext::system())
+            .subject("alice")
+            .failure("Invalid credentials".to_string());
+        event.seq = 1;
+        event.prev_hash = GENESIS_HASH.to_string();
+        event
+    }
+
+    #[test]
+    fn chain_hash_is_stable_hex_sha256() {
+        let event = event();
+        let hash = event.chain_hash();
+
+        assert_eq!(hash, event.clone().chain_hash());
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn chain_hash_covers_every_recorded_field() {
+        let original = event().chain_hash();
+        let edits: Vec<fn(&mut AuditEvent)> = vec![
+            |e| e.seq = 2,
+            |e| e.prev_hash = "1".repeat(64),
+            |e| e.action = AuditAction::MfaLogin,
+            |e| e.outcome = AuditOutcome::Success,
+            |e| e.actor_id = Some(ObjectId::new()),
+            |e| e.target_id = Some(ObjectId::new()),
+            |e| e.subject = Some("mallory".to_string()),
+            |e| e.ip = Some("203.0.113.7".to_string()),
+            |e| e.user_agent = Some("curl/8.0".to_string()),
+            |e| e.reason = None,
+            |e| e.created_at = DateTime::from_millis(e.created_at.timestamp_millis() + 1),
+        ];
+
+        for (i, edit) in edits.into_iter().enumerate() {
+            let mut edited = event();
+            edit(&mut edited);
+            assert_ne!(edited.chain_hash(), original, "edit {}", i);
+        }
+    }
+
+    #[test]
+    fn chain_hash_ignores_the_stored_hash() {
+        let mut event = event();
+        let hash = event.chain_hash();
+        event.hash = hash.clone();
+        event.id = Some(ObjectId::new());
+
+        assert_eq!(event.chain_hash(), hash);
+    }
+}
@@ -0,0 +1,14 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// A DPoP proof that has been accepted once. Kept until the proof would be
+/// too old to pass the freshness check anyway.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DpopProof {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // JWK thumbprint of the key that signed the proof
+    pub jkt: String,
+    pub jti: String,
+    pub expires_at: DateTime,
+}
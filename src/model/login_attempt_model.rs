@@ -0,0 +1,33 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// Failed login counter for one key, `user:<username>` or `ip:<address>`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginAttempt {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: DateTime,
+    pub locked_until: Option<DateTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockoutAction {
+    Locked,
+    Unlocked,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockoutEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub key: String,
+    pub action: LockoutAction,
+    pub failures: i32,
+    pub locked_until: Option<DateTime>,
+    // Set when an administrator lifted the lockout
+    pub actor_id: Option<ObjectId>,
+    pub created_at: DateTime,
+}
@@ -0,0 +1,15 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// Server-side record of an emailed login link, so each link works once.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MagicLink {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // `jti` of the signed link token
+    pub jti: String,
+    pub expires_at: DateTime,
+    pub used_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
@@ -1,2 +1,12 @@
 pub mod user_model;
-pub mod todo_model;
\ No newline at end of file
+pub mod todo_model;
+pub mod refresh_token_model;
+pub mod revocation_model;
+pub mod access_token_model;
+pub mod password_reset_model;
+pub mod login_attempt_model;
+pub mod audit_model;
+pub mod magic_link_model;
+pub mod passkey_model;
+pub mod session_model;
+pub mod dpop_model;
\ No newline at end of file
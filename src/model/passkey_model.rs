@@ -0,0 +1,38 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// A WebAuthn credential registered to a user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Passkey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Base64url, as browsers report it
+    pub credential_id: String,
+    // Base64url SEC1 encoded P-256 public key
+    pub public_key: String,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: DateTime,
+    pub last_used_at: Option<DateTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasskeyCeremony {
+    Registration,
+    Authentication,
+}
+
+/// A challenge handed out when a ceremony begins. Deleted when the response
+/// comes back, so each challenge is answered once.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasskeyChallenge {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub challenge: String,
+    pub ceremony: PasskeyCeremony,
+    // Set for registrations, which are tied to the signed in user
+    pub user_id: Option<ObjectId>,
+    pub expires_at: DateTime,
+}
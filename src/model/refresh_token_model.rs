@@ -0,0 +1,19 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Every token issued by rotation shares the family of the login that started it
+    pub family_id: ObjectId,
+    pub token_hash: String,
+    pub revoked: bool,
+    pub replaced_by: Option<String>,
+    pub expires_at: DateTime,
+    pub created_at: DateTime,
+    // Thumbprint of the DPoP key the family is bound to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+}
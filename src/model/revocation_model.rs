@@ -0,0 +1,22 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// A single access token that was revoked before it expired.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevokedToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub jti: String,
+    pub user_id: ObjectId,
+    // Kept until the token would have expired anyway
+    pub expires_at: DateTime,
+}
+
+/// Every access token issued to `user_id` before `revoked_before` is revoked.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserRevocation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub revoked_before: DateTime,
+}
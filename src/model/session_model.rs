@@ -0,0 +1,22 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// One login on one device. The session ID doubles as the family ID of the
+/// refresh tokens issued for it and is carried in the `sid` access token
+/// claim.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    // Derived from the User-Agent, e.g. "Firefox on Linux"
+    pub device_name: String,
+    pub user_agent: Option<String>,
+    // Address of the most recent use
+    pub ip: String,
+    pub created_at: DateTime,
+    pub last_used_at: DateTime,
+    // Pushed forward on every refresh, like the refresh token expiry
+    pub expires_at: DateTime,
+    pub revoked_at: Option<DateTime>,
+}
@@ -1,8 +1,52 @@
-use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
 use serde::{Deserialize, Serialize};
 
-use crate::utils::hashing;
-use bcrypt;
+use crate::utils::{hashing, identity};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    #[serde(rename = "todos:read")]
+    ReadTodos,
+    #[serde(rename = "todos:write")]
+    WriteTodos,
+    #[serde(rename = "users:manage")]
+    ManageUsers,
+    #[serde(rename = "audit:read")]
+    ReadAudit,
+    #[serde(rename = "metrics:read")]
+    ReadMetrics,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::User => &[Permission::ReadTodos, Permission::WriteTodos],
+            Role::Admin => &[
+                Permission::ReadTodos,
+                Permission::WriteTodos,
+                Permission::ManageUsers,
+                Permission::ReadAudit,
+                Permission::ReadMetrics,
+            ],
+        }
+    }
+}
+
+pub fn default_roles() -> Vec<Role> {
+    vec![Role::User]
+}
+
+// Accounts created before email verification existed count as verified
+fn default_email_verified() -> bool {
+    true
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
@@ -10,7 +54,66 @@ pub struct User {
     pub id: Option<ObjectId>,
     pub username: String,
     pub email: String,
+    // Canonical forms from `identity::normalize`, unique across users
+    #[serde(default)]
+    pub username_normalized: String,
+    #[serde(default)]
+    pub email_normalized: String,
     pub password: String,
+    #[serde(default = "default_roles")]
+    pub roles: Vec<Role>,
+    #[serde(default = "default_email_verified")]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    // Secret of an enrollment that has not been confirmed with a code yet
+    #[serde(default)]
+    pub totp_pending_secret: Option<String>,
+    // Last accepted time step, so a code cannot be replayed
+    #[serde(default)]
+    pub totp_last_step: Option<i64>,
+    // Hashes of the unused recovery codes
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    // Set while a deleted account can still be restored
+    #[serde(default)]
+    pub deleted_at: Option<DateTime>,
+    #[serde(default)]
+    pub purge_after: Option<DateTime>,
+    #[serde(default)]
+    pub suspended: bool,
+    #[serde(default)]
+    pub suspended_reason: Option<String>,
+    // Set by an administrator; login is refused until the password is reset
+    #[serde(default)]
+    pub password_reset_required: bool,
+}
+
+/// What a user may see of their own account. `User` itself is never
+/// returned from handlers since it carries the password hash and 2FA secrets.
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub roles: Vec<Role>,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+}
+
+impl From<&User> for UserProfile {
+    fn from(user: &User) -> Self {
+        UserProfile {
+            id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            roles: user.roles.clone(),
+            email_verified: user.email_verified,
+            totp_enabled: user.totp_enabled,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -22,13 +125,27 @@ impl User {
         };
         User {
             id: None,
+            username_normalized: identity::normalize(&username),
+            email_normalized: identity::normalize(&email),
             username,
             email,
             password: hashed_password,
+            roles: default_roles(),
+            email_verified: false,
+            totp_enabled: false,
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+            deleted_at: None,
+            purge_after: None,
+            suspended: false,
+            suspended_reason: None,
+            password_reset_required: false,
         }
     }
 
-    pub fn verify_password(&self, password: &str) -> Result<bool, bcrypt::BcryptError> {
+    pub fn verify_password(&self, password: &str) -> Result<bool, hashing::HashError> {
         hashing::verify_password(password, &self.password)
     }
 }
@@ -1,21 +1,284 @@
-use crate::controller::{todo_controller, user_controller};
+use crate::controller::{
+    access_token_controller, admin_controller, key_controller, metrics_controller, mfa_controller,
+    passkey_controller, todo_controller, user_controller,
+};
+use crate::middleware::auth::RequireAuth;
+use crate::middleware::rate_limit::{KeyBy, RateLimit, RateLimitPolicy};
+use crate::middleware::rbac::Authorize;
+use crate::model::user_model::{Permission, Role};
 use actix_web::web;
 
+// Routes sharing a policy name share its buckets
+fn login_limit() -> RateLimit {
+    RateLimit::new(RateLimitPolicy::new("login", 10, 60, KeyBy::Ip))
+}
+
+fn email_limit() -> RateLimit {
+    RateLimit::new(RateLimitPolicy::new("email", 5, 900, KeyBy::Ip))
+}
+
+// Requests that re-check the password of a signed-in user
+fn reauth_limit() -> RateLimit {
+    RateLimit::new(RateLimitPolicy::new("reauth", 5, 300, KeyBy::Caller))
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/.well-known/jwks.json", web::get().to(key_controller::jwks));
+    // Scrapers authenticate with an access token scoped to `metrics:read`
+    cfg.route(
+        "/metrics",
+        web::get()
+            .to(metrics_controller::metrics)
+            .wrap(Authorize::permission(Permission::ReadMetrics))
+            .wrap(RateLimit::new(RateLimitPolicy::new(
+                "metrics",
+                60,
+                60,
+                KeyBy::Caller,
+            ))),
+    );
     cfg.service(
         web::scope("/v1")
+            .wrap(RateLimit::new(RateLimitPolicy::new(
+                "api",
+                300,
+                60,
+                KeyBy::Caller,
+            )))
             .service(
                 web::scope("/users")
-                    .route("/register", web::post().to(user_controller::register_user))
-                    .route("/login", web::post().to(user_controller::login_user)), // Add more routes here
+                    .route(
+                        "/register",
+                        web::post()
+                            .to(user_controller::register_user)
+                            .wrap(RateLimit::new(RateLimitPolicy::new(
+                                "register",
+                                5,
+                                3600,
+                                KeyBy::Ip,
+                            ))),
+                    )
+                    .route(
+                        "/login",
+                        web::post()
+                            .to(user_controller::login_user)
+                            .wrap(login_limit()),
+                    )
+                    .route(
+                        "/login/mfa",
+                        web::post()
+                            .to(mfa_controller::login_mfa)
+                            .wrap(login_limit()),
+                    )
+                    .route(
+                        "/magic-link",
+                        web::post()
+                            .to(user_controller::request_magic_link)
+                            .wrap(email_limit()),
+                    )
+                    .route(
+                        "/magic-link/login",
+                        web::post()
+                            .to(user_controller::login_magic_link)
+                            .wrap(login_limit()),
+                    )
+                    .route(
+                        "/restore",
+                        web::post()
+                            .to(user_controller::restore_account)
+                            .wrap(login_limit()),
+                    )
+                    .route("/refresh", web::post().to(user_controller::refresh_token))
+                    .route("/logout", web::post().to(user_controller::logout_user))
+                    .route("/logout-all", web::post().to(user_controller::logout_all))
+                    .route("/verify-email", web::get().to(user_controller::verify_email))
+                    .route(
+                        "/verify-email/resend",
+                        web::post()
+                            .to(user_controller::resend_verification)
+                            .wrap(email_limit()),
+                    )
+                    .route(
+                        "/password/forgot",
+                        web::post()
+                            .to(user_controller::forgot_password)
+                            .wrap(email_limit()),
+                    )
+                    .route(
+                        "/password/reset",
+                        web::post().to(user_controller::reset_password),
+                    )
+                    .route("/me", web::get().to(user_controller::get_me))
+                    .route("/me", web::patch().to(user_controller::update_me))
+                    .route("/me", web::delete().to(user_controller::delete_me))
+                    .route(
+                        "/me/password",
+                        web::post().to(user_controller::change_password),
+                    )
+                    .route(
+                        "/me/sessions",
+                        web::get().to(user_controller::list_sessions),
+                    )
+                    .route(
+                        "/me/sessions/{id}",
+                        web::delete().to(user_controller::revoke_session),
+                    )
+                    .route(
+                        "/me/tokens",
+                        web::post().to(access_token_controller::create_token),
+                    )
+                    .route(
+                        "/me/tokens",
+                        web::get().to(access_token_controller::list_tokens),
+                    )
+                    .route(
+                        "/me/tokens/{id}",
+                        web::delete().to(access_token_controller::revoke_token),
+                    )
+                    .route(
+                        "/passkeys/register/options",
+                        web::post()
+                            .to(passkey_controller::registration_options)
+                            .wrap(reauth_limit()),
+                    )
+                    .route(
+                        "/passkeys/register",
+                        web::post().to(passkey_controller::register_passkey),
+                    )
+                    .route(
+                        "/passkeys/login/options",
+                        web::post()
+                            .to(passkey_controller::login_options)
+                            .wrap(login_limit()),
+                    )
+                    .route(
+                        "/passkeys/login",
+                        web::post()
+                            .to(passkey_controller::login_passkey)
+                            .wrap(login_limit()),
+                    )
+                    .route(
+                        "/passkeys",
+                        web::get().to(passkey_controller::list_passkeys),
+                    )
+                    .route(
+                        "/passkeys/{id}",
+                        web::delete().to(passkey_controller::remove_passkey),
+                    )
+                    .route("/me/2fa/setup", web::post().to(mfa_controller::setup_totp))
+                    .route("/me/2fa/enable", web::post().to(mfa_controller::enable_totp))
+                    .route("/me/2fa/disable", web::post().to(mfa_controller::disable_totp)), // Add more routes here
             )
             .service(
                 web::scope("/todos")
-                    .route("", web::post().to(todo_controller::create_todo))
-                    .route("", web::get().to(todo_controller::list_all))
-                    .route("/{id}", web::get().to(todo_controller::list_one))
-                    .route("/{id}", web::put().to(todo_controller::update_todo)),
+                    .wrap(RequireAuth)
+                    .route(
+                        "",
+                        web::post()
+                            .to(todo_controller::create_todo)
+                            .wrap(Authorize::permission(Permission::WriteTodos)),
+                    )
+                    .route(
+                        "",
+                        web::get()
+                            .to(todo_controller::list_all)
+                            .wrap(Authorize::permission(Permission::ReadTodos)),
+                    )
+                    .route(
+                        "/{id}",
+                        web::get()
+                            .to(todo_controller::list_one)
+                            .wrap(Authorize::permission(Permission::ReadTodos)),
+                    )
+                    .route(
+                        "/{id}",
+                        web::put()
+                            .to(todo_controller::update_todo)
+                            .wrap(Authorize::permission(Permission::WriteTodos)),
+                    ),
                 // .route("/{id}", web::delete().to(todo_controller::delete_todo)),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(Authorize::role(Role::Admin))
+                    .route(
+                        "/users",
+                        web::get()
+                            .to(admin_controller::list_users)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}",
+                        web::get()
+                            .to(admin_controller::get_user)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}",
+                        web::delete()
+                            .to(admin_controller::delete_user)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}/suspend",
+                        web::post()
+                            .to(admin_controller::suspend_user)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}/unsuspend",
+                        web::post()
+                            .to(admin_controller::unsuspend_user)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}/force-password-reset",
+                        web::post()
+                            .to(admin_controller::force_password_reset)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}/revoke-sessions",
+                        web::post()
+                            .to(admin_controller::revoke_user_sessions)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/users/{id}/roles",
+                        web::put()
+                            .to(admin_controller::set_user_roles)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/lockouts",
+                        web::get()
+                            .to(admin_controller::list_lockouts)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/lockouts/unlock",
+                        web::post()
+                            .to(admin_controller::unlock_login)
+                            .wrap(Authorize::permission(Permission::ManageUsers)),
+                    )
+                    .route(
+                        "/audit",
+                        web::get()
+                            .to(admin_controller::list_audit_events)
+                            .wrap(Authorize::permission(Permission::ReadAudit)),
+                    )
+                    .route(
+                        "/audit/export",
+                        web::get()
+                            .to(admin_controller::export_audit_events)
+                            .wrap(Authorize::permission(Permission::ReadAudit)),
+                    )
+                    .route(
+                        "/audit/verify",
+                        web::get()
+                            .to(admin_controller::verify_audit_chain)
+                            .wrap(Authorize::permission(Permission::ReadAudit)),
+                    ),
             ),
     );
 }
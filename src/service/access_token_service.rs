@@ -0,0 +1,154 @@
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Client, ClientSession, Collection};
+
+use crate::model::access_token_model::{PersonalAccessToken, ACCESS_TOKEN_PREFIX};
+use crate::model::user_model::Permission;
+use crate::utils::error::CustomError;
+use crate::utils::token;
+
+pub struct AccessTokenService {
+    collection: Collection<PersonalAccessToken>,
+}
+
+/// Longest lifetime a token can be created with, in days.
+pub const MAX_TOKEN_LIFETIME_DAYS: i64 = 3650;
+
+impl AccessTokenService {
+    pub fn new(client: &Client) -> Self {
+        let collection = client.database("Rust_PRo").collection("access_tokens");
+        AccessTokenService { collection }
+    }
+
+    /// Creates a token and returns it with its plaintext value, which is
+    /// never stored and cannot be shown again.
+    pub async fn create_token(
+        &self,
+        user_id: ObjectId,
+        name: String,
+        scopes: Vec<Permission>,
+        expires_in_days: Option<i64>,
+    ) -> Result<(PersonalAccessToken, String), CustomError> {
+        let expires_at = match expires_in_days {
+            Some(days) => Some(
+                Duration::try_days(days)
+                    .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                    .map(|expires_at| DateTime::from_millis(expires_at.timestamp_millis()))
+                    .ok_or_else(|| {
+                        CustomError::ValidationError("expires_in_days is out of range".to_string())
+                    })?,
+            ),
+            None => None,
+        };
+        let plaintext = format!("{}{}", ACCESS_TOKEN_PREFIX, token::generate_token());
+
+        let mut access_token = PersonalAccessToken {
+            id: None,
+            user_id,
+            name,
+            token_hash: token::hash_token(&plaintext),
+            scopes,
+            expires_at,
+            revoked: false,
+            created_at: DateTime::now(),
+            last_used_at: None,
+        };
+
+        let result = self
+            .collection
+            .insert_one(&access_token, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        access_token.id = result.inserted_id.as_object_id();
+
+        Ok((access_token, plaintext))
+    }
+
+    pub async fn list_tokens(
+        &self,
+        user_id: ObjectId,
+    ) -> Result<Vec<PersonalAccessToken>, CustomError> {
+        let cursor = self
+            .collection
+            .find(doc! { "user_id": user_id, "revoked": false }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    pub async fn revoke_token(&self, user_id: ObjectId, id: ObjectId) -> Result<bool, CustomError> {
+        let result = self
+            .collection
+            .update_one(
+                doc! { "_id": id, "user_id": user_id, "revoked": false },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(result.modified_count == 1)
+    }
+
+    pub async fn revoke_all_for_user(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        self.collection
+            .update_many(
+                doc! { "user_id": user_id, "revoked": false },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Looks up an active token by its plaintext value and records its use.
+    pub async fn verify_token(&self, plaintext: &str) -> Result<PersonalAccessToken, CustomError> {
+        let access_token = self
+            .collection
+            .find_one(
+                doc! { "token_hash": token::hash_token(plaintext), "revoked": false },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| CustomError::UnauthorizedError("Invalid token".to_string()))?;
+
+        if matches!(access_token.expires_at, Some(expires_at) if expires_at < DateTime::now()) {
+            return Err(CustomError::UnauthorizedError(
+                "Token has expired".to_string(),
+            ));
+        }
+
+        self.collection
+            .update_one(
+                doc! { "_id": access_token.id },
+                doc! { "$set": { "last_used_at": DateTime::now() } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(access_token)
+    }
+
+    /// Removes every access token of a user as part of the caller's transaction.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        self.collection
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::options::{FindOneOptions, FindOptions, IndexOptions, UpdateOptions};
+use mongodb::{Client, Collection, Cursor, IndexModel};
+use rand::Rng;
+use serde::Serialize;
+
+use crate::database::{is_duplicate_key, page_offset};
+use crate::model::audit_model::{
+    AuditAction, AuditCheckpoint, AuditEvent, AuditHead, AuditOutcome, CheckpointClaims,
+    GENESIS_HASH,
+};
+use crate::utils::error::CustomError;
+use crate::utils::jwt;
+
+// Appends that lose a race for the next sequence number are retried this
+// many times, after a short random delay
+const MAX_APPEND_ATTEMPTS: u64 = 10;
+
+// `_id` of the document in `audit_heads` that tracks the audit log
+const HEAD_ID: &str = "audit_log";
+
+/// Criteria for searching the audit log. Unset fields match everything.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    pub outcome: Option<AuditOutcome>,
+    pub actor_id: Option<ObjectId>,
+    pub target_id: Option<ObjectId>,
+    pub ip: Option<String>,
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+}
+
+fn to_bson<T: Serialize>(value: &T) -> Result<Bson, CustomError> {
+    mongodb::bson::to_bson(value).map_err(|e| CustomError::InternalServerError(e.to_string()))
+}
+
+impl AuditFilter {
+    fn to_document(&self) -> Result<Document, CustomError> {
+        let mut filter = doc! {};
+        if let Some(action) = &self.action {
+            filter.insert("action", to_bson(action)?);
+        }
+        if let Some(outcome) = &self.outcome {
+            filter.insert("outcome", to_bson(outcome)?);
+        }
+        if let Some(actor_id) = self.actor_id {
+            filter.insert("actor_id", actor_id);
+        }
+        if let Some(target_id) = self.target_id {
+            filter.insert("target_id", target_id);
+        }
+        if let Some(ip) = &self.ip {
+            filter.insert("ip", ip);
+        }
+
+        let mut created_at = doc! {};
+        if let Some(from) = self.from {
+            created_at.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            created_at.insert("$lt", to);
+        }
+        if !created_at.is_empty() {
+            filter.insert("created_at", created_at);
+        }
+
+        Ok(filter)
+    }
+}
+
+/// Where a chain verification stopped.
+#[derive(Debug, Serialize)]
+pub struct BrokenLink {
+    pub seq: i64,
+    pub id: Option<String>,
+    pub reason: String,
+}
+
+/// Result of walking the audit chain from the first entry to the head.
+#[derive(Debug, Serialize)]
+pub struct ChainReport {
+    // Chained entries that verified before the first broken link
+    pub verified: u64,
+    pub checkpoints_verified: u64,
+    // Entries written before hash chaining, which cannot be verified
+    pub unchained: u64,
+    pub head_seq: i64,
+    pub broken: Option<BrokenLink>,
+}
+
+/// Append-only store of security relevant events. Entries are never updated
+/// or deleted through this service.
+///
+/// Every entry is hash-chained to the one before it, and `create_checkpoint`
+/// periodically signs the chain head. Set `AUDIT_CHECKPOINT_PATH` to also
+/// append each checkpoint to a file kept outside the database.
+pub struct AuditService {
+    collection: Collection<AuditEvent>,
+    checkpoints: Collection<AuditCheckpoint>,
+    heads: Collection<AuditHead>,
+}
+
+impl AuditService {
+    pub fn new(client: &Client) -> Self {
+        let database = client.database("Rust_PRo");
+        AuditService {
+            collection: database.collection("audit_log"),
+            checkpoints: database.collection("audit_checkpoints"),
+            heads: database.collection("audit_heads"),
+        }
+    }
+
+    /// Creates the unique `seq` index that keeps concurrent appends from
+    /// forking the chain, and the head document appends start from. Call once
+    /// at startup.
+    pub async fn ensure_indexes(&self) -> Result<(), CustomError> {
+        let index = IndexModel::builder()
+            .keys(doc! { "seq": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(doc! { "seq": { "$exists": true } })
+                    .build(),
+            )
+            .build();
+
+        self.collection
+            .create_index(index, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        self.seed_head()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Links an event onto the chain and stores it. A failed write is logged
+    /// but never fails the request being audited.
+    pub async fn record(&self, event: AuditEvent) {
+        for attempt in 1..=MAX_APPEND_ATTEMPTS {
+            match self.append(event.clone()).await {
+                Ok(()) => return,
+                // Another append took this sequence number; link onto it
+                Err(e) if is_duplicate_key(&e) => {
+                    if let Err(e) = self.catch_up_head().await {
+                        error!("Failed to advance the audit head: {}", e);
+                    }
+                    let delay = rand::thread_rng().gen_range(0..=attempt * 5);
+                    actix_web::rt::time::sleep(Duration::from_millis(delay)).await;
+                }
+                Err(e) => {
+                    error!("Failed to write audit event {:?}: {}", event, e);
+                    return;
+                }
+            }
+        }
+
+        error!(
+            "Gave up writing audit event {:?} after repeated conflicts",
+            event
+        );
+    }
+
+    // The unique `seq` index rejects the insert if another append already
+    // took the next sequence number, so no two entries link to the same one
+    async fn append(&self, mut event: AuditEvent) -> Result<(), mongodb::error::Error> {
+        let head = match self.heads.find_one(doc! { "_id": HEAD_ID }, None).await? {
+            Some(head) => head,
+            None => self.seed_head().await?,
+        };
+        event.seq = head.seq + 1;
+        event.prev_hash = head.hash.clone();
+        event.hash = event.chain_hash();
+
+        self.collection.insert_one(&event, None).await?;
+        self.advance_head(&head, &event).await
+    }
+
+    // Moves the head document from `from` to `to`. Does nothing if another
+    // append already moved it, so the head never goes backwards.
+    async fn advance_head(
+        &self,
+        from: &AuditHead,
+        to: &AuditEvent,
+    ) -> Result<(), mongodb::error::Error> {
+        self.heads
+            .update_one(
+                doc! { "_id": HEAD_ID, "seq": from.seq },
+                doc! { "$set": { "seq": to.seq, "hash": &to.hash } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Brings the head document up to the newest stored entry, in case the
+    // append that wrote it failed before advancing the head
+    async fn catch_up_head(&self) -> Result<(), mongodb::error::Error> {
+        let head = match self.heads.find_one(doc! { "_id": HEAD_ID }, None).await? {
+            Some(head) => head,
+            None => return self.seed_head().await.map(|_| ()),
+        };
+        match self.head().await? {
+            Some(newest) if newest.seq > head.seq => self.advance_head(&head, &newest).await,
+            _ => Ok(()),
+        }
+    }
+
+    // Creates the head document from the newest stored entry, unless it
+    // already exists
+    async fn seed_head(&self) -> Result<AuditHead, mongodb::error::Error> {
+        let (seq, hash) = match self.head().await? {
+            Some(newest) => (newest.seq, newest.hash),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.heads
+            .update_one(
+                doc! { "_id": HEAD_ID },
+                doc! { "$setOnInsert": { "seq": seq, "hash": &hash } },
+                options,
+            )
+            .await?;
+
+        let head = self.heads.find_one(doc! { "_id": HEAD_ID }, None).await?;
+        Ok(head.unwrap_or(AuditHead {
+            id: HEAD_ID.to_string(),
+            seq,
+            hash,
+        }))
+    }
+
+    async fn head(&self) -> Result<Option<AuditEvent>, mongodb::error::Error> {
+        let options = FindOneOptions::builder().sort(doc! { "seq": -1 }).build();
+        self.collection
+            .find_one(doc! { "seq": { "$gt": 0 } }, options)
+            .await
+    }
+
+    /// Newest events first. Returns the page and the total match count.
+    pub async fn query(
+        &self,
+        filter: &AuditFilter,
+        page: u64,
+        per_page: i64,
+    ) -> Result<(Vec<AuditEvent>, u64), CustomError> {
+        let filter = filter.to_document()?;
+
+        let total = self
+            .collection
+            .count_documents(filter.clone(), None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1, "_id": -1 })
+            .skip(page_offset(page, per_page)?)
+            .limit(per_page)
+            .build();
+        let events = self
+            .collection
+            .find(filter, options)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok((events, total))
+    }
+
+    /// Every matching event, oldest first, for streaming out as an export.
+    pub async fn export(&self, filter: &AuditFilter) -> Result<Cursor<AuditEvent>, CustomError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1, "_id": 1 })
+            .build();
+
+        self.collection
+            .find(filter.to_document()?, options)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    /// Walks the chain in order and reports the first entry whose sequence
+    /// number, link or content hash does not check out. Stored checkpoints
+    /// are verified along the way, which also catches a truncated chain.
+    pub async fn verify_chain(&self) -> Result<ChainReport, CustomError> {
+        let mut checkpoints: VecDeque<AuditCheckpoint> = self
+            .checkpoints
+            .find(
+                doc! {},
+                FindOptions::builder().sort(doc! { "seq": 1 }).build(),
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let unchained = self
+            .collection
+            .count_documents(doc! { "seq": { "$exists": false } }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let mut report = ChainReport {
+            verified: 0,
+            checkpoints_verified: 0,
+            unchained,
+            head_seq: 0,
+            broken: None,
+        };
+
+        let mut cursor = self
+            .collection
+            .find(
+                doc! { "seq": { "$gt": 0 } },
+                FindOptions::builder().sort(doc! { "seq": 1 }).build(),
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let mut prev_hash = GENESIS_HASH.to_string();
+        while let Some(event) = cursor
+            .try_next()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+        {
+            let expected_seq = report.head_seq + 1;
+            if let Some(reason) = check_link(&event, expected_seq, &prev_hash) {
+                report.broken = Some(BrokenLink {
+                    seq: expected_seq,
+                    id: event.id.map(|id| id.to_hex()),
+                    reason,
+                });
+                return Ok(report);
+            }
+
+            while let Some(checkpoint) = checkpoints.front().filter(|c| c.seq <= event.seq) {
+                if let Some(reason) = check_checkpoint(checkpoint, &event) {
+                    report.broken = Some(BrokenLink {
+                        seq: checkpoint.seq,
+                        id: checkpoint.id.map(|id| id.to_hex()),
+                        reason,
+                    });
+                    return Ok(report);
+                }
+                checkpoints.pop_front();
+                report.checkpoints_verified += 1;
+            }
+
+            report.verified += 1;
+            report.head_seq = event.seq;
+            prev_hash = event.hash;
+        }
+
+        // A checkpoint past the end means entries were cut off the chain
+        if let Some(checkpoint) = checkpoints.front() {
+            report.broken = Some(BrokenLink {
+                seq: report.head_seq + 1,
+                id: None,
+                reason: format!(
+                    "chain ends at entry {} but a checkpoint covers entry {}",
+                    report.head_seq, checkpoint.seq
+                ),
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Signs the current chain head, unless nothing was recorded since the
+    /// last checkpoint.
+    pub async fn create_checkpoint(&self) -> Result<Option<AuditCheckpoint>, CustomError> {
+        let head = match self
+            .head()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+        {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+
+        let latest = self
+            .checkpoints
+            .find_one(
+                doc! {},
+                FindOneOptions::builder().sort(doc! { "seq": -1 }).build(),
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        if latest.is_some_and(|latest| latest.seq >= head.seq) {
+            return Ok(None);
+        }
+
+        let created_at = DateTime::now();
+        let signature = jwt::encode_token(&CheckpointClaims {
+            seq: head.seq,
+            hash: head.hash.clone(),
+            iat: (created_at.timestamp_millis() / 1000) as usize,
+        })?;
+        let mut checkpoint = AuditCheckpoint {
+            id: None,
+            seq: head.seq,
+            hash: head.hash,
+            signature,
+            created_at,
+        };
+
+        let result = self
+            .checkpoints
+            .insert_one(&checkpoint, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        checkpoint.id = result.inserted_id.as_object_id();
+
+        if let Ok(path) = std::env::var("AUDIT_CHECKPOINT_PATH") {
+            export_checkpoint(&path, &checkpoint)?;
+        }
+        info!("Audit checkpoint signed at entry {}", checkpoint.seq);
+
+        Ok(Some(checkpoint))
+    }
+}
+
+// Why `event` is not the entry that should follow the one hashed `prev_hash`
+fn check_link(event: &AuditEvent, expected_seq: i64, prev_hash: &str) -> Option<String> {
+    if event.seq != expected_seq {
+        Some(format!(
+            "expected entry {}, found {}",
+            expected_seq, event.seq
+        ))
+    } else if event.prev_hash != prev_hash {
+        Some("link to the previous entry does not match".to_string())
+    } else if event.hash != event.chain_hash() {
+        Some("entry content does not match its hash".to_string())
+    } else {
+        None
+    }
+}
+
+// Why `checkpoint` does not vouch for `event`, the entry it is positioned at
+fn check_checkpoint(checkpoint: &AuditCheckpoint, event: &AuditEvent) -> Option<String> {
+    let claims = match jwt::decode_record::<CheckpointClaims>(&checkpoint.signature) {
+        Ok(claims) => claims,
+        Err(_) => return Some("checkpoint signature is invalid".to_string()),
+    };
+    if claims.seq != checkpoint.seq || claims.hash != checkpoint.hash {
+        return Some("checkpoint does not match its signature".to_string());
+    }
+    if checkpoint.seq != event.seq {
+        return Some(format!("checkpointed entry {} is missing", checkpoint.seq));
+    }
+    if checkpoint.hash != event.hash {
+        return Some("entry differs from the signed checkpoint".to_string());
+    }
+    None
+}
+
+// One JSON line per checkpoint, for shipping to storage the database
+// administrators cannot rewrite
+fn export_checkpoint(path: &str, checkpoint: &AuditCheckpoint) -> Result<(), CustomError> {
+    let line = serde_json::json!({
+        "seq": checkpoint.seq,
+        "hash": checkpoint.hash,
+        "signature": checkpoint.signature,
+        "created_at": checkpoint.created_at.try_to_rfc3339_string().unwrap_or_default(),
+    });
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| {
+            CustomError::InternalServerError(format!("Failed to export checkpoint: {}", e))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::request::RequestContext;
+
+    fn chain(len: i64) -> Vec<AuditEvent> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        (1..=len)
+            .map(|seq| {
+                let mut event = AuditEvent::new(AuditAction::Login, &RequestContext::system())
+                    .subject(&format!("user{}", seq));
+                event.seq = seq;
+                event.prev_hash = prev_hash.clone();
+                event.hash = event.chain_hash();
+                prev_hash = event.hash.clone();
+                event
+            })
+            .collect()
+    }
+
+    // The first broken link, walking the entries as `verify_chain` does
+    fn first_broken(events: &[AuditEvent]) -> Option<(i64, String)> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for (expected_seq, event) in (1..).zip(events) {
+            if let Some(reason) = check_link(event, expected_seq, &prev_hash) {
+                return Some((expected_seq, reason));
+            }
+            prev_hash = event.hash.clone();
+        }
+        None
+    }
+
+    fn checkpoint(event: &AuditEvent) -> AuditCheckpoint {
+        jwt::init_test_keys();
+        let signature = jwt::encode_token(&CheckpointClaims {
+            seq: event.seq,
+            hash: event.hash.clone(),
+            iat: 0,
+        })
+        .unwrap();
+        AuditCheckpoint {
+            id: None,
+            seq: event.seq,
+            hash: event.hash.clone(),
+            signature,
+            created_at: DateTime::now(),
+        }
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        assert_eq!(first_broken(&chain(5)), None);
+    }
+
+    #[test]
+    fn edited_entry_breaks_at_that_entry() {
+        let mut events = chain(5);
+        events[2].reason = Some("rewritten".to_string());
+
+        let (seq, reason) = first_broken(&events).unwrap();
+        assert_eq!(seq, 3);
+        assert_eq!(reason, "entry content does not match its hash");
+    }
+
+    #[test]
+    fn rehashed_entry_breaks_the_next_link() {
+        let mut events = chain(5);
+        events[2].reason = Some("rewritten".to_string());
+        events[2].hash = events[2].chain_hash();
+
+        let (seq, reason) = first_broken(&events).unwrap();
+        assert_eq!(seq, 4);
+        assert_eq!(reason, "link to the previous entry does not match");
+    }
+
+    #[test]
+    fn removed_entry_is_a_gap() {
+        let mut events = chain(5);
+        events.remove(1);
+
+        let (seq, reason) = first_broken(&events).unwrap();
+        assert_eq!(seq, 2);
+        assert_eq!(reason, "expected entry 2, found 3");
+    }
+
+    #[test]
+    fn checkpoint_vouches_for_its_entry() {
+        let events = chain(3);
+        assert_eq!(check_checkpoint(&checkpoint(&events[2]), &events[2]), None);
+    }
+
+    #[test]
+    fn checkpoint_rejects_a_forged_signature() {
+        let events = chain(3);
+        let mut forged = checkpoint(&events[2]);
+        forged.signature.push('x');
+
+        assert_eq!(
+            check_checkpoint(&forged, &events[2]).as_deref(),
+            Some("checkpoint signature is invalid")
+        );
+    }
+
+    #[test]
+    fn checkpoint_rejects_fields_that_differ_from_its_signature() {
+        let events = chain(3);
+        let mut edited = checkpoint(&events[2]);
+        edited.hash = events[1].hash.clone();
+
+        assert_eq!(
+            check_checkpoint(&edited, &events[2]).as_deref(),
+            Some("checkpoint does not match its signature")
+        );
+    }
+
+    #[test]
+    fn checkpoint_detects_a_rewritten_chain() {
+        let events = chain(3);
+        let signed = checkpoint(&events[2]);
+
+        // The whole chain rebuilt with different content, links intact
+        let mut rewritten = chain(3);
+        rewritten[2].subject = Some("someone else".to_string());
+        rewritten[2].hash = rewritten[2].chain_hash();
+
+        assert_eq!(
+            check_checkpoint(&signed, &rewritten[2]).as_deref(),
+            Some("entry differs from the signed checkpoint")
+        );
+        assert_eq!(
+            check_checkpoint(&signed, &events[1]).as_deref(),
+            Some("checkpointed entry 3 is missing")
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Client, Collection, IndexModel};
+
+use crate::database::is_duplicate_key;
+use crate::model::dpop_model::DpopProof;
+use crate::utils::dpop::VerifiedProof;
+use crate::utils::error::CustomError;
+
+/// Remembers accepted DPoP proofs so that each one is used only once.
+pub struct DpopService {
+    collection: Collection<DpopProof>,
+}
+
+impl DpopService {
+    pub fn new(client: &Client) -> Self {
+        let collection = client.database("Rust_PRo").collection("dpop_proofs");
+        DpopService { collection }
+    }
+
+    /// A `jti` may be used once per key. Records expire with the proofs.
+    pub async fn ensure_indexes(&self) -> Result<(), CustomError> {
+        let index = IndexModel::builder()
+            .keys(doc! { "jkt": 1, "jti": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.collection
+            .create_index(index, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        self.collection
+            .create_index(index, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Records a verified proof, rejecting it if it was seen before.
+    pub async fn record_use(&self, proof: &VerifiedProof) -> Result<(), CustomError> {
+        let record = DpopProof {
+            id: None,
+            jkt: proof.jkt.clone(),
+            jti: proof.jti.clone(),
+            expires_at: DateTime::from_millis(proof.expires_at * 1000),
+        };
+
+        self.collection
+            .insert_one(record, None)
+            .await
+            .map_err(|e| {
+                if is_duplicate_key(&e) {
+                    CustomError::DpopProofError(
+                        "DPoP proof has already been used".to_string(),
+                        None,
+                    )
+                } else {
+                    CustomError::InternalServerError(e.to_string())
+                }
+            })?;
+
+        Ok(())
+    }
+}
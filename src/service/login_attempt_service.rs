@@ -0,0 +1,289 @@
+use futures::TryStreamExt;
+use log::warn;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use mongodb::{Client, ClientSession, Collection};
+
+use crate::model::login_attempt_model::{LockoutAction, LockoutEvent, LoginAttempt};
+use crate::utils::error::CustomError;
+
+/// Thresholds for login throttling, read from the environment once.
+struct LockoutPolicy {
+    max_failures_per_user: i32,
+    max_failures_per_ip: i32,
+    base_lockout_secs: i64,
+    max_lockout_secs: i64,
+    failure_window_secs: i64,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+impl LockoutPolicy {
+    fn from_env() -> Self {
+        LockoutPolicy {
+            max_failures_per_user: env_or("LOGIN_MAX_FAILURES_PER_USER", 5),
+            max_failures_per_ip: env_or("LOGIN_MAX_FAILURES_PER_IP", 20),
+            base_lockout_secs: env_or("LOGIN_LOCKOUT_BASE_SECONDS", 60),
+            max_lockout_secs: env_or("LOGIN_LOCKOUT_MAX_SECONDS", 3600),
+            failure_window_secs: env_or("LOGIN_FAILURE_WINDOW_SECONDS", 900),
+        }
+    }
+
+    /// Doubles the lockout for every failure past the threshold.
+    fn lockout_secs(&self, failures: i32, threshold: i32) -> i64 {
+        let exponent = (failures - threshold).clamp(0, 20) as u32;
+        self.base_lockout_secs
+            .saturating_mul(2i64.pow(exponent))
+            .min(self.max_lockout_secs)
+    }
+}
+
+pub struct LoginAttemptService {
+    attempts: Collection<LoginAttempt>,
+    events: Collection<LockoutEvent>,
+    policy: LockoutPolicy,
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username.trim())
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+impl LoginAttemptService {
+    pub fn new(client: &Client) -> Self {
+        let database = client.database("Rust_PRo");
+        LoginAttemptService {
+            attempts: database.collection("login_attempts"),
+            events: database.collection("lockout_events"),
+            policy: LockoutPolicy::from_env(),
+        }
+    }
+
+    /// Rejects the attempt with a 429 while the username or IP is locked out.
+    pub async fn check(&self, username: &str, ip: &str) -> Result<(), CustomError> {
+        let now = DateTime::now();
+
+        let locked = self
+            .attempts
+            .find_one(
+                doc! {
+                    "key": { "$in": [user_key(username), ip_key(ip)] },
+                    "locked_until": { "$gt": now },
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        if let Some(LoginAttempt {
+            locked_until: Some(locked_until),
+            ..
+        }) = locked
+        {
+            let retry_after =
+                (locked_until.timestamp_millis() - now.timestamp_millis() + 999) / 1000;
+            return Err(CustomError::TooManyRequestsError(
+                "Too many failed login attempts, try again later".to_string(),
+                retry_after.max(1) as u64,
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub async fn record_failure(&self, username: &str, ip: &str) -> Result<(), CustomError> {
+        self.register_failure(user_key(username), self.policy.max_failures_per_user)
+            .await?;
+        self.register_failure(ip_key(ip), self.policy.max_failures_per_ip)
+            .await
+    }
+
+    /// Clears the username counter. The IP counter is left alone so one valid
+    /// account cannot be used to reset throttling for a guessing client.
+    pub async fn record_success(&self, username: &str) -> Result<(), CustomError> {
+        self.clear(user_key(username), None).await
+    }
+
+    /// Lifts a lockout on behalf of an administrator.
+    pub async fn unlock(&self, key: &str, actor_id: Option<ObjectId>) -> Result<bool, CustomError> {
+        let exists = self
+            .attempts
+            .count_documents(doc! { "key": key }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        if exists == 0 {
+            return Ok(false);
+        }
+
+        self.clear(key.to_string(), actor_id).await?;
+        Ok(true)
+    }
+
+    pub async fn list_events(
+        &self,
+        key: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<LockoutEvent>, CustomError> {
+        let filter = match key {
+            Some(key) => doc! { "key": key },
+            None => doc! {},
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .build();
+
+        let cursor = self
+            .events
+            .find(filter, options)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    async fn register_failure(&self, key: String, threshold: i32) -> Result<(), CustomError> {
+        let now = DateTime::now();
+        let window_start =
+            DateTime::from_millis(now.timestamp_millis() - self.policy.failure_window_secs * 1000);
+
+        // Old failures outside the window no longer count, unless still locked
+        self.attempts
+            .delete_one(
+                doc! {
+                    "key": &key,
+                    "last_failure_at": { "$lt": window_start },
+                    "$or": [
+                        { "locked_until": null },
+                        { "locked_until": { "$lt": now } },
+                    ],
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let attempt = self
+            .attempts
+            .find_one_and_update(
+                doc! { "key": &key },
+                doc! {
+                    "$inc": { "failures": 1 },
+                    "$set": { "last_failure_at": now },
+                    "$setOnInsert": { "locked_until": null },
+                },
+                options,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| {
+                CustomError::InternalServerError("Failed to record login attempt".to_string())
+            })?;
+
+        if attempt.failures < threshold {
+            return Ok(());
+        }
+
+        let lockout_secs = self.policy.lockout_secs(attempt.failures, threshold);
+        let locked_until = DateTime::from_millis(now.timestamp_millis() + lockout_secs * 1000);
+
+        self.attempts
+            .update_one(
+                doc! { "key": &key },
+                doc! { "$set": { "locked_until": locked_until } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        warn!(
+            "Locking out {} for {}s after {} failed logins",
+            key, lockout_secs, attempt.failures
+        );
+
+        self.record_event(LockoutEvent {
+            id: None,
+            key,
+            action: LockoutAction::Locked,
+            failures: attempt.failures,
+            locked_until: Some(locked_until),
+            actor_id: None,
+            created_at: now,
+        })
+        .await
+    }
+
+    async fn clear(&self, key: String, actor_id: Option<ObjectId>) -> Result<(), CustomError> {
+        let attempt = self
+            .attempts
+            .find_one_and_delete(doc! { "key": &key }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        // Only a key that was actually locked out gets an unlock event
+        match attempt {
+            Some(attempt) if attempt.locked_until.is_some() => {
+                self.record_event(LockoutEvent {
+                    id: None,
+                    key,
+                    action: LockoutAction::Unlocked,
+                    failures: attempt.failures,
+                    locked_until: None,
+                    actor_id,
+                    created_at: DateTime::now(),
+                })
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn record_event(&self, event: LockoutEvent) -> Result<(), CustomError> {
+        self.events
+            .insert_one(event, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Removes the counters and lockout history kept for a user's names as
+    /// part of the caller's transaction.
+    pub async fn delete_for_usernames(
+        &self,
+        usernames: &[&str],
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        let keys: Vec<String> = usernames
+            .iter()
+            .map(|username| user_key(username))
+            .collect();
+
+        self.attempts
+            .delete_many_with_session(doc! { "key": { "$in": &keys } }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        self.events
+            .delete_many_with_session(doc! { "key": { "$in": &keys } }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use chrono::{Duration, Utc};
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Client, ClientSession, Collection};
+use serde::{Deserialize, Serialize};
+
+use crate::model::magic_link_model::MagicLink;
+use crate::utils::error::CustomError;
+use crate::utils::{jwt, token};
+
+const MAGIC_LINK_PURPOSE: &str = "magic_link";
+
+pub struct MagicLinkService {
+    collection: Collection<MagicLink>,
+}
+
+/// Claims of an emailed login link. The address is part of the token so the
+/// link dies if the user changes email meanwhile.
+#[derive(Debug, Serialize, Deserialize)]
+struct MagicLinkClaims {
+    sub: String,
+    email: String,
+    jti: String,
+    purpose: String,
+    exp: usize,
+}
+
+pub fn magic_link_ttl() -> Duration {
+    let minutes = std::env::var("MAGIC_LINK_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    Duration::minutes(minutes)
+}
+
+impl MagicLinkService {
+    pub fn new(client: &Client) -> Self {
+        let collection = client.database("Rust_PRo").collection("magic_links");
+        MagicLinkService { collection }
+    }
+
+    /// Issues a signed single-use login token for `user_id` at `email`.
+    pub async fn issue_token(&self, user_id: ObjectId, email: &str) -> Result<String, CustomError> {
+        let expires_at = Utc::now() + magic_link_ttl();
+        let jti = token::generate_token();
+
+        let link_token = jwt::encode_token(&MagicLinkClaims {
+            sub: user_id.to_hex(),
+            email: email.to_string(),
+            jti: jti.clone(),
+            purpose: MAGIC_LINK_PURPOSE.to_string(),
+            exp: expires_at.timestamp() as usize,
+        })?;
+
+        let link = MagicLink {
+            id: None,
+            user_id,
+            jti,
+            expires_at: DateTime::from_millis(expires_at.timestamp_millis()),
+            used_at: None,
+            created_at: DateTime::now(),
+        };
+
+        self.collection
+            .insert_one(link, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(link_token)
+    }
+
+    /// Checks the signature and marks the link as used, returning the user
+    /// and email it was issued for. Any other outstanding links of that user
+    /// are burned as well, so a replayed or older link is rejected.
+    pub async fn consume_token(&self, link_token: &str) -> Result<(ObjectId, String), CustomError> {
+        let invalid =
+            || CustomError::UnauthorizedError("Login link is invalid or has expired".to_string());
+
+        let claims = jwt::decode_token::<MagicLinkClaims>(link_token).map_err(|_| invalid())?;
+        if claims.purpose != MAGIC_LINK_PURPOSE {
+            return Err(invalid());
+        }
+        let user_id = ObjectId::parse_str(&claims.sub).map_err(|_| invalid())?;
+        let now = DateTime::now();
+
+        self.collection
+            .find_one_and_update(
+                doc! {
+                    "jti": &claims.jti,
+                    "user_id": user_id,
+                    "used_at": null,
+                    "expires_at": { "$gt": now },
+                },
+                doc! { "$set": { "used_at": now } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(invalid)?;
+
+        self.collection
+            .update_many(
+                doc! { "user_id": user_id, "used_at": null },
+                doc! { "$set": { "used_at": now } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok((user_id, claims.email))
+    }
+
+    /// Removes every magic link of a user as part of the caller's transaction.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        self.collection
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+}
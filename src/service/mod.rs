@@ -1,2 +1,12 @@
 pub mod user_service;
-pub mod todo_service;
\ No newline at end of file
+pub mod todo_service;
+pub mod token_service;
+pub mod revocation_service;
+pub mod access_token_service;
+pub mod password_reset_service;
+pub mod login_attempt_service;
+pub mod audit_service;
+pub mod magic_link_service;
+pub mod passkey_service;
+pub mod session_service;
+pub mod dpop_service;
\ No newline at end of file
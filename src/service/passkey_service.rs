@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Client, ClientSession, Collection, IndexModel};
+
+use crate::database::is_duplicate_key;
+use crate::model::passkey_model::{Passkey, PasskeyCeremony, PasskeyChallenge};
+use crate::model::user_model::User;
+use crate::utils::error::CustomError;
+use crate::utils::webauthn::{
+    self, AuthenticationCredential, RegistrationCredential, RelyingParty,
+};
+
+pub struct PasskeyService {
+    passkeys: Collection<Passkey>,
+    challenges: Collection<PasskeyChallenge>,
+    rp: RelyingParty,
+}
+
+impl PasskeyService {
+    pub fn new(client: &Client) -> Self {
+        let database = client.database("Rust_PRo");
+        PasskeyService {
+            passkeys: database.collection("passkeys"),
+            challenges: database.collection("passkey_challenges"),
+            rp: RelyingParty::from_env(),
+        }
+    }
+
+    /// A credential ID may only ever belong to one passkey. Challenges that
+    /// were never answered expire on their own.
+    pub async fn ensure_indexes(&self) -> Result<(), CustomError> {
+        let index = IndexModel::builder()
+            .keys(doc! { "credential_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.passkeys
+            .create_index(index, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        self.challenges
+            .create_index(index, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Starts registering a passkey for `user` and returns the options to
+    /// pass to `navigator.credentials.create()`.
+    pub async fn begin_registration(&self, user: &User) -> Result<serde_json::Value, CustomError> {
+        let user_id = user
+            .id
+            .ok_or_else(|| CustomError::InternalServerError("User has no ID".to_string()))?;
+
+        let challenge = self
+            .issue_challenge(PasskeyCeremony::Registration, Some(user_id))
+            .await?;
+        let existing: Vec<String> = self
+            .list_passkeys(user_id)
+            .await?
+            .into_iter()
+            .map(|passkey| passkey.credential_id)
+            .collect();
+
+        Ok(webauthn::creation_options(
+            &self.rp,
+            &challenge,
+            &user_id.bytes(),
+            &user.username,
+            &existing,
+        ))
+    }
+
+    /// Verifies the browser's response and stores the new passkey.
+    pub async fn finish_registration(
+        &self,
+        user_id: ObjectId,
+        name: String,
+        credential: &RegistrationCredential,
+    ) -> Result<Passkey, CustomError> {
+        let challenge = webauthn::challenge_of(&credential.response.client_data_json)?;
+        self.take_challenge(&challenge, PasskeyCeremony::Registration, Some(user_id))
+            .await?;
+
+        let new_credential = webauthn::verify_registration(&self.rp, &challenge, credential)?;
+
+        let mut passkey = Passkey {
+            id: None,
+            user_id,
+            credential_id: webauthn::encode(&new_credential.credential_id),
+            public_key: webauthn::encode(&new_credential.public_key),
+            sign_count: i64::from(new_credential.sign_count),
+            name,
+            created_at: DateTime::now(),
+            last_used_at: None,
+        };
+
+        let result = self
+            .passkeys
+            .insert_one(&passkey, None)
+            .await
+            .map_err(|e| {
+                if is_duplicate_key(&e) {
+                    CustomError::ConflictError("Passkey is already registered".to_string())
+                } else {
+                    CustomError::InternalServerError(e.to_string())
+                }
+            })?;
+        passkey.id = result.inserted_id.as_object_id();
+
+        Ok(passkey)
+    }
+
+    /// Starts a passkey login and returns the options to pass to
+    /// `navigator.credentials.get()`.
+    pub async fn begin_authentication(&self) -> Result<serde_json::Value, CustomError> {
+        let challenge = self
+            .issue_challenge(PasskeyCeremony::Authentication, None)
+            .await?;
+        Ok(webauthn::request_options(&self.rp, &challenge))
+    }
+
+    /// Verifies a login response and returns the user the passkey belongs to.
+    pub async fn finish_authentication(
+        &self,
+        credential: &AuthenticationCredential,
+    ) -> Result<ObjectId, CustomError> {
+        let challenge = webauthn::challenge_of(&credential.response.client_data_json)?;
+        self.take_challenge(&challenge, PasskeyCeremony::Authentication, None)
+            .await?;
+
+        let credential_id = webauthn::encode(&webauthn::decode(&credential.id)?);
+        let passkey = self
+            .passkeys
+            .find_one(doc! { "credential_id": &credential_id }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| CustomError::UnauthorizedError("Unknown passkey".to_string()))?;
+
+        // The user handle is the user ID the passkey was created for
+        if let Some(user_handle) = &credential.response.user_handle {
+            if webauthn::decode(user_handle)? != passkey.user_id.bytes() {
+                return Err(CustomError::UnauthorizedError(
+                    "Passkey does not belong to this user".to_string(),
+                ));
+            }
+        }
+
+        let sign_count = webauthn::verify_assertion(
+            &self.rp,
+            &challenge,
+            &webauthn::decode(&passkey.public_key)?,
+            passkey.sign_count.try_into().unwrap_or_default(),
+            credential,
+        )
+        .map_err(|e| CustomError::UnauthorizedError(e.to_string()))?;
+
+        self.passkeys
+            .update_one(
+                doc! { "_id": passkey.id },
+                doc! { "$set": {
+                    "sign_count": i64::from(sign_count),
+                    "last_used_at": DateTime::now(),
+                } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(passkey.user_id)
+    }
+
+    pub async fn list_passkeys(&self, user_id: ObjectId) -> Result<Vec<Passkey>, CustomError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .build();
+        let cursor = self
+            .passkeys
+            .find(doc! { "user_id": user_id }, options)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    /// Removes one of the user's passkeys. Returns `false` if there was no
+    /// such passkey.
+    pub async fn remove_passkey(
+        &self,
+        user_id: ObjectId,
+        id: ObjectId,
+    ) -> Result<bool, CustomError> {
+        let result = self
+            .passkeys
+            .delete_one(doc! { "_id": id, "user_id": user_id }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Deletes every passkey of a user, and their unanswered registration
+    /// challenges, as part of purging the account.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        self.passkeys
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        self.challenges
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn issue_challenge(
+        &self,
+        ceremony: PasskeyCeremony,
+        user_id: Option<ObjectId>,
+    ) -> Result<String, CustomError> {
+        let challenge = webauthn::generate_challenge();
+        let expires_at = DateTime::from_millis(
+            DateTime::now().timestamp_millis() + webauthn::CEREMONY_TIMEOUT_SECS * 1000,
+        );
+
+        self.challenges
+            .insert_one(
+                PasskeyChallenge {
+                    id: None,
+                    challenge: challenge.clone(),
+                    ceremony,
+                    user_id,
+                    expires_at,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(challenge)
+    }
+
+    /// Uses up an outstanding challenge so a response cannot be replayed.
+    async fn take_challenge(
+        &self,
+        challenge: &str,
+        ceremony: PasskeyCeremony,
+        user_id: Option<ObjectId>,
+    ) -> Result<(), CustomError> {
+        let ceremony = mongodb::bson::to_bson(&ceremony)
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        self.challenges
+            .find_one_and_delete(
+                doc! {
+                    "challenge": challenge,
+                    "ceremony": ceremony,
+                    "user_id": user_id,
+                    "expires_at": { "$gt": DateTime::now() },
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .map(|_| ())
+            .ok_or_else(|| {
+                CustomError::BadRequestError(
+                    "Passkey challenge is invalid or has expired".to_string(),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Needs a MongoDB server: MONGODB_URI=... cargo test -- --ignored
+    #[actix_web::test]
+    #[ignore]
+    async fn challenge_can_only_be_answered_once() {
+        let uri = std::env::var("MONGODB_URI").expect("MONGODB_URI must be set");
+        let client = Client::with_uri_str(&uri).await.unwrap();
+        let service = PasskeyService::new(&client);
+        let user_id = ObjectId::new();
+
+        let challenge = service
+            .issue_challenge(PasskeyCeremony::Registration, Some(user_id))
+            .await
+            .unwrap();
+
+        // Bound to the ceremony and the user it was issued for
+        assert!(service
+            .take_challenge(&challenge, PasskeyCeremony::Authentication, None)
+            .await
+            .is_err());
+        assert!(service
+            .take_challenge(
+                &challenge,
+                PasskeyCeremony::Registration,
+                Some(ObjectId::new())
+            )
+            .await
+            .is_err());
+
+        service
+            .take_challenge(&challenge, PasskeyCeremony::Registration, Some(user_id))
+            .await
+            .unwrap();
+        assert!(service
+            .take_challenge(&challenge, PasskeyCeremony::Registration, Some(user_id))
+            .await
+            .is_err());
+    }
+}
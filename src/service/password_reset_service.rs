@@ -0,0 +1,114 @@
+use chrono::{Duration, Utc};
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Client, ClientSession, Collection};
+
+use crate::model::password_reset_model::PasswordReset;
+use crate::utils::error::CustomError;
+use crate::utils::token;
+
+pub struct PasswordResetService {
+    collection: Collection<PasswordReset>,
+}
+
+fn reset_token_ttl() -> Duration {
+    let minutes = std::env::var("PASSWORD_RESET_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    Duration::minutes(minutes)
+}
+
+impl PasswordResetService {
+    pub fn new(client: &Client) -> Self {
+        let collection = client.database("Rust_PRo").collection("password_resets");
+        PasswordResetService { collection }
+    }
+
+    /// Issues a single-use reset token and returns its plaintext value.
+    pub async fn issue_token(&self, user_id: ObjectId) -> Result<String, CustomError> {
+        let reset_token = token::generate_token();
+
+        let reset = PasswordReset {
+            id: None,
+            user_id,
+            token_hash: token::hash_token(&reset_token),
+            expires_at: DateTime::from_millis((Utc::now() + reset_token_ttl()).timestamp_millis()),
+            used_at: None,
+            created_at: DateTime::now(),
+        };
+
+        self.collection
+            .insert_one(reset, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(reset_token)
+    }
+
+    /// Returns the user a still-valid token was issued for, without using it up.
+    pub async fn find_user(&self, reset_token: &str) -> Result<ObjectId, CustomError> {
+        self.collection
+            .find_one(
+                doc! {
+                    "token_hash": token::hash_token(reset_token),
+                    "used_at": null,
+                    "expires_at": { "$gt": DateTime::now() },
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .map(|reset| reset.user_id)
+            .ok_or_else(|| {
+                CustomError::BadRequestError("Reset token is invalid or has expired".to_string())
+            })
+    }
+
+    /// Marks the token as used and returns the user it was issued for. Any
+    /// other outstanding tokens of that user are burned as well.
+    pub async fn consume_token(&self, reset_token: &str) -> Result<ObjectId, CustomError> {
+        let now = DateTime::now();
+
+        let reset = self
+            .collection
+            .find_one_and_update(
+                doc! {
+                    "token_hash": token::hash_token(reset_token),
+                    "used_at": null,
+                    "expires_at": { "$gt": now },
+                },
+                doc! { "$set": { "used_at": now } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| {
+                CustomError::BadRequestError("Reset token is invalid or has expired".to_string())
+            })?;
+
+        self.collection
+            .update_many(
+                doc! { "user_id": reset.user_id, "used_at": null },
+                doc! { "$set": { "used_at": now } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(reset.user_id)
+    }
+
+    /// Removes every password reset of a user as part of the caller's transaction.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        self.collection
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::UpdateOptions;
+use mongodb::{Client, Collection};
+
+use crate::model::revocation_model::{RevokedToken, UserRevocation};
+use crate::service::user_service::Claims;
+use crate::utils::error::CustomError;
+
+pub struct RevocationService {
+    revoked_tokens: Collection<RevokedToken>,
+    user_revocations: Collection<UserRevocation>,
+}
+
+impl RevocationService {
+    pub fn new(client: &Client) -> Self {
+        let database = client.database("Rust_PRo");
+        RevocationService {
+            revoked_tokens: database.collection("revoked_tokens"),
+            user_revocations: database.collection("user_revocations"),
+        }
+    }
+
+    /// Adds a single access token to the denylist.
+    pub async fn revoke_token(&self, claims: &Claims) -> Result<(), CustomError> {
+        let user_id = ObjectId::parse_str(&claims.id).map_err(|_| {
+            CustomError::BadRequestError("Invalid user ID format in token".to_string())
+        })?;
+
+        let revoked = RevokedToken {
+            id: None,
+            jti: claims.jti.clone(),
+            user_id,
+            expires_at: DateTime::from_millis(claims.exp as i64 * 1000),
+        };
+
+        self.revoked_tokens
+            .insert_one(revoked, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Revokes every access token issued to `user_id` up to now.
+    pub async fn revoke_all_for_user(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        self.user_revocations
+            .update_one(
+                doc! { "user_id": user_id },
+                doc! { "$set": { "revoked_before": DateTime::now() } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn is_revoked(&self, claims: &Claims) -> Result<bool, CustomError> {
+        let denied = self
+            .revoked_tokens
+            .count_documents(doc! { "jti": &claims.jti }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        if denied > 0 {
+            return Ok(true);
+        }
+
+        let user_id = match ObjectId::parse_str(&claims.id) {
+            Ok(user_id) => user_id,
+            Err(_) => return Ok(true),
+        };
+
+        let revocation = self
+            .user_revocations
+            .find_one(doc! { "user_id": user_id }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(match revocation {
+            Some(revocation) => {
+                (claims.iat as i64 * 1000) < revocation.revoked_before.timestamp_millis()
+            }
+            None => false,
+        })
+    }
+}
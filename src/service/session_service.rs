@@ -0,0 +1,197 @@
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::{FindOptions, UpdateOptions};
+use mongodb::{Client, ClientSession, Collection};
+
+use crate::model::session_model::Session;
+use crate::service::token_service::refresh_token_ttl;
+use crate::utils::error::CustomError;
+use crate::utils::request::{device_name, RequestContext};
+
+// Authenticated requests refresh `last_used_at` at most this often
+const TOUCH_INTERVAL_MILLIS: i64 = 5 * 60 * 1000;
+
+pub struct SessionService {
+    collection: Collection<Session>,
+}
+
+fn session_expiry() -> DateTime {
+    DateTime::from_millis((Utc::now() + refresh_token_ttl()).timestamp_millis())
+}
+
+impl SessionService {
+    pub fn new(client: &Client) -> Self {
+        let collection = client.database("Rust_PRo").collection("sessions");
+        SessionService { collection }
+    }
+
+    /// Records a new login from the device described by `ctx`.
+    pub async fn start(
+        &self,
+        user_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<ObjectId, CustomError> {
+        let now = DateTime::now();
+        let session = Session {
+            id: None,
+            user_id,
+            device_name: device_name(ctx.user_agent.as_deref()),
+            user_agent: ctx.user_agent.clone(),
+            ip: ctx.ip.clone(),
+            created_at: now,
+            last_used_at: now,
+            expires_at: session_expiry(),
+            revoked_at: None,
+        };
+
+        let result = self
+            .collection
+            .insert_one(session, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        result.inserted_id.as_object_id().ok_or_else(|| {
+            CustomError::InternalServerError("Failed to get inserted ID".to_string())
+        })
+    }
+
+    /// Extends a session after its refresh token was rotated. Refresh token
+    /// families from before sessions were recorded get a session here.
+    pub async fn refresh(
+        &self,
+        session_id: ObjectId,
+        user_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let now = DateTime::now();
+        self.collection
+            .update_one(
+                doc! { "_id": session_id, "user_id": user_id },
+                doc! {
+                    "$set": {
+                        "ip": &ctx.ip,
+                        "last_used_at": now,
+                        "expires_at": session_expiry(),
+                    },
+                    "$setOnInsert": {
+                        "device_name": device_name(ctx.user_agent.as_deref()),
+                        "user_agent": &ctx.user_agent,
+                        "created_at": now,
+                        "revoked_at": null,
+                    },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Rejects the session once it has been revoked, and notes the use.
+    pub async fn check_active(
+        &self,
+        session_id: ObjectId,
+        user_id: ObjectId,
+        ip: &str,
+    ) -> Result<(), CustomError> {
+        let session = self
+            .collection
+            .find_one(
+                doc! { "_id": session_id, "user_id": user_id, "revoked_at": null },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| {
+                CustomError::UnauthorizedError("Session has been revoked".to_string())
+            })?;
+
+        let now = DateTime::now();
+        if now.timestamp_millis() - session.last_used_at.timestamp_millis() > TOUCH_INTERVAL_MILLIS
+        {
+            self.collection
+                .update_one(
+                    doc! { "_id": session_id },
+                    doc! { "$set": { "last_used_at": now, "ip": ip } },
+                    None,
+                )
+                .await
+                .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// The user's sessions that are neither revoked nor expired, most
+    /// recently used first.
+    pub async fn list_sessions(&self, user_id: ObjectId) -> Result<Vec<Session>, CustomError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "last_used_at": -1 })
+            .build();
+        let cursor = self
+            .collection
+            .find(
+                doc! {
+                    "user_id": user_id,
+                    "revoked_at": null,
+                    "expires_at": { "$gt": DateTime::now() },
+                },
+                options,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    /// Returns `false` if the user has no such active session.
+    pub async fn revoke(
+        &self,
+        user_id: ObjectId,
+        session_id: ObjectId,
+    ) -> Result<bool, CustomError> {
+        let result = self
+            .collection
+            .update_one(
+                doc! { "_id": session_id, "user_id": user_id, "revoked_at": null },
+                doc! { "$set": { "revoked_at": DateTime::now() } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    pub async fn revoke_all_for_user(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        self.collection
+            .update_many(
+                doc! { "user_id": user_id, "revoked_at": null },
+                doc! { "$set": { "revoked_at": DateTime::now() } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Removes every session of a user as part of the caller's transaction.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        self.collection
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use futures::TryStreamExt;
 use mongodb::{
     bson::{doc, oid::ObjectId},
-    Client, Collection,
+    Client, ClientSession, Collection,
 };
 
 use crate::model::todo_model::Todo;
@@ -102,4 +102,19 @@ impl TodoService {
 
         Ok(delete_result.deleted_count == 1)
     }
+
+    /// Removes every todo of a user as part of the caller's transaction.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<u64, String> {
+        let delete_result = self
+            .collection
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(delete_result.deleted_count)
+    }
 }
@@ -0,0 +1,221 @@
+use chrono::{Duration, Utc};
+use log::warn;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Client, ClientSession, Collection};
+
+use crate::model::refresh_token_model::RefreshToken;
+use crate::utils::error::CustomError;
+use crate::utils::token;
+
+pub struct TokenService {
+    collection: Collection<RefreshToken>,
+}
+
+pub fn refresh_token_ttl() -> Duration {
+    let days = std::env::var("REFRESH_TOKEN_TTL_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    Duration::days(days)
+}
+
+impl TokenService {
+    pub fn new(client: &Client) -> Self {
+        let collection = client.database("Rust_PRo").collection("refresh_tokens");
+        TokenService { collection }
+    }
+
+    /// Issues a refresh token that starts a new token family (a fresh login).
+    /// The family is identified by the login's session ID and, when `jkt` is
+    /// given, bound to that DPoP key.
+    pub async fn issue_refresh_token(
+        &self,
+        user_id: ObjectId,
+        session_id: ObjectId,
+        jkt: Option<String>,
+    ) -> Result<String, CustomError> {
+        let refresh_token = token::generate_token();
+        self.store(user_id, session_id, &refresh_token, jkt).await?;
+
+        Ok(refresh_token)
+    }
+
+    /// Exchanges a refresh token for a new one in the same family. Returns
+    /// the user, the family and the new token.
+    ///
+    /// A token can only be rotated once. Presenting an already rotated (or
+    /// otherwise revoked) token is treated as theft and revokes the whole family.
+    /// A family bound to a DPoP key can only be rotated with a proof from
+    /// that key (`jkt`).
+    pub async fn rotate_refresh_token(
+        &self,
+        refresh_token: &str,
+        jkt: Option<&str>,
+    ) -> Result<(ObjectId, ObjectId, String), CustomError> {
+        let token_hash = token::hash_token(refresh_token);
+
+        let existing = self
+            .collection
+            .find_one(doc! { "token_hash": &token_hash }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| CustomError::UnauthorizedError("Invalid refresh token".to_string()))?;
+
+        if existing.revoked {
+            // Rotated tokens remember their successor; anything else was logged out
+            if existing.replaced_by.is_some() {
+                return Err(self.reuse_detected(&existing).await);
+            }
+            return Err(CustomError::UnauthorizedError(
+                "Refresh token has been revoked".to_string(),
+            ));
+        }
+
+        if existing.expires_at < DateTime::now() {
+            return Err(CustomError::UnauthorizedError(
+                "Refresh token has expired".to_string(),
+            ));
+        }
+
+        if let Some(bound_jkt) = &existing.jkt {
+            if jkt != Some(bound_jkt.as_str()) {
+                return Err(CustomError::UnauthorizedError(
+                    "Refresh token is bound to a different DPoP key".to_string(),
+                ));
+            }
+        }
+
+        let new_token = token::generate_token();
+
+        // Only one caller can win the rotation; a concurrent replay sees no match
+        let claimed = self
+            .collection
+            .update_one(
+                doc! { "_id": existing.id, "revoked": false },
+                doc! { "$set": {
+                    "revoked": true,
+                    "replaced_by": token::hash_token(&new_token),
+                } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        if claimed.modified_count != 1 {
+            return Err(self.reuse_detected(&existing).await);
+        }
+
+        self.store(
+            existing.user_id,
+            existing.family_id,
+            &new_token,
+            existing.jkt.clone(),
+        )
+        .await?;
+
+        Ok((existing.user_id, existing.family_id, new_token))
+    }
+
+    pub async fn revoke_family(&self, family_id: ObjectId) -> Result<(), CustomError> {
+        self.collection
+            .update_many(
+                doc! { "family_id": family_id },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Revokes the family of `refresh_token` if it belongs to `user_id`.
+    pub async fn revoke_refresh_token(
+        &self,
+        user_id: ObjectId,
+        refresh_token: &str,
+    ) -> Result<(), CustomError> {
+        let existing = self
+            .collection
+            .find_one(
+                doc! { "token_hash": token::hash_token(refresh_token), "user_id": user_id },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        match existing {
+            Some(existing) => self.revoke_family(existing.family_id).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn revoke_all_for_user(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        self.collection
+            .update_many(
+                doc! { "user_id": user_id, "revoked": false },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn store(
+        &self,
+        user_id: ObjectId,
+        family_id: ObjectId,
+        refresh_token: &str,
+        jkt: Option<String>,
+    ) -> Result<(), CustomError> {
+        let record = RefreshToken {
+            id: None,
+            user_id,
+            family_id,
+            token_hash: token::hash_token(refresh_token),
+            revoked: false,
+            replaced_by: None,
+            expires_at: DateTime::from_millis(
+                (Utc::now() + refresh_token_ttl()).timestamp_millis(),
+            ),
+            created_at: DateTime::now(),
+            jkt,
+        };
+
+        self.collection
+            .insert_one(record, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn reuse_detected(&self, existing: &RefreshToken) -> CustomError {
+        warn!(
+            "Refresh token reuse detected for user {}, revoking family {}",
+            existing.user_id, existing.family_id
+        );
+
+        if let Err(e) = self.revoke_family(existing.family_id).await {
+            return e;
+        }
+
+        CustomError::UnauthorizedError("Refresh token reuse detected".to_string())
+    }
+
+    /// Removes every refresh token of a user as part of the caller's transaction.
+    pub async fn delete_all_for_user(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        self.collection
+            .delete_many_with_session(doc! { "user_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+}
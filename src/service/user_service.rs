@@ -1,35 +1,217 @@
-use crate::model::user_model::User;
+use crate::database::{is_duplicate_key, page_offset};
+use crate::mail::{Email, MailTransport};
+use crate::model::audit_model::{AuditAction, AuditEvent};
+use crate::model::login_attempt_model::LockoutEvent;
+use crate::model::user_model::{default_roles, Permission, Role, User};
+use crate::service::access_token_service::AccessTokenService;
+use crate::service::audit_service::AuditService;
+use crate::service::login_attempt_service::LoginAttemptService;
+use crate::service::magic_link_service::{self, MagicLinkService};
+use crate::service::passkey_service::PasskeyService;
+use crate::service::password_reset_service::PasswordResetService;
+use crate::service::revocation_service::RevocationService;
+use crate::service::session_service::SessionService;
+use crate::service::todo_service::TodoService;
+use crate::service::token_service::TokenService;
 use crate::utils::error::CustomError;
 use crate::utils::model::LoginRequests;
-use crate::utils::{hashing, password_validation};
+use crate::utils::request::RequestContext;
+use crate::utils::webauthn::AuthenticationCredential;
+use crate::utils::{
+    email_validation, hash_pool, hashing, identity, jwt, password_validation, token, totp,
+};
 use actix_web::ResponseError;
 use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use futures::TryStreamExt;
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
 
-use mongodb::bson::{doc, oid::ObjectId};
-use mongodb::{Client, Collection};
-use serde::Serialize;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Client, ClientSession, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
 
 pub struct UserService {
+    client: Client,
     collection: Collection<User>,
+    todo_service: TodoService,
+    token_service: TokenService,
+    revocation_service: RevocationService,
+    access_token_service: AccessTokenService,
+    password_reset_service: PasswordResetService,
+    magic_link_service: MagicLinkService,
+    passkey_service: PasskeyService,
+    session_service: SessionService,
+    login_attempt_service: LoginAttemptService,
+    audit_service: AuditService,
+    mailer: Arc<dyn MailTransport>,
 }
 
-#[allow(dead_code)]
 #[derive(Serialize)]
 pub struct LoginResponse {
-    token: String,
+    pub token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub csrf_token: String,
 }
 
-#[derive(Serialize)]
-struct Claims {
-    id: String,
+/// Result of a password login: either tokens, or a challenge that has to be
+/// completed with a second factor first.
+pub enum LoginOutcome {
+    Tokens(LoginResponse),
+    MfaRequired { mfa_token: String, expires_in: i64 },
+}
+
+pub struct TotpSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+pub enum AccountDeletion {
+    Deleted,
+    // The account can be restored until `purge_after`
+    Scheduled { purge_after: DateTime },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub id: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: String,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    // Only set when the caller used a personal access token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Permission>>,
+    // Token that cookie-authenticated requests must echo in a header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub csrf: Option<String>,
+    // The login session the token belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // Set when the token may only be used with proofs from a DPoP key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+/// The `cnf` claim of a DPoP-bound token (RFC 9449).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Confirmation {
+    // JWK thumbprint of the client's key
+    pub jkt: String,
+}
+
+const VERIFY_EMAIL_PURPOSE: &str = "verify_email";
+const MFA_PURPOSE: &str = "mfa";
+const MFA_TOKEN_TTL_MINUTES: i64 = 5;
+// Access tokens cannot be revoked before they expire, so keep them short
+const MAX_ACCESS_TOKEN_TTL_MINUTES: i64 = 24 * 60;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Claims of the short-lived challenge returned by a password login when the
+/// user has two-factor authentication enabled.
+#[derive(Debug, Serialize, Deserialize)]
+struct MfaClaims {
+    sub: String,
+    purpose: String,
+    exp: usize,
+}
+
+/// Claims of the signed link sent to confirm an email address. The address is
+/// part of the token so the link dies if the user changes email meanwhile.
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailVerificationClaims {
+    sub: String,
+    email: String,
+    purpose: String,
     exp: usize,
 }
 
 impl UserService {
-    pub fn new(client: &Client) -> Self {
+    pub fn new(client: &Client, mailer: Arc<dyn MailTransport>) -> Self {
         let collection = client.database("Rust_PRo").collection("users");
-        UserService { collection }
+        let todo_service = TodoService::new(client);
+        let token_service = TokenService::new(client);
+        let revocation_service = RevocationService::new(client);
+        let access_token_service = AccessTokenService::new(client);
+        let password_reset_service = PasswordResetService::new(client);
+        let magic_link_service = MagicLinkService::new(client);
+        let passkey_service = PasskeyService::new(client);
+        let session_service = SessionService::new(client);
+        let login_attempt_service = LoginAttemptService::new(client);
+        let audit_service = AuditService::new(client);
+        UserService {
+            client: client.clone(),
+            collection,
+            todo_service,
+            token_service,
+            revocation_service,
+            access_token_service,
+            password_reset_service,
+            magic_link_service,
+            passkey_service,
+            session_service,
+            login_attempt_service,
+            audit_service,
+            mailer,
+        }
+    }
+
+    /// Backfills the normalized identity fields of accounts created before
+    /// they existed, then enforces their uniqueness. Index creation fails if
+    /// two existing accounts only differ by case; those have to be renamed by
+    /// hand.
+    pub async fn migrate_identities(&self) -> Result<(), CustomError> {
+        let mut cursor = self
+            .collection
+            .find(
+                doc! { "$or": [
+                    { "username_normalized": { "$exists": false } },
+                    { "email_normalized": { "$exists": false } },
+                ] },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let mut migrated = 0;
+        while let Some(user) = cursor
+            .try_next()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+        {
+            self.collection
+                .update_one(
+                    doc! { "_id": user.id },
+                    doc! { "$set": {
+                        "username_normalized": identity::normalize(&user.username),
+                        "email_normalized": identity::normalize(&user.email),
+                    } },
+                    None,
+                )
+                .await
+                .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!("Normalized identities of {} users", migrated);
+        }
+
+        let indexes = ["username_normalized", "email_normalized"].map(|field| {
+            IndexModel::builder()
+                .keys(doc! { field: 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build()
+        });
+        self.collection
+            .create_indexes(indexes, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
     }
 
     pub async fn create_user(
@@ -37,7 +219,26 @@ impl UserService {
         username: String,
         email: String,
         password: String,
+        ctx: &RequestContext,
+    ) -> Result<ObjectId, CustomError> {
+        let event = AuditEvent::new(AuditAction::UserRegistered, ctx).subject(&username);
+        let result = self.insert_user(username, email, password).await;
+        self.audit(event.target(result.as_ref().ok().copied()), &result)
+            .await;
+        result
+    }
+
+    async fn insert_user(
+        &self,
+        username: String,
+        email: String,
+        password: String,
     ) -> Result<ObjectId, CustomError> {
+        let username = identity::clean(&username);
+        let email = identity::clean(&email);
+        identity::validate_username(&username)?;
+        email_validation::validate_email(&email)?;
+
         // Check if email already exists
         if self.email_exists(&email).await.map_err(|_| {
             CustomError::InternalServerError("Failed to check email existence".to_string())
@@ -52,38 +253,162 @@ impl UserService {
         return Err(CustomError::ConflictError("Username already exists".to_string()));
     }
         // Validate password
-        password_validation::validate_password(&password)
-            .map_err(|e| CustomError::BadRequestError(e.to_string()))?;
+        password_validation::validate_password(&password, &username, &email)?;
 
         // Hash the password
-        let hashed_password = hashing::hash_password(&password)
-            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        let hashed_password = hash_pool::hash_password(&password).await?;
 
         // Create new user
         let new_user = User {
             id: None,
+            username_normalized: identity::normalize(&username),
+            email_normalized: identity::normalize(&email),
             username,
             email,
             password: hashed_password,
+            roles: default_roles(),
+            email_verified: false,
+            totp_enabled: false,
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+            deleted_at: None,
+            purge_after: None,
+            suspended: false,
+            suspended_reason: None,
+            password_reset_required: false,
         };
 
-        // Insert the user
+        // Insert the user; the unique indexes catch a concurrent registration
         let result = self
             .collection
-            .insert_one(new_user, None)
+            .insert_one(&new_user, None)
             .await
-            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+            .map_err(|e| {
+                if is_duplicate_key(&e) {
+                    CustomError::ConflictError("Username or email already exists".to_string())
+                } else {
+                    CustomError::InternalServerError(e.to_string())
+                }
+            })?;
 
-        // Return the inserted ID
-        result.inserted_id.as_object_id().ok_or_else(|| {
+        let user_id = result.inserted_id.as_object_id().ok_or_else(|| {
             CustomError::InternalServerError("Failed to get inserted ID".to_string())
-        })
+        })?;
+
+        // The account exists either way; the user can ask for a new link
+        if let Err(e) = self.send_verification_email(user_id, &new_user.email).await {
+            error!("Failed to send verification email to user {}: {}", user_id, e);
+        }
+
+        // Return the inserted ID
+        Ok(user_id)
+    }
+
+    async fn send_verification_email(
+        &self,
+        user_id: ObjectId,
+        email: &str,
+    ) -> Result<(), CustomError> {
+        let ttl_hours = std::env::var("EMAIL_VERIFICATION_TTL_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(24);
+
+        let token = jwt::encode_token(&EmailVerificationClaims {
+            sub: user_id.to_hex(),
+            email: email.to_string(),
+            purpose: VERIFY_EMAIL_PURPOSE.to_string(),
+            exp: (Utc::now() + Duration::hours(ttl_hours)).timestamp() as usize,
+        })?;
+
+        let link = format!("{}/v1/users/verify-email?token={}", app_base_url(), token);
+
+        self.mailer
+            .send(Email {
+                to: email.to_string(),
+                subject: "Verify your email address".to_string(),
+                body: format!(
+                    "Welcome! Confirm your email address by opening this link:\n\n{}\n\nThe link expires in {} hours.",
+                    link, ttl_hours
+                ),
+            })
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    pub async fn verify_email(&self, token: &str, ctx: &RequestContext) -> Result<(), CustomError> {
+        let result = self.confirm_email(token).await;
+        let event = AuditEvent::new(AuditAction::EmailVerified, ctx);
+        self.audit(event.target(result.as_ref().ok().copied()), &result)
+            .await;
+        result.map(|_| ())
+    }
+
+    async fn confirm_email(&self, token: &str) -> Result<ObjectId, CustomError> {
+        let invalid = || CustomError::BadRequestError("Verification link is invalid or has expired".to_string());
+
+        let claims = jwt::decode_token::<EmailVerificationClaims>(token).map_err(|_| invalid())?;
+        if claims.purpose != VERIFY_EMAIL_PURPOSE {
+            return Err(invalid());
+        }
+        let user_id = ObjectId::parse_str(&claims.sub).map_err(|_| invalid())?;
+
+        let result = self
+            .collection
+            .update_one(
+                doc! { "_id": user_id, "email": &claims.email },
+                doc! { "$set": { "email_verified": true } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        if result.matched_count == 0 {
+            return Err(invalid());
+        }
+
+        Ok(user_id)
+    }
+
+    /// Sends a fresh verification link. Succeeds silently for unknown or
+    /// already verified addresses so it cannot be used to probe accounts.
+    pub async fn resend_verification_email(
+        &self,
+        email: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.resend_verification(email).await;
+        let event = AuditEvent::new(AuditAction::VerificationResent, ctx).subject(email);
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn resend_verification(&self, email: &str) -> Result<(), CustomError> {
+        let user = self
+            .collection
+            .find_one(
+                doc! { "email_normalized": identity::normalize(email), "email_verified": false },
+                None,
+            )
+            .await
+            .map_err(|_| CustomError::InternalServerError("Database error".to_string()))?;
+
+        if let Some(User { id: Some(user_id), email, .. }) = user {
+            self.send_verification_email(user_id, &email).await?;
+        }
+
+        Ok(())
     }
 
     async fn email_exists(&self, email: &str) -> Result<bool, mongodb::error::Error> {
         let count = self
             .collection
-            .count_documents(doc! { "email": email }, None)
+            .count_documents(
+                doc! { "email_normalized": identity::normalize(email) },
+                None,
+            )
             .await?;
         Ok(count > 0)
     }
@@ -91,54 +416,1604 @@ impl UserService {
     async fn username_exists(&self, username: &str) -> Result<bool, mongodb::error::Error> {
         let count = self
             .collection
-            .count_documents(doc! { "username": username }, None)
+            .count_documents(
+                doc! { "username_normalized": identity::normalize(username) },
+                None,
+            )
             .await?;
         Ok(count > 0)
     }
 
-    pub async fn authenticate_user(
+    /// Emails a single-use reset link. Succeeds silently for unknown addresses
+    /// so it cannot be used to probe accounts.
+    pub async fn forgot_password(
         &self,
-        username: &str,
-        password: &str,
-    ) -> Result<User, CustomError> {
+        email: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.send_reset_link(email).await;
+        let event = AuditEvent::new(AuditAction::PasswordResetRequested, ctx).subject(email);
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn send_reset_link(&self, email: &str) -> Result<(), CustomError> {
         let user = self
             .collection
-            .find_one(doc! { "username": username }, None)
+            .find_one(
+                doc! { "email_normalized": identity::normalize(email) },
+                None,
+            )
             .await
-            .map_err(|_| CustomError::InternalServerError("Database error".to_string()))?
-            .ok_or_else(|| CustomError::UnauthorizedError("Invalid credentials".to_string()))?;
+            .map_err(|_| CustomError::InternalServerError("Database error".to_string()))?;
 
-        if !hashing::verify_password(password, &user.password)
-            .map_err(|_| CustomError::InternalServerError("Invalid credentials".to_string()))?
-        {
+        let (user_id, email) = match user {
+            Some(User { id: Some(user_id), email, .. }) => (user_id, email),
+            _ => return Ok(()),
+        };
+
+        self.send_password_reset_email(
+            user_id,
+            email,
+            "Someone asked to reset the password for your account. If it was you, open this link:",
+            "If you did not ask for this, you can ignore this email.",
+        )
+        .await
+    }
+
+    async fn send_password_reset_email(
+        &self,
+        user_id: ObjectId,
+        email: String,
+        intro: &str,
+        outro: &str,
+    ) -> Result<(), CustomError> {
+        let reset_token = self.password_reset_service.issue_token(user_id).await?;
+        let reset_url = std::env::var("PASSWORD_RESET_URL")
+            .unwrap_or_else(|_| format!("{}/reset-password", app_base_url()));
+
+        self.mailer
+            .send(Email {
+                to: email,
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "{}\n\n{}?token={}\n\nThe link can be used once. {}",
+                    intro, reset_url, reset_token, outro
+                ),
+            })
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    /// Sets a new password using a reset token and signs the user out
+    /// everywhere.
+    pub async fn reset_password(
+        &self,
+        reset_token: &str,
+        new_password: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.apply_password_reset(reset_token, new_password).await;
+        let event = AuditEvent::new(AuditAction::PasswordReset, ctx);
+        self.audit(event.target(result.as_ref().ok().copied()), &result)
+            .await;
+        result.map(|_| ())
+    }
+
+    async fn apply_password_reset(
+        &self,
+        reset_token: &str,
+        new_password: &str,
+    ) -> Result<ObjectId, CustomError> {
+        // Validate before consuming so a rejected password does not burn the token
+        let user = self
+            .find_by_id(self.password_reset_service.find_user(reset_token).await?)
+            .await?;
+        password_validation::validate_password(new_password, &user.username, &user.email)?;
+
+        let user_id = self.password_reset_service.consume_token(reset_token).await?;
+
+        let hashed_password = hash_pool::hash_password(new_password).await?;
+
+        // Receiving the reset email proves the address belongs to the user
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$set": {
+                    "password": hashed_password,
+                    "email_verified": true,
+                    "password_reset_required": false,
+                } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        self.revoke_all_sessions(user_id).await?;
+        Ok(user_id)
+    }
+
+    /// Looks an account up by email when `identifier` contains an `@`, and by
+    /// username otherwise, ignoring case and Unicode representation. An email
+    /// that matches no account is tried as a legacy username.
+    async fn find_by_identifier(&self, identifier: &str) -> Result<Option<User>, CustomError> {
+        let normalized = identity::normalize(identifier);
+
+        for field in identity::lookup_fields(&normalized) {
+            let user = self
+                .collection
+                .find_one(doc! { *field: &normalized }, None)
+                .await
+                .map_err(|_| CustomError::InternalServerError("Database error".to_string()))?;
+            if user.is_some() {
+                return Ok(user);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Checks a password against the account found by `find_by_identifier`.
+    async fn check_credentials(
+        &self,
+        user: Option<User>,
+        password: &str,
+    ) -> Result<User, CustomError> {
+        let user =
+            user.ok_or_else(|| CustomError::UnauthorizedError("Invalid credentials".to_string()))?;
+
+        if !hash_pool::verify_password(password, &user.password).await? {
             return Err(CustomError::UnauthorizedError(
                 "Invalid credentials".to_string(),
             ));
         }
 
+        check_can_log_in(&user)?;
+
+        if hashing::needs_rehash(&user.password) {
+            self.upgrade_password_hash(&user, password).await;
+        }
+
         Ok(user)
     }
-    pub async fn login_fn(&self, login_data: LoginRequests) -> Result<String, CustomError> {
+
+    /// Re-hashes a verified password with the current algorithm. Failures are
+    /// only logged; the old hash keeps working until the next login.
+    async fn upgrade_password_hash(&self, user: &User, password: &str) {
+        let hashed_password = match hash_pool::hash_password(password).await {
+            Ok(hashed_password) => hashed_password,
+            Err(e) => {
+                error!("Failed to rehash password for {}: {}", user.username, e);
+                return;
+            }
+        };
+
+        // Matching on the old hash keeps a concurrent password change intact
+        if let Err(e) = self
+            .collection
+            .update_one(
+                doc! { "_id": user.id, "password": &user.password },
+                doc! { "$set": { "password": hashed_password } },
+                None,
+            )
+            .await
+        {
+            error!("Failed to store rehashed password for {}: {}", user.username, e);
+        }
+    }
+
+    /// Logs a user in by username or email. Repeated failures for the same
+    /// account or client IP lock further attempts out with a 429 until the
+    /// backoff expires.
+    pub async fn login_fn(
+        &self,
+        login_data: LoginRequests,
+        ctx: &RequestContext,
+    ) -> Result<LoginOutcome, CustomError> {
+        let event = AuditEvent::new(AuditAction::Login, ctx)
+            .subject(&identity::normalize(&login_data.identifier));
+        let result = self.login(login_data, ctx).await;
+        self.audit(
+            event.target(result.as_ref().ok().map(|(user_id, _)| *user_id)),
+            &result,
+        )
+        .await;
+        result.map(|(_, outcome)| outcome)
+    }
+
+    async fn login(
+        &self,
+        login_data: LoginRequests,
+        ctx: &RequestContext,
+    ) -> Result<(ObjectId, LoginOutcome), CustomError> {
+        let user = self.find_by_identifier(&login_data.identifier).await?;
+        let lockout_key = lockout_key(user.as_ref(), &login_data.identifier);
+        self.login_attempt_service
+            .check(&lockout_key, &ctx.ip)
+            .await?;
+
         // Authenticate user
-        let user = self
-            .authenticate_user(&login_data.username, &login_data.password)
+        let user = match self.check_credentials(user, &login_data.password).await {
+            Ok(user) => user,
+            Err(e) => {
+                if let CustomError::UnauthorizedError(..) = e {
+                    self.login_attempt_service
+                        .record_failure(&lockout_key, &ctx.ip)
+                        .await?;
+                }
+                return Err(e);
+            }
+        };
+        self.login_attempt_service
+            .record_success(&lockout_key)
             .await?;
 
-        // Generate JWT token
-        let claims = Claims {
-            id: user.id.unwrap().to_string(),
-            exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
+        self.finish_login(user, ctx).await
+    }
+
+    /// Issues tokens for an authenticated user, or a second factor challenge
+    /// if they have one enabled.
+    async fn finish_login(
+        &self,
+        user: User,
+        ctx: &RequestContext,
+    ) -> Result<(ObjectId, LoginOutcome), CustomError> {
+        if !user.email_verified {
+            return Err(CustomError::ForbiddenError(
+                "Email address has not been verified".to_string(),
+            ));
+        }
+
+        let user_id = user
+            .id
+            .ok_or_else(|| CustomError::InternalServerError("User has no ID".to_string()))?;
+
+        if user.totp_enabled {
+            let ttl = Duration::minutes(MFA_TOKEN_TTL_MINUTES);
+            let mfa_token = jwt::encode_token(&MfaClaims {
+                sub: user_id.to_hex(),
+                purpose: MFA_PURPOSE.to_string(),
+                exp: (Utc::now() + ttl).timestamp() as usize,
+            })?;
+
+            return Ok((
+                user_id,
+                LoginOutcome::MfaRequired {
+                    mfa_token,
+                    expires_in: ttl.num_seconds(),
+                },
+            ));
+        }
+
+        let tokens = self.issue_tokens(&user, ctx).await?;
+        Ok((user_id, LoginOutcome::Tokens(tokens)))
+    }
+
+    /// Emails a single-use login link. Succeeds silently for unknown addresses
+    /// so it cannot be used to probe accounts.
+    pub async fn request_magic_link(
+        &self,
+        email: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.send_magic_link(email).await;
+        let event = AuditEvent::new(AuditAction::MagicLinkRequested, ctx).subject(email);
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn send_magic_link(&self, email: &str) -> Result<(), CustomError> {
+        let user = self
+            .collection
+            .find_one(
+                doc! { "email_normalized": identity::normalize(email) },
+                None,
+            )
+            .await
+            .map_err(|_| CustomError::InternalServerError("Database error".to_string()))?;
+
+        // No point mailing a link the account could not log in with
+        let (user_id, email) = match user {
+            Some(user) if check_can_log_in(&user).is_ok() => match user.id {
+                Some(user_id) => (user_id, user.email),
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
         };
 
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(std::env::var("JWT_SECRET").unwrap().as_ref()),
+        let link_token = self.magic_link_service.issue_token(user_id, &email).await?;
+        let link_url = std::env::var("MAGIC_LINK_URL")
+            .unwrap_or_else(|_| format!("{}/magic-link", app_base_url()));
+
+        self.mailer
+            .send(Email {
+                to: email,
+                subject: "Your login link".to_string(),
+                body: format!(
+                    "Open this link to log in:\n\n{}?token={}\n\nThe link can be used once and expires in {} minutes. If you did not ask for it, you can ignore this email.",
+                    link_url,
+                    link_token,
+                    magic_link_service::magic_link_ttl().num_minutes()
+                ),
+            })
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+
+    /// Logs a user in with an emailed link. Accounts with two-factor
+    /// authentication still have to complete the second factor.
+    pub async fn login_with_magic_link(
+        &self,
+        link_token: &str,
+        ctx: &RequestContext,
+    ) -> Result<LoginOutcome, CustomError> {
+        let result = self.magic_link_login(link_token, ctx).await;
+        let event = AuditEvent::new(AuditAction::MagicLinkLogin, ctx);
+        self.audit(
+            event.target(result.as_ref().ok().map(|(user_id, _)| *user_id)),
+            &result,
         )
-        .map_err(|_| CustomError::InternalServerError("Token generation failed".to_string()))?;
+        .await;
+        result.map(|(_, outcome)| outcome)
+    }
+
+    async fn magic_link_login(
+        &self,
+        link_token: &str,
+        ctx: &RequestContext,
+    ) -> Result<(ObjectId, LoginOutcome), CustomError> {
+        let (user_id, email) = self.magic_link_service.consume_token(link_token).await?;
+        let mut user = self.find_by_id(user_id).await?;
+        if user.email != email {
+            return Err(CustomError::UnauthorizedError(
+                "Login link is invalid or has expired".to_string(),
+            ));
+        }
+        check_can_log_in(&user)?;
 
-        Ok(token)
+        // Following the link proves the user controls the address
+        if !user.email_verified {
+            self.collection
+                .update_one(
+                    doc! { "_id": user_id },
+                    doc! { "$set": { "email_verified": true } },
+                    None,
+                )
+                .await
+                .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+            user.email_verified = true;
+        }
+
+        self.finish_login(user, ctx).await
     }
 
-    // ... other methods ...
+    /// Logs a user in with a passkey. User verification by the authenticator
+    /// stands in for the second factor, so no TOTP code is asked for.
+    pub async fn login_with_passkey(
+        &self,
+        credential: &AuthenticationCredential,
+        ctx: &RequestContext,
+    ) -> Result<LoginResponse, CustomError> {
+        let result = self.passkey_login(credential, ctx).await;
+        let event = AuditEvent::new(AuditAction::PasskeyLogin, ctx);
+        self.audit(
+            event.target(result.as_ref().ok().map(|(user_id, _)| *user_id)),
+            &result,
+        )
+        .await;
+        result.map(|(_, tokens)| tokens)
+    }
+
+    async fn passkey_login(
+        &self,
+        credential: &AuthenticationCredential,
+        ctx: &RequestContext,
+    ) -> Result<(ObjectId, LoginResponse), CustomError> {
+        let user_id = self
+            .passkey_service
+            .finish_authentication(credential)
+            .await?;
+        let user = self.find_by_id(user_id).await?;
+        check_can_log_in(&user)?;
+        if !user.email_verified {
+            return Err(CustomError::ForbiddenError(
+                "Email address has not been verified".to_string(),
+            ));
+        }
+
+        let tokens = self.issue_tokens(&user, ctx).await?;
+        Ok((user_id, tokens))
+    }
+
+    /// Completes a login that returned `LoginOutcome::MfaRequired`.
+    pub async fn complete_mfa_login(
+        &self,
+        mfa_token: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+        ctx: &RequestContext,
+    ) -> Result<LoginResponse, CustomError> {
+        let result = self
+            .verify_mfa_login(mfa_token, code, recovery_code, ctx)
+            .await;
+        let event = AuditEvent::new(AuditAction::MfaLogin, ctx);
+        self.audit(
+            event.target(result.as_ref().ok().map(|(user_id, _)| *user_id)),
+            &result,
+        )
+        .await;
+        result.map(|(_, tokens)| tokens)
+    }
+
+    async fn verify_mfa_login(
+        &self,
+        mfa_token: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+        ctx: &RequestContext,
+    ) -> Result<(ObjectId, LoginResponse), CustomError> {
+        let invalid = || CustomError::UnauthorizedError("MFA challenge is invalid or has expired".to_string());
+
+        let claims = jwt::decode_token::<MfaClaims>(mfa_token).map_err(|_| invalid())?;
+        if claims.purpose != MFA_PURPOSE {
+            return Err(invalid());
+        }
+        let user_id = ObjectId::parse_str(&claims.sub).map_err(|_| invalid())?;
+        let user = self.find_by_id(user_id).await.map_err(|_| invalid())?;
+        // The account may have changed since the password was checked
+        check_can_log_in(&user)?;
+
+        // Second factor guesses count against the same lockout as passwords
+        let lockout_key = lockout_key(Some(&user), &user.username);
+        self.login_attempt_service
+            .check(&lockout_key, &ctx.ip)
+            .await?;
+        if let Err(e) = self.verify_second_factor(&user, code, recovery_code).await {
+            if let CustomError::UnauthorizedError(..) = e {
+                self.login_attempt_service
+                    .record_failure(&lockout_key, &ctx.ip)
+                    .await?;
+            }
+            return Err(e);
+        }
+        self.login_attempt_service
+            .record_success(&lockout_key)
+            .await?;
+
+        let tokens = self.issue_tokens(&user, ctx).await?;
+        Ok((user_id, tokens))
+    }
+
+    /// Starts TOTP enrollment. The secret only takes effect once confirmed
+    /// with `enable_totp`.
+    pub async fn setup_totp(
+        &self,
+        user_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<TotpSetup, CustomError> {
+        let result = self.begin_totp_setup(user_id).await;
+        let event = AuditEvent::new(AuditAction::TotpSetup, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn begin_totp_setup(&self, user_id: ObjectId) -> Result<TotpSetup, CustomError> {
+        let user = self.find_by_id(user_id).await?;
+        if user.totp_enabled {
+            return Err(CustomError::ConflictError(
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+
+        let secret = totp::generate_secret();
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$set": { "totp_pending_secret": &secret } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let issuer = std::env::var("TOTP_ISSUER")
+            .or_else(|_| std::env::var("SERVICE_NAME"))
+            .unwrap_or_else(|_| "Rust_API".to_string());
+
+        Ok(TotpSetup {
+            otpauth_uri: totp::provisioning_uri(&secret, &user.email, &issuer),
+            secret,
+        })
+    }
+
+    /// Confirms enrollment with a code from the authenticator and returns the
+    /// recovery codes. They are only shown this once.
+    pub async fn enable_totp(
+        &self,
+        user_id: ObjectId,
+        code: &str,
+        ctx: &RequestContext,
+    ) -> Result<Vec<String>, CustomError> {
+        let result = self.confirm_totp(user_id, code).await;
+        let event = AuditEvent::new(AuditAction::TotpEnabled, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn confirm_totp(&self, user_id: ObjectId, code: &str) -> Result<Vec<String>, CustomError> {
+        let user = self.find_by_id(user_id).await?;
+        let secret = user.totp_pending_secret.ok_or_else(|| {
+            CustomError::BadRequestError("Start two-factor setup first".to_string())
+        })?;
+
+        let step = totp::verify_code(&secret, code, Utc::now().timestamp())
+            .ok_or_else(|| CustomError::BadRequestError("Invalid authentication code".to_string()))?;
+
+        let recovery_codes = totp::generate_recovery_codes(RECOVERY_CODE_COUNT);
+        let hashes: Vec<String> = recovery_codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! {
+                    "$set": {
+                        "totp_enabled": true,
+                        "totp_secret": &secret,
+                        "totp_last_step": step,
+                        "recovery_codes": hashes,
+                    },
+                    "$unset": { "totp_pending_secret": "" },
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(recovery_codes)
+    }
+
+    pub async fn disable_totp(
+        &self,
+        user_id: ObjectId,
+        password: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self
+            .remove_totp(user_id, password, code, recovery_code)
+            .await;
+        let event = AuditEvent::new(AuditAction::TotpDisabled, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn remove_totp(
+        &self,
+        user_id: ObjectId,
+        password: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+    ) -> Result<(), CustomError> {
+        let user = self.find_by_id(user_id).await?;
+        if !user.totp_enabled {
+            return Err(CustomError::BadRequestError(
+                "Two-factor authentication is not enabled".to_string(),
+            ));
+        }
+
+        if !hash_pool::verify_password(password, &user.password).await? {
+            return Err(CustomError::UnauthorizedError("Invalid credentials".to_string()));
+        }
+
+        self.verify_second_factor(&user, code, recovery_code).await?;
+
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! {
+                    "$set": { "totp_enabled": false, "recovery_codes": [] },
+                    "$unset": { "totp_secret": "", "totp_pending_secret": "", "totp_last_step": "" },
+                },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Re-checks a signed-in user's password, and their second factor when
+    /// two-factor authentication is enabled, before a sensitive change.
+    /// Wrong guesses count against the same lockout as logins, so a stolen
+    /// session cannot be used to brute-force the password.
+    pub async fn reauthenticate(
+        &self,
+        user_id: ObjectId,
+        password: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+        ctx: &RequestContext,
+    ) -> Result<User, CustomError> {
+        let user = self.find_by_id(user_id).await?;
+
+        let lockout_key = lockout_key(Some(&user), &user.username);
+        self.login_attempt_service
+            .check(&lockout_key, &ctx.ip)
+            .await?;
+        if let Err(e) = self
+            .check_reauthentication(&user, password, code, recovery_code)
+            .await
+        {
+            if let CustomError::UnauthorizedError(..) = e {
+                self.login_attempt_service
+                    .record_failure(&lockout_key, &ctx.ip)
+                    .await?;
+            }
+            return Err(e);
+        }
+        self.login_attempt_service
+            .record_success(&lockout_key)
+            .await?;
+
+        Ok(user)
+    }
+
+    async fn check_reauthentication(
+        &self,
+        user: &User,
+        password: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+    ) -> Result<(), CustomError> {
+        if !hash_pool::verify_password(password, &user.password).await? {
+            return Err(CustomError::UnauthorizedError(
+                "Invalid credentials".to_string(),
+            ));
+        }
+        if user.totp_enabled {
+            self.verify_second_factor(user, code, recovery_code).await?;
+        }
+        Ok(())
+    }
+
+    /// Accepts either a current TOTP code or an unused recovery code. Both are
+    /// consumed atomically so neither can be replayed.
+    async fn verify_second_factor(
+        &self,
+        user: &User,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+    ) -> Result<(), CustomError> {
+        let invalid = || CustomError::UnauthorizedError("Invalid authentication code".to_string());
+        let user_id = user
+            .id
+            .ok_or_else(|| CustomError::InternalServerError("User has no ID".to_string()))?;
+
+        let result = match (code, recovery_code) {
+            (Some(code), _) => {
+                let secret = user.totp_secret.as_deref().ok_or_else(invalid)?;
+                let step = totp::verify_code(secret, code, Utc::now().timestamp())
+                    .ok_or_else(invalid)?;
+
+                self.collection
+                    .update_one(
+                        doc! {
+                            "_id": user_id,
+                            "$or": [
+                                { "totp_last_step": null },
+                                { "totp_last_step": { "$lt": step } },
+                            ],
+                        },
+                        doc! { "$set": { "totp_last_step": step } },
+                        None,
+                    )
+                    .await
+            }
+            (None, Some(recovery_code)) => {
+                let hash = totp::hash_recovery_code(recovery_code);
+                self.collection
+                    .update_one(
+                        doc! { "_id": user_id, "recovery_codes": &hash },
+                        doc! { "$pull": { "recovery_codes": &hash } },
+                        None,
+                    )
+                    .await
+            }
+            (None, None) => {
+                return Err(CustomError::BadRequestError(
+                    "An authentication code or recovery code is required".to_string(),
+                ))
+            }
+        };
+
+        let result = result.map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        if result.modified_count != 1 {
+            return Err(invalid());
+        }
+
+        Ok(())
+    }
+
+    pub async fn refresh_fn(
+        &self,
+        refresh_token: &str,
+        ctx: &RequestContext,
+    ) -> Result<LoginResponse, CustomError> {
+        let result = self.rotate_session(refresh_token, ctx).await;
+        let event = AuditEvent::new(AuditAction::TokenRefreshed, ctx);
+        self.audit(
+            event.target(result.as_ref().ok().map(|(user_id, _)| *user_id)),
+            &result,
+        )
+        .await;
+        result.map(|(_, tokens)| tokens)
+    }
+
+    async fn rotate_session(
+        &self,
+        refresh_token: &str,
+        ctx: &RequestContext,
+    ) -> Result<(ObjectId, LoginResponse), CustomError> {
+        let (user_id, session_id, refresh_token) = self
+            .token_service
+            .rotate_refresh_token(refresh_token, ctx.dpop_jkt.as_deref())
+            .await?;
+
+        // Roles may have changed since the last token was issued
+        let user = self.find_by_id(user_id).await.map_err(|e| match e {
+            CustomError::NotFoundError(_) => {
+                CustomError::UnauthorizedError("Invalid refresh token".to_string())
+            }
+            e => e,
+        })?;
+
+        self.session_service
+            .refresh(session_id, user_id, ctx)
+            .await?;
+
+        Ok((
+            user_id,
+            login_response(&user, refresh_token, session_id, ctx.dpop_jkt.clone())?,
+        ))
+    }
+
+    /// Revokes the access token described by `claims` and, when given, the
+    /// refresh token family it was issued with.
+    pub async fn logout_fn(
+        &self,
+        claims: &Claims,
+        refresh_token: Option<&str>,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.end_session(claims, refresh_token).await;
+        let event =
+            AuditEvent::new(AuditAction::Logout, ctx).target(ObjectId::parse_str(&claims.id).ok());
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn end_session(
+        &self,
+        claims: &Claims,
+        refresh_token: Option<&str>,
+    ) -> Result<(), CustomError> {
+        let user_id = ObjectId::parse_str(&claims.id).map_err(|_| {
+            CustomError::BadRequestError("Invalid user ID format in token".to_string())
+        })?;
+
+        // Logging out with a personal access token revokes that token
+        if claims.scopes.is_some() {
+            let id = ObjectId::parse_str(&claims.jti).map_err(|_| {
+                CustomError::BadRequestError("Invalid token ID format".to_string())
+            })?;
+            self.access_token_service.revoke_token(user_id, id).await?;
+            return Ok(());
+        }
+
+        self.revocation_service.revoke_token(claims).await?;
+
+        if let Some(session_id) = claims
+            .sid
+            .as_deref()
+            .and_then(|sid| ObjectId::parse_str(sid).ok())
+        {
+            self.session_service.revoke(user_id, session_id).await?;
+            self.token_service.revoke_family(session_id).await?;
+        }
+        if let Some(refresh_token) = refresh_token {
+            self.token_service
+                .revoke_refresh_token(user_id, refresh_token)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Revokes every access, refresh and personal access token the user
+    /// currently holds.
+    pub async fn logout_all_fn(
+        &self,
+        user_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.revoke_all_sessions(user_id).await;
+        let event = AuditEvent::new(AuditAction::LogoutAll, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn revoke_all_sessions(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        self.revocation_service.revoke_all_for_user(user_id).await?;
+        self.session_service.revoke_all_for_user(user_id).await?;
+        self.token_service.revoke_all_for_user(user_id).await?;
+        self.access_token_service.revoke_all_for_user(user_id).await
+    }
+
+    /// Signs one of the user's devices out: its refresh tokens stop working
+    /// and access tokens carrying its `sid` are rejected. Returns `false` if
+    /// the user has no such active session.
+    pub async fn revoke_session(
+        &self,
+        user_id: ObjectId,
+        session_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<bool, CustomError> {
+        let result = self.end_device_session(user_id, session_id).await;
+        let event = AuditEvent::new(AuditAction::SessionRevoked, ctx)
+            .target(Some(user_id))
+            .subject(&session_id.to_hex());
+        let event = match &result {
+            Ok(true) => event,
+            Ok(false) => event.failure("Session not found".to_string()),
+            Err(e) => event.failure(e.to_string()),
+        };
+        self.audit_service.record(event).await;
+        result
+    }
+
+    async fn end_device_session(
+        &self,
+        user_id: ObjectId,
+        session_id: ObjectId,
+    ) -> Result<bool, CustomError> {
+        if !self.session_service.revoke(user_id, session_id).await? {
+            return Ok(false);
+        }
+        self.token_service.revoke_family(session_id).await?;
+        Ok(true)
+    }
+
+    pub async fn find_by_id(&self, user_id: ObjectId) -> Result<User, CustomError> {
+        self.collection
+            .find_one(doc! { "_id": user_id }, None)
+            .await
+            .map_err(|_| CustomError::InternalServerError("Database error".to_string()))?
+            .ok_or_else(|| CustomError::NotFoundError("User not found".to_string()))
+    }
+
+    /// Changes the username and/or email. A new email address has to be
+    /// verified again before the account can log in.
+    pub async fn update_profile(
+        &self,
+        user_id: ObjectId,
+        username: Option<String>,
+        email: Option<String>,
+        ctx: &RequestContext,
+    ) -> Result<User, CustomError> {
+        let result = self.apply_profile_update(user_id, username, email).await;
+        let event = AuditEvent::new(AuditAction::ProfileUpdated, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn apply_profile_update(
+        &self,
+        user_id: ObjectId,
+        username: Option<String>,
+        email: Option<String>,
+    ) -> Result<User, CustomError> {
+        let user = self.find_by_id(user_id).await?;
+        let mut changes = doc! {};
+
+        let username = username.map(|u| identity::clean(&u));
+        if let Some(username) = username.filter(|u| *u != user.username) {
+            identity::validate_username(&username)?;
+            let normalized = identity::normalize(&username);
+            // Changing only the case of one's own username is allowed
+            if normalized != user.username_normalized
+                && self.username_exists(&username).await.map_err(|_| {
+                    CustomError::InternalServerError(
+                        "Failed to check username existence".to_string(),
+                    )
+                })?
+            {
+                return Err(CustomError::ConflictError(
+                    "Username already exists".to_string(),
+                ));
+            }
+            changes.insert("username", username);
+            changes.insert("username_normalized", normalized);
+        }
+
+        let email = email.map(|e| identity::clean(&e));
+        let new_email = email.filter(|e| *e != user.email);
+        if let Some(email) = &new_email {
+            email_validation::validate_email(email)?;
+            let normalized = identity::normalize(email);
+            if normalized != user.email_normalized
+                && self.email_exists(email).await.map_err(|_| {
+                    CustomError::InternalServerError("Failed to check email existence".to_string())
+                })?
+            {
+                return Err(CustomError::ConflictError(
+                    "Email already exists".to_string(),
+                ));
+            }
+            changes.insert("email", email);
+            changes.insert("email_normalized", normalized);
+            changes.insert("email_verified", false);
+        }
+
+        if changes.is_empty() {
+            return Ok(user);
+        }
+
+        self.collection
+            .update_one(doc! { "_id": user_id }, doc! { "$set": changes }, None)
+            .await
+            .map_err(|e| {
+                if is_duplicate_key(&e) {
+                    CustomError::ConflictError("Username or email already exists".to_string())
+                } else {
+                    CustomError::InternalServerError(e.to_string())
+                }
+            })?;
+
+        if let Some(email) = &new_email {
+            if let Err(e) = self.send_verification_email(user_id, email).await {
+                error!("Failed to send verification email to user {}: {}", user_id, e);
+            }
+        }
+
+        self.find_by_id(user_id).await
+    }
+
+    /// Changes the password after re-checking the current one, then signs the
+    /// user out everywhere.
+    pub async fn change_password(
+        &self,
+        user_id: ObjectId,
+        current_password: &str,
+        new_password: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self
+            .apply_password_change(user_id, current_password, new_password)
+            .await;
+        let event = AuditEvent::new(AuditAction::PasswordChanged, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn apply_password_change(
+        &self,
+        user_id: ObjectId,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), CustomError> {
+        let user = self.find_by_id(user_id).await?;
+
+        if !hash_pool::verify_password(current_password, &user.password).await? {
+            return Err(CustomError::UnauthorizedError(
+                "Current password is incorrect".to_string(),
+            ));
+        }
+
+        password_validation::validate_password(new_password, &user.username, &user.email)?;
+
+        let hashed_password = hash_pool::hash_password(new_password).await?;
+
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$set": { "password": hashed_password } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        self.revoke_all_sessions(user_id).await
+    }
+
+    /// Deletes the account after re-checking the password. With a grace
+    /// period configured the account is only disabled and can be restored
+    /// until it is purged.
+    pub async fn delete_account(
+        &self,
+        user_id: ObjectId,
+        password: &str,
+        ctx: &RequestContext,
+    ) -> Result<AccountDeletion, CustomError> {
+        let result = self.remove_account(user_id, password).await;
+        let event = AuditEvent::new(AuditAction::AccountDeleted, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn remove_account(
+        &self,
+        user_id: ObjectId,
+        password: &str,
+    ) -> Result<AccountDeletion, CustomError> {
+        let user = self.find_by_id(user_id).await?;
+
+        if !hash_pool::verify_password(password, &user.password).await? {
+            return Err(CustomError::UnauthorizedError(
+                "Password is incorrect".to_string(),
+            ));
+        }
+
+        self.revoke_all_sessions(user_id).await?;
+
+        let grace_days = account_deletion_grace_days();
+        if grace_days == 0 {
+            self.purge_account(user_id).await?;
+            return Ok(AccountDeletion::Deleted);
+        }
+
+        let purge_after =
+            DateTime::from_millis((Utc::now() + Duration::days(grace_days)).timestamp_millis());
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$set": { "deleted_at": DateTime::now(), "purge_after": purge_after } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(AccountDeletion::Scheduled { purge_after })
+    }
+
+    /// Undoes a scheduled deletion that is still within its grace period.
+    pub async fn restore_account(
+        &self,
+        identifier: &str,
+        password: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.reactivate_account(identifier, password, &ctx.ip).await;
+        let event = AuditEvent::new(AuditAction::AccountRestored, ctx)
+            .subject(&identity::normalize(identifier));
+        self.audit(event.target(result.as_ref().ok().copied()), &result)
+            .await;
+        result.map(|_| ())
+    }
+
+    async fn reactivate_account(
+        &self,
+        identifier: &str,
+        password: &str,
+        ip: &str,
+    ) -> Result<ObjectId, CustomError> {
+        let user = self.find_by_identifier(identifier).await?;
+        let lockout_key = lockout_key(user.as_ref(), identifier);
+        self.login_attempt_service.check(&lockout_key, ip).await?;
+        let invalid = || CustomError::UnauthorizedError("Invalid credentials".to_string());
+
+        let now = DateTime::now();
+        let user = user.filter(|user| {
+            user.purge_after
+                .is_some_and(|purge_after| purge_after > now)
+        });
+        let user = match user {
+            Some(user) if hash_pool::verify_password(password, &user.password).await? => user,
+            _ => {
+                self.login_attempt_service
+                    .record_failure(&lockout_key, ip)
+                    .await?;
+                return Err(invalid());
+            }
+        };
+        self.login_attempt_service
+            .record_success(&lockout_key)
+            .await?;
+
+        self.collection
+            .update_one(
+                doc! { "_id": user.id },
+                doc! { "$unset": { "deleted_at": "", "purge_after": "" } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        user.id
+            .ok_or_else(|| CustomError::InternalServerError("User has no ID".to_string()))
+    }
+
+    /// Purges accounts whose grace period has run out. Returns how many were
+    /// removed.
+    pub async fn purge_deleted_accounts(&self) -> Result<usize, CustomError> {
+        let cursor = self
+            .collection
+            .find(doc! { "purge_after": { "$lte": DateTime::now() } }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        let expired: Vec<User> = cursor
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let ctx = RequestContext::system();
+        let mut purged = 0;
+        for user_id in expired.into_iter().filter_map(|user| user.id) {
+            let result = self.purge_account(user_id).await;
+            let event = AuditEvent::new(AuditAction::AccountPurged, &ctx).target(Some(user_id));
+            self.audit(event, &result).await;
+            match result {
+                Ok(()) => purged += 1,
+                Err(e) => error!("Failed to purge deleted account {}: {}", user_id, e),
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Removes the user and everything that belongs to them (todos, tokens,
+    /// sessions, passkeys and login history) in one transaction, so a failure
+    /// never leaves orphaned records behind.
+    async fn purge_account(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        let mut session = self
+            .client
+            .start_session(None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        session
+            .start_transaction(None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        if let Err(e) = self.delete_user_documents(user_id, &mut session).await {
+            let _ = session.abort_transaction().await;
+            return Err(e);
+        }
+
+        session
+            .commit_transaction()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        info!("Deleted account {}", user_id);
+        Ok(())
+    }
+
+    async fn delete_user_documents(
+        &self,
+        user_id: ObjectId,
+        session: &mut ClientSession,
+    ) -> Result<(), CustomError> {
+        let user = self
+            .collection
+            .find_one_with_session(doc! { "_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .ok_or_else(|| CustomError::NotFoundError("User not found".to_string()))?;
+
+        self.todo_service
+            .delete_all_for_user(user_id, session)
+            .await
+            .map_err(CustomError::InternalServerError)?;
+        self.token_service
+            .delete_all_for_user(user_id, session)
+            .await?;
+        self.session_service
+            .delete_all_for_user(user_id, session)
+            .await?;
+        self.access_token_service
+            .delete_all_for_user(user_id, session)
+            .await?;
+        self.password_reset_service
+            .delete_all_for_user(user_id, session)
+            .await?;
+        self.magic_link_service
+            .delete_all_for_user(user_id, session)
+            .await?;
+        self.passkey_service
+            .delete_all_for_user(user_id, session)
+            .await?;
+        // Lockouts were keyed by the raw username before identities were normalized
+        self.login_attempt_service
+            .delete_for_usernames(&[&user.username_normalized, &user.username], session)
+            .await?;
+
+        self.collection
+            .delete_one_with_session(doc! { "_id": user_id }, None, session)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Pages through users, optionally filtered by a case-insensitive search
+    /// over username and email. Returns the page and the total match count.
+    pub async fn list_users(
+        &self,
+        search: Option<&str>,
+        suspended: Option<bool>,
+        page: u64,
+        per_page: i64,
+    ) -> Result<(Vec<User>, u64), CustomError> {
+        let mut filter = doc! {};
+        if let Some(search) = search.filter(|s| !s.trim().is_empty()) {
+            let pattern = regex::escape(search.trim());
+            filter.insert(
+                "$or",
+                vec![
+                    doc! { "username": { "$regex": &pattern, "$options": "i" } },
+                    doc! { "email": { "$regex": &pattern, "$options": "i" } },
+                ],
+            );
+        }
+        if let Some(suspended) = suspended {
+            filter.insert("suspended", suspended);
+        }
+
+        let total = self
+            .collection
+            .count_documents(filter.clone(), None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .skip(page_offset(page, per_page)?)
+            .limit(per_page)
+            .build();
+        let users = self
+            .collection
+            .find(filter, options)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?
+            .try_collect()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        Ok((users, total))
+    }
+
+    /// Suspending also ends every session, so the user is locked out at once.
+    pub async fn set_suspended(
+        &self,
+        user_id: ObjectId,
+        suspended: bool,
+        reason: Option<String>,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let action = if suspended {
+            AuditAction::UserSuspended
+        } else {
+            AuditAction::UserUnsuspended
+        };
+        let result = self.apply_suspension(user_id, suspended, reason).await;
+        self.audit(AuditEvent::new(action, ctx).target(Some(user_id)), &result)
+            .await;
+        result
+    }
+
+    async fn apply_suspension(
+        &self,
+        user_id: ObjectId,
+        suspended: bool,
+        reason: Option<String>,
+    ) -> Result<(), CustomError> {
+        let update = if suspended {
+            doc! { "$set": { "suspended": true, "suspended_reason": reason } }
+        } else {
+            doc! { "$set": { "suspended": false }, "$unset": { "suspended_reason": "" } }
+        };
+
+        let result = self
+            .collection
+            .update_one(doc! { "_id": user_id }, update, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        if result.matched_count == 0 {
+            return Err(CustomError::NotFoundError("User not found".to_string()));
+        }
+
+        if suspended {
+            self.revoke_all_sessions(user_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Signs the user out and blocks login until they pick a new password
+    /// through the emailed reset link.
+    pub async fn force_password_reset(
+        &self,
+        user_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.require_password_reset(user_id).await;
+        let event = AuditEvent::new(AuditAction::PasswordResetForced, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn require_password_reset(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        let user = self.find_by_id(user_id).await?;
+
+        self.collection
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$set": { "password_reset_required": true } },
+                None,
+            )
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        self.revoke_all_sessions(user_id).await?;
+
+        self.send_password_reset_email(
+            user_id,
+            user.email,
+            "An administrator has asked you to choose a new password. Open this link to set one:",
+            "You will not be able to log in until your password has been reset.",
+        )
+        .await
+    }
+
+    /// Deletes an account and its todos right away, skipping any grace period.
+    pub async fn delete_user(
+        &self,
+        user_id: ObjectId,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.delete_user_now(user_id).await;
+        let event = AuditEvent::new(AuditAction::UserDeleted, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn delete_user_now(&self, user_id: ObjectId) -> Result<(), CustomError> {
+        self.find_by_id(user_id).await?;
+        self.revoke_all_sessions(user_id).await?;
+        self.purge_account(user_id).await
+    }
+
+    /// Rejects users that could no longer log in: suspended, scheduled for
+    /// deletion or required to reset their password since their token was
+    /// issued. Returns the current user.
+    pub async fn ensure_active(&self, user_id: ObjectId) -> Result<User, CustomError> {
+        let user = self.find_by_id(user_id).await.map_err(|e| match e {
+            CustomError::NotFoundError(_) => {
+                CustomError::UnauthorizedError("Invalid token".to_string())
+            }
+            e => e,
+        })?;
+        check_can_log_in(&user)?;
+
+        Ok(user)
+    }
+
+    pub async fn set_roles(
+        &self,
+        user_id: ObjectId,
+        roles: Vec<Role>,
+        ctx: &RequestContext,
+    ) -> Result<(), CustomError> {
+        let result = self.apply_roles(user_id, roles).await;
+        let event = AuditEvent::new(AuditAction::RolesChanged, ctx).target(Some(user_id));
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn apply_roles(&self, user_id: ObjectId, roles: Vec<Role>) -> Result<(), CustomError> {
+        let roles = mongodb::bson::to_bson(&roles)
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        let result = self
+            .collection
+            .update_one(doc! { "_id": user_id }, doc! { "$set": { "roles": roles } }, None)
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+
+        if result.matched_count == 0 {
+            return Err(CustomError::NotFoundError("User not found".to_string()));
+        }
+
+        Ok(())
+    }
+
+    pub async fn lockout_events(
+        &self,
+        key: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<LockoutEvent>, CustomError> {
+        self.login_attempt_service.list_events(key, limit).await
+    }
+
+    /// Lifts a login lockout for `key` (`user:<normalized username>` or
+    /// `ip:<address>`).
+    pub async fn unlock_login(&self, key: &str, ctx: &RequestContext) -> Result<(), CustomError> {
+        let result = match self.login_attempt_service.unlock(key, ctx.actor_id).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(CustomError::NotFoundError(
+                "No failed logins recorded for this key".to_string(),
+            )),
+            Err(e) => Err(e),
+        };
+        let event = AuditEvent::new(AuditAction::LoginUnlocked, ctx).subject(key);
+        self.audit(event, &result).await;
+        result
+    }
+
+    async fn audit<T>(&self, event: AuditEvent, result: &Result<T, CustomError>) {
+        self.audit_service.record(event.outcome(result)).await;
+    }
+
+    /// Starts a session for a completed login and issues its tokens.
+    async fn issue_tokens(
+        &self,
+        user: &User,
+        ctx: &RequestContext,
+    ) -> Result<LoginResponse, CustomError> {
+        let user_id = user.id.ok_or_else(|| {
+            CustomError::InternalServerError("User has no ID".to_string())
+        })?;
+
+        let session_id = self.session_service.start(user_id, ctx).await?;
+        let refresh_token = self
+            .token_service
+            .issue_refresh_token(user_id, session_id, ctx.dpop_jkt.clone())
+            .await?;
+
+        login_response(user, refresh_token, session_id, ctx.dpop_jkt.clone())
+    }
+
+    // ... other methods ...
+}
+
+/// Rejects accounts that may not log in, whatever the credential.
+fn check_can_log_in(user: &User) -> Result<(), CustomError> {
+    if user.suspended {
+        return Err(CustomError::ForbiddenError(
+            "Account is suspended".to_string(),
+        ));
+    }
+    if user.deleted_at.is_some() {
+        return Err(CustomError::ForbiddenError(
+            "Account is scheduled for deletion, restore it to log in".to_string(),
+        ));
+    }
+    if user.password_reset_required {
+        return Err(CustomError::ForbiddenError(
+            "A password reset is required, check your email".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Lockouts apply per account, whichever identifier was used to log in.
+/// Unknown identifiers are throttled under their normalized form.
+fn lockout_key(user: Option<&User>, identifier: &str) -> String {
+    match user {
+        Some(user) if !user.username_normalized.is_empty() => user.username_normalized.clone(),
+        _ => identity::normalize(identifier),
+    }
+}
+
+fn app_base_url() -> String {
+    std::env::var("APP_BASE_URL").unwrap_or_else(|_| "http://localhost:5001".to_string())
+}
+
+fn account_deletion_grace_days() -> i64 {
+    std::env::var("ACCOUNT_DELETION_GRACE_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+static ACCESS_TOKEN_TTL: OnceCell<Duration> = OnceCell::new();
+
+/// Loads the access token lifetime from `ACCESS_TOKEN_TTL_MINUTES` (default
+/// 15). Call once from `main` before serving requests.
+pub fn init_access_token_ttl() -> Result<(), String> {
+    let ttl = parse_access_token_ttl(std::env::var("ACCESS_TOKEN_TTL_MINUTES").ok().as_deref())?;
+    ACCESS_TOKEN_TTL
+        .set(ttl)
+        .map_err(|_| "Access token lifetime is already initialized".to_string())
+}
+
+fn parse_access_token_ttl(value: Option<&str>) -> Result<Duration, String> {
+    let minutes = match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid value for ACCESS_TOKEN_TTL_MINUTES: {}", value))?,
+        None => 15,
+    };
+    if !(1..=MAX_ACCESS_TOKEN_TTL_MINUTES).contains(&minutes) {
+        return Err(format!(
+            "ACCESS_TOKEN_TTL_MINUTES must be between 1 and {}",
+            MAX_ACCESS_TOKEN_TTL_MINUTES
+        ));
+    }
+    Ok(Duration::minutes(minutes))
+}
+
+fn access_token_ttl() -> Result<Duration, CustomError> {
+    ACCESS_TOKEN_TTL.get().copied().ok_or_else(|| {
+        CustomError::InternalServerError("Access token lifetime is not initialized".to_string())
+    })
+}
+
+/// Tokens for a login. With a DPoP key the access token is bound to it.
+fn login_response(
+    user: &User,
+    refresh_token: String,
+    session_id: ObjectId,
+    dpop_jkt: Option<String>,
+) -> Result<LoginResponse, CustomError> {
+    let ttl = access_token_ttl()?;
+    let csrf_token = token::generate_token();
+    let token_type = if dpop_jkt.is_some() { "DPoP" } else { "Bearer" };
+    Ok(LoginResponse {
+        token: generate_access_token(user, ttl, &csrf_token, session_id, dpop_jkt)?,
+        refresh_token,
+        token_type: token_type.to_string(),
+        expires_in: ttl.num_seconds(),
+        csrf_token,
+    })
+}
+
+fn generate_access_token(
+    user: &User,
+    ttl: Duration,
+    csrf_token: &str,
+    session_id: ObjectId,
+    dpop_jkt: Option<String>,
+) -> Result<String, CustomError> {
+    let user_id = user
+        .id
+        .ok_or_else(|| CustomError::InternalServerError("User has no ID".to_string()))?;
+
+    let now = Utc::now();
+    let claims = Claims {
+        id: user_id.to_string(),
+        exp: (now + ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        jti: ObjectId::new().to_hex(),
+        roles: user.roles.clone(),
+        scopes: None,
+        csrf: Some(csrf_token.to_string()),
+        sid: Some(session_id.to_hex()),
+        cnf: dpop_jkt.map(|jkt| Confirmation { jkt }),
+    };
+
+    jwt::encode_token(&claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_token_ttl_defaults_to_fifteen_minutes() {
+        assert_eq!(parse_access_token_ttl(None), Ok(Duration::minutes(15)));
+        assert_eq!(
+            parse_access_token_ttl(Some("60")),
+            Ok(Duration::minutes(60))
+        );
+    }
+
+    #[test]
+    fn access_token_ttl_rejects_unusable_values() {
+        for value in ["0", "-5", "1441", "9223372036854775807", "soon", ""] {
+            assert!(parse_access_token_ttl(Some(value)).is_err(), "{}", value);
+        }
+    }
 }
@@ -0,0 +1,520 @@
+use actix_web::dev::Payload;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use chrono::{Duration, Utc};
+use data_encoding::BASE64URL_NOPAD;
+use futures_util::future::LocalBoxFuture;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::service::dpop_service::DpopService;
+use crate::utils::error::CustomError;
+use crate::utils::jwt;
+
+/// Carries the proof JWT, on token requests and on requests made with a
+/// DPoP-bound access token.
+pub const DPOP_HEADER: &str = "DPoP";
+/// Hands the client a nonce to include in its next proof.
+pub const DPOP_NONCE_HEADER: &str = "DPoP-Nonce";
+
+const NONCE_PURPOSE: &str = "dpop_nonce";
+
+/// How DPoP proofs (RFC 9449) are checked.
+///
+/// Configuration:
+/// - `API_BASE_URL`: external URL of this API, which proofs name in `htu`
+///   (default `http://localhost:5001`)
+/// - `DPOP_PROOF_MAX_AGE_SECONDS`: how far a proof's `iat` may be from the
+///   server's clock (default 60)
+/// - `DPOP_REQUIRE_NONCE`: set to `false` to accept proofs without a
+///   server-issued nonce (default `true`)
+/// - `DPOP_NONCE_TTL_SECONDS`: how long a nonce stays valid (default 300)
+struct DpopPolicy {
+    base_url: String,
+    max_age: Duration,
+    require_nonce: bool,
+    nonce_ttl: Duration,
+}
+
+impl DpopPolicy {
+    fn from_env() -> Self {
+        let seconds = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::seconds)
+                .unwrap_or_else(|| Duration::seconds(default))
+        };
+
+        DpopPolicy {
+            base_url: std::env::var("API_BASE_URL")
+                .unwrap_or_else(|_| "http://localhost:5001".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            max_age: seconds("DPOP_PROOF_MAX_AGE_SECONDS", 60),
+            require_nonce: std::env::var("DPOP_REQUIRE_NONCE")
+                .map(|v| v != "false")
+                .unwrap_or(true),
+            nonce_ttl: seconds("DPOP_NONCE_TTL_SECONDS", 300),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProofHeader {
+    typ: String,
+    alg: String,
+    jwk: ProofKey,
+}
+
+#[derive(Deserialize)]
+struct ProofKey {
+    kty: String,
+    crv: String,
+    x: String,
+    y: String,
+    // Present only if the client leaked its private key
+    d: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    ath: Option<String>,
+    nonce: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NonceClaims {
+    purpose: String,
+    exp: usize,
+}
+
+/// A proof that passed every check except replay detection.
+pub struct VerifiedProof {
+    // JWK thumbprint (RFC 7638) of the client's key
+    pub jkt: String,
+    pub jti: String,
+    // Unix seconds after which the proof is no longer fresh
+    pub expires_at: i64,
+}
+
+fn invalid(reason: &str) -> CustomError {
+    CustomError::DpopProofError(reason.to_string(), None)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, CustomError> {
+    BASE64URL_NOPAD
+        .decode(value.as_bytes())
+        .map_err(|_| invalid("malformed base64url"))
+}
+
+/// base64url SHA-256, as used for thumbprints and the `ath` claim.
+fn digest(value: &[u8]) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(value))
+}
+
+/// Issues a nonce the client must echo in its next proof. Nonces are signed
+/// rather than stored, so any instance can check them.
+pub fn issue_nonce() -> Result<String, CustomError> {
+    let policy = DpopPolicy::from_env();
+    jwt::encode_token(&NonceClaims {
+        purpose: NONCE_PURPOSE.to_string(),
+        exp: (Utc::now() + policy.nonce_ttl).timestamp() as usize,
+    })
+}
+
+fn check_nonce(nonce: Option<&str>) -> Result<(), CustomError> {
+    let valid = nonce
+        .and_then(|nonce| jwt::decode_token::<NonceClaims>(nonce).ok())
+        .is_some_and(|claims| claims.purpose == NONCE_PURPOSE);
+    if valid {
+        return Ok(());
+    }
+
+    let reason = if nonce.is_some() {
+        "nonce is invalid or has expired"
+    } else {
+        "a nonce is required"
+    };
+    Err(CustomError::DpopProofError(
+        reason.to_string(),
+        Some(issue_nonce()?),
+    ))
+}
+
+/// Verifies a proof for a request to `method` and `path` on this API. Only
+/// ES256 proofs are accepted.
+///
+/// `access_token` is the token the request is authorized with, which the
+/// proof has to be bound to through `ath`. Token requests pass `None`.
+pub fn verify_proof(
+    proof: &str,
+    method: &str,
+    path: &str,
+    access_token: Option<&str>,
+) -> Result<VerifiedProof, CustomError> {
+    let policy = DpopPolicy::from_env();
+
+    let mut parts = proof.split('.');
+    let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next())
+    {
+        (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+        _ => return Err(invalid("malformed proof")),
+    };
+
+    let proof_header: ProofHeader =
+        serde_json::from_slice(&decode(header)?).map_err(|_| invalid("malformed header"))?;
+    if proof_header.typ != "dpop+jwt" {
+        return Err(invalid("typ must be dpop+jwt"));
+    }
+    if proof_header.alg != "ES256" {
+        return Err(invalid("unsupported algorithm"));
+    }
+    let jwk = proof_header.jwk;
+    if jwk.kty != "EC" || jwk.crv != "P-256" {
+        return Err(invalid("key must be an EC P-256 key"));
+    }
+    if jwk.d.is_some() {
+        return Err(invalid("key must not contain private key material"));
+    }
+
+    let (x, y) = (decode(&jwk.x)?, decode(&jwk.y)?);
+    if x.len() != 32 || y.len() != 32 {
+        return Err(invalid("malformed key"));
+    }
+    let mut point = vec![0x04];
+    point.extend_from_slice(&x);
+    point.extend_from_slice(&y);
+    let key = VerifyingKey::from_sec1_bytes(&point).map_err(|_| invalid("malformed key"))?;
+
+    let signature =
+        Signature::from_slice(&decode(signature)?).map_err(|_| invalid("malformed signature"))?;
+    // JOSE signers do not normalize S, which the verifier would reject
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let signing_input = &proof[..header.len() + 1 + claims.len()];
+    key.verify(signing_input.as_bytes(), &signature)
+        .map_err(|_| invalid("signature does not verify"))?;
+
+    let claims: ProofClaims =
+        serde_json::from_slice(&decode(claims)?).map_err(|_| invalid("malformed claims"))?;
+    if claims.jti.is_empty() {
+        return Err(invalid("jti is required"));
+    }
+    if claims.htm != method {
+        return Err(invalid("htm does not match the request method"));
+    }
+    // Query and fragment are not part of the comparison
+    let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+    if htu != format!("{}{}", policy.base_url, path) {
+        return Err(invalid("htu does not match the request URL"));
+    }
+
+    let now = Utc::now().timestamp();
+    if (now - claims.iat).abs() > policy.max_age.num_seconds() {
+        return Err(invalid("proof is not fresh"));
+    }
+
+    if let Some(access_token) = access_token {
+        if claims.ath.as_deref() != Some(digest(access_token.as_bytes()).as_str()) {
+            return Err(invalid("ath does not match the access token"));
+        }
+    }
+
+    if policy.require_nonce {
+        check_nonce(claims.nonce.as_deref())?;
+    }
+
+    // Members in lexicographic order, as RFC 7638 requires
+    let thumbprint_input = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        BASE64URL_NOPAD.encode(&x),
+        BASE64URL_NOPAD.encode(&y)
+    );
+
+    Ok(VerifiedProof {
+        jkt: digest(thumbprint_input.as_bytes()),
+        jti: claims.jti,
+        expires_at: claims.iat + policy.max_age.num_seconds(),
+    })
+}
+
+/// Checks the request's `DPoP` header, if any, and returns the thumbprint of
+/// the key that signed it. Each proof is accepted once.
+pub async fn check_request(
+    req: &HttpRequest,
+    access_token: Option<&str>,
+) -> Result<Option<String>, CustomError> {
+    let mut headers = req.headers().get_all(DPOP_HEADER);
+    let proof = match (headers.next(), headers.next()) {
+        (None, _) => return Ok(None),
+        (Some(proof), None) => proof.to_str().map_err(|_| invalid("malformed proof"))?,
+        (Some(_), Some(_)) => return Err(invalid("only one proof may be sent")),
+    };
+
+    let proof = verify_proof(proof, req.method().as_str(), req.path(), access_token)?;
+
+    let dpop_service = req.app_data::<web::Data<DpopService>>().ok_or_else(|| {
+        CustomError::InternalServerError("DPoP proof store is not configured".to_string())
+    })?;
+    dpop_service.record_use(&proof).await?;
+
+    Ok(Some(proof.jkt))
+}
+
+/// The key a token request proved possession of, if the client sent a DPoP
+/// proof. Pass it on with `RequestContext::with_dpop_key` to bind the
+/// issued tokens to it.
+pub struct DpopKey(pub Option<String>);
+
+impl FromRequest for DpopKey {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { Ok(DpopKey(check_request(&req, None).await?)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+    use serde_json::{json, Value};
+
+    const URL: &str = "http://localhost:5001/v1/users/me";
+    const PATH: &str = "/v1/users/me";
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn jwk(key: &SigningKey) -> Value {
+        let point = key.verifying_key().to_encoded_point(false);
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": BASE64URL_NOPAD.encode(point.x().unwrap()),
+            "y": BASE64URL_NOPAD.encode(point.y().unwrap()),
+        })
+    }
+
+    fn proof_header(key: &SigningKey) -> Value {
+        json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": jwk(key) })
+    }
+
+    // Claims for a GET of `URL` with a fresh nonce
+    fn fresh_claims() -> Value {
+        jwt::init_test_keys();
+        json!({
+            "jti": generate_jti(),
+            "htm": "GET",
+            "htu": URL,
+            "iat": Utc::now().timestamp(),
+            "nonce": issue_nonce().unwrap(),
+        })
+    }
+
+    fn generate_jti() -> String {
+        BASE64URL_NOPAD.encode(&rand::random::<[u8; 16]>())
+    }
+
+    fn sign(key: &SigningKey, header: &Value, claims: &Value) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            BASE64URL_NOPAD.encode(header.to_string().as_bytes()),
+            BASE64URL_NOPAD.encode(claims.to_string().as_bytes())
+        );
+        let signature: Signature = key.sign(signing_input.as_bytes());
+        format!(
+            "{}.{}",
+            signing_input,
+            BASE64URL_NOPAD.encode(&signature.to_bytes())
+        )
+    }
+
+    fn verify(proof: &str, access_token: Option<&str>) -> Result<VerifiedProof, CustomError> {
+        verify_proof(proof, "GET", PATH, access_token)
+    }
+
+    fn assert_rejected(result: Result<VerifiedProof, CustomError>, expected: &str) {
+        match result {
+            Err(CustomError::DpopProofError(reason, _)) => assert_eq!(reason, expected),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("accepted, expected: {}", expected),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_proof() {
+        let key = signing_key(1);
+        let claims = fresh_claims();
+        let proof = verify(&sign(&key, &proof_header(&key), &claims), None).unwrap();
+
+        assert_eq!(proof.jti, claims["jti"].as_str().unwrap());
+        assert_eq!(proof.expires_at, claims["iat"].as_i64().unwrap() + 60);
+    }
+
+    #[test]
+    fn thumbprint_identifies_the_key() {
+        let (first, second) = (signing_key(1), signing_key(2));
+        let jkt = |key: &SigningKey| {
+            verify(&sign(key, &proof_header(key), &fresh_claims()), None)
+                .unwrap()
+                .jkt
+        };
+
+        assert_eq!(jkt(&first), jkt(&first));
+        assert_ne!(jkt(&first), jkt(&second));
+        // RFC 7638: base64url SHA-256 over the required members in order
+        let expected = {
+            let jwk = jwk(&first);
+            digest(
+                format!(
+                    r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                    jwk["x"].as_str().unwrap(),
+                    jwk["y"].as_str().unwrap()
+                )
+                .as_bytes(),
+            )
+        };
+        assert_eq!(jkt(&first), expected);
+    }
+
+    #[test]
+    fn binds_the_proof_to_the_request() {
+        let key = signing_key(1);
+
+        let mut claims = fresh_claims();
+        claims["htm"] = json!("POST");
+        assert_rejected(
+            verify(&sign(&key, &proof_header(&key), &claims), None),
+            "htm does not match the request method",
+        );
+
+        let mut claims = fresh_claims();
+        claims["htu"] = json!("http://localhost:5001/v1/users/me/sessions");
+        assert_rejected(
+            verify(&sign(&key, &proof_header(&key), &claims), None),
+            "htu does not match the request URL",
+        );
+
+        let mut claims = fresh_claims();
+        claims["htu"] = json!(format!("{}?page=2#top", URL));
+        assert!(verify(&sign(&key, &proof_header(&key), &claims), None).is_ok());
+    }
+
+    #[test]
+    fn binds_the_proof_to_the_access_token() {
+        let key = signing_key(1);
+
+        let mut claims = fresh_claims();
+        claims["ath"] = json!(digest(b"access-token"));
+        let proof = sign(&key, &proof_header(&key), &claims);
+        assert!(verify(&proof, Some("access-token")).is_ok());
+        assert_rejected(
+            verify(&proof, Some("another-token")),
+            "ath does not match the access token",
+        );
+
+        let proof = sign(&key, &proof_header(&key), &fresh_claims());
+        assert_rejected(
+            verify(&proof, Some("access-token")),
+            "ath does not match the access token",
+        );
+    }
+
+    #[test]
+    fn rejects_a_stale_proof() {
+        let key = signing_key(1);
+        for offset in [-120, 120] {
+            let mut claims = fresh_claims();
+            claims["iat"] = json!(Utc::now().timestamp() + offset);
+            assert_rejected(
+                verify(&sign(&key, &proof_header(&key), &claims), None),
+                "proof is not fresh",
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_proof() {
+        let key = signing_key(1);
+        let proof = sign(&key, &proof_header(&key), &fresh_claims());
+
+        let mut parts: Vec<String> = proof.split('.').map(str::to_string).collect();
+        let mut claims: Value = serde_json::from_slice(&decode(&parts[1]).unwrap()).unwrap();
+        claims["htm"] = json!("DELETE");
+        parts[1] = BASE64URL_NOPAD.encode(claims.to_string().as_bytes());
+        assert_rejected(
+            verify_proof(&parts.join("."), "DELETE", PATH, None),
+            "signature does not verify",
+        );
+
+        // Signed by a different key than the one in the header
+        let other = signing_key(2);
+        assert_rejected(
+            verify(&sign(&other, &proof_header(&key), &fresh_claims()), None),
+            "signature does not verify",
+        );
+    }
+
+    #[test]
+    fn requires_a_server_issued_nonce() {
+        let key = signing_key(1);
+
+        let mut claims = fresh_claims();
+        claims.as_object_mut().unwrap().remove("nonce");
+        let nonce = match verify(&sign(&key, &proof_header(&key), &claims), None) {
+            Err(CustomError::DpopProofError(reason, Some(nonce))) => {
+                assert_eq!(reason, "a nonce is required");
+                nonce
+            }
+            _ => panic!("expected a nonce challenge"),
+        };
+
+        claims["nonce"] = json!(nonce);
+        assert!(verify(&sign(&key, &proof_header(&key), &claims), None).is_ok());
+
+        claims["nonce"] = json!("not-a-nonce");
+        assert_rejected(
+            verify(&sign(&key, &proof_header(&key), &claims), None),
+            "nonce is invalid or has expired",
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_headers() {
+        let key = signing_key(1);
+        let claims = fresh_claims();
+
+        let mut header = proof_header(&key);
+        header["jwk"]["d"] = json!(BASE64URL_NOPAD.encode(&key.to_bytes()));
+        assert_rejected(
+            verify(&sign(&key, &header, &claims), None),
+            "key must not contain private key material",
+        );
+
+        let mut header = proof_header(&key);
+        header["typ"] = json!("JWT");
+        assert_rejected(
+            verify(&sign(&key, &header, &claims), None),
+            "typ must be dpop+jwt",
+        );
+
+        let mut header = proof_header(&key);
+        header["alg"] = json!("HS256");
+        assert_rejected(
+            verify(&sign(&key, &header, &claims), None),
+            "unsupported algorithm",
+        );
+
+        assert_rejected(verify("not.a-proof", None), "malformed proof");
+    }
+}
@@ -0,0 +1,13 @@
+use crate::utils::error::CustomError;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static EMAIL_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap());
+
+pub fn validate_email(email: &str) -> Result<(), CustomError> {
+    if email.len() > 254 || !EMAIL_RE.is_match(email) {
+        return Err(CustomError::BadRequestError("Email address is not valid.".into()));
+    }
+
+    Ok(())
+}
@@ -1,7 +1,21 @@
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 use serde_json::json;
 use thiserror::Error;
 
+use crate::utils::dpop::DPOP_NONCE_HEADER;
+use crate::utils::password_validation::PasswordViolation;
+
+fn violation_codes(violations: &[PasswordViolation]) -> String {
+    violations
+        .iter()
+        .map(PasswordViolation::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Error)]
 pub enum CustomError {
@@ -25,6 +39,23 @@ pub enum CustomError {
     
     #[error("Validation Error: {0}")]
     ValidationError(String),
+
+    #[error("Forbidden: {0}")]
+    ForbiddenError(String),
+
+    // Carries the number of seconds the client should wait before retrying
+    #[error("Too Many Requests: {0}")]
+    TooManyRequestsError(String, u64),
+
+    #[error("Service Unavailable: {0}")]
+    ServiceUnavailableError(String),
+
+    #[error("Password Policy: {}", violation_codes(.0))]
+    PasswordPolicyError(Vec<PasswordViolation>),
+
+    // Carries a fresh nonce when the client has to retry with one
+    #[error("Invalid DPoP proof: {0}")]
+    DpopProofError(String, Option<String>),
 }
 
 impl ResponseError for CustomError {
@@ -37,11 +68,16 @@ impl ResponseError for CustomError {
             CustomError::UnauthenticatedError(..) => StatusCode::UNAUTHORIZED,
             CustomError::NotFoundError(..) => StatusCode::NOT_FOUND,
             CustomError::ValidationError(..) => StatusCode::BAD_REQUEST,
+            CustomError::ForbiddenError(..) => StatusCode::FORBIDDEN,
+            CustomError::TooManyRequestsError(..) => StatusCode::TOO_MANY_REQUESTS,
+            CustomError::ServiceUnavailableError(..) => StatusCode::SERVICE_UNAVAILABLE,
+            CustomError::PasswordPolicyError(..) => StatusCode::BAD_REQUEST,
+            CustomError::DpopProofError(..) => StatusCode::UNAUTHORIZED,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        let error_message = json!({
+        let mut error_message = json!({
             "success": false,
             "message": self.to_string(),
             "httpStatusCode": self.status_code().as_u16(),
@@ -53,11 +89,39 @@ impl ResponseError for CustomError {
                 CustomError::UnauthenticatedError(..) => "UNAUTHENTICATED_ERROR",
                 CustomError::NotFoundError(..) => "NOT_FOUND_ERROR",
                 CustomError::ValidationError(..) => "VALIDATION_ERROR",
+                CustomError::ForbiddenError(..) => "FORBIDDEN_ERROR",
+                CustomError::TooManyRequestsError(..) => "TOO_MANY_REQUESTS_ERROR",
+                CustomError::ServiceUnavailableError(..) => "SERVICE_UNAVAILABLE_ERROR",
+                CustomError::PasswordPolicyError(..) => "PASSWORD_POLICY_ERROR",
+                CustomError::DpopProofError(_, None) => "INVALID_DPOP_PROOF_ERROR",
+                CustomError::DpopProofError(_, Some(_)) => "USE_DPOP_NONCE_ERROR",
             },
             "service": std::env::var("SERVICE_NAME").unwrap_or_else(|_| "Unknown".to_string()),
         });
 
-        HttpResponse::build(self.status_code())
-            .json(error_message)
+        if let CustomError::PasswordPolicyError(violations) = self {
+            error_message["reasons"] = json!(violations);
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let CustomError::TooManyRequestsError(_, retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+        if let CustomError::DpopProofError(_, nonce) = self {
+            let error = if nonce.is_some() {
+                "use_dpop_nonce"
+            } else {
+                "invalid_dpop_proof"
+            };
+            response.insert_header((
+                header::WWW_AUTHENTICATE,
+                format!("DPoP algs=\"ES256\", error=\"{}\"", error),
+            ));
+            if let Some(nonce) = nonce {
+                response.insert_header((DPOP_NONCE_HEADER, nonce.as_str()));
+            }
+        }
+
+        response.json(error_message)
     }
 }
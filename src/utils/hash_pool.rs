@@ -0,0 +1,216 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::task::spawn_blocking;
+use once_cell::sync::Lazy;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::utils::error::CustomError;
+use crate::utils::hashing;
+
+/// Upper bounds of the queue time histogram, in seconds.
+const QUEUE_TIME_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Runs password hashing on the blocking thread pool so it never stalls an
+/// actix worker.
+///
+/// At most `HASH_MAX_CONCURRENCY` jobs (default: the number of CPUs) run at
+/// once and at most `HASH_MAX_QUEUE` (default 64) wait for a slot. Anything
+/// beyond that is rejected with a 503 instead of piling up.
+struct HashPool {
+    permits: Arc<Semaphore>,
+    max_queue: usize,
+    queued: AtomicUsize,
+    metrics: HashMetrics,
+}
+
+#[derive(Default)]
+struct HashMetrics {
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    in_flight: AtomicU64,
+    queue_time_count: AtomicU64,
+    queue_time_micros: AtomicU64,
+    queue_time_buckets: [AtomicU64; QUEUE_TIME_BUCKETS.len()],
+}
+
+static POOL: Lazy<HashPool> = Lazy::new(HashPool::from_env);
+
+impl HashPool {
+    fn from_env() -> Self {
+        let default_concurrency = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        let env_or = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        HashPool {
+            permits: Arc::new(Semaphore::new(
+                env_or("HASH_MAX_CONCURRENCY", default_concurrency).max(1),
+            )),
+            max_queue: env_or("HASH_MAX_QUEUE", 64),
+            queued: AtomicUsize::new(0),
+            metrics: HashMetrics::default(),
+        }
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, CustomError> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            self.metrics.observe_queue_time(Duration::ZERO);
+            return Ok(permit);
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queue {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(CustomError::ServiceUnavailableError(
+                "Server is busy, try again shortly".to_string(),
+            ));
+        }
+
+        // Leaves the queue even if the request is dropped while waiting
+        let _queued = QueueSlot(&self.queued);
+        let enqueued_at = Instant::now();
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| CustomError::InternalServerError(e.to_string()))?;
+        self.metrics.observe_queue_time(enqueued_at.elapsed());
+
+        Ok(permit)
+    }
+
+    async fn run<T, F>(&'static self, job: F) -> Result<T, CustomError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let permit = self.acquire().await?;
+
+        // Counted inside the job, which runs to completion even if the caller goes away
+        spawn_blocking(move || {
+            let _permit = permit;
+            self.metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+            let result = job();
+            self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+            self.metrics.completed.fetch_add(1, Ordering::Relaxed);
+            result
+        })
+        .await
+        .map_err(|e| CustomError::InternalServerError(e.to_string()))
+    }
+}
+
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl HashMetrics {
+    fn observe_queue_time(&self, elapsed: Duration) {
+        self.queue_time_count.fetch_add(1, Ordering::Relaxed);
+        self.queue_time_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+        let seconds = elapsed.as_secs_f64();
+        for (bound, count) in QUEUE_TIME_BUCKETS.iter().zip(&self.queue_time_buckets) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// `hashing::hash_password` on the bounded pool.
+pub async fn hash_password(password: &str) -> Result<String, CustomError> {
+    let password = password.to_string();
+    POOL.run(move || hashing::hash_password(&password))
+        .await?
+        .map_err(|e| CustomError::InternalServerError(e.to_string()))
+}
+
+/// `hashing::verify_password` on the bounded pool.
+pub async fn verify_password(password: &str, hashed_password: &str) -> Result<bool, CustomError> {
+    let password = password.to_string();
+    let hashed_password = hashed_password.to_string();
+    POOL.run(move || hashing::verify_password(&password, &hashed_password))
+        .await?
+        .map_err(|e| CustomError::InternalServerError(e.to_string()))
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Pool metrics in the Prometheus text exposition format.
+pub fn metrics_text() -> String {
+    let pool = &*POOL;
+    let metrics = &pool.metrics;
+    let mut out = String::new();
+
+    write_metric(
+        &mut out,
+        "password_hash_jobs_total",
+        "counter",
+        "Password hashing jobs run to completion.",
+        metrics.completed.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "password_hash_rejected_total",
+        "counter",
+        "Password hashing jobs shed because the queue was full.",
+        metrics.rejected.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "password_hash_in_flight",
+        "gauge",
+        "Password hashing jobs currently running.",
+        metrics.in_flight.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "password_hash_queued",
+        "gauge",
+        "Password hashing jobs waiting for a slot.",
+        pool.queued.load(Ordering::SeqCst) as u64,
+    );
+
+    let name = "password_hash_queue_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time password hashing jobs waited for a slot.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (bound, bucket) in QUEUE_TIME_BUCKETS.iter().zip(&metrics.queue_time_buckets) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            bound,
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = metrics.queue_time_count.load(Ordering::Relaxed);
+    let sum = metrics.queue_time_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+
+    out
+}
@@ -1,9 +1,249 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use once_cell::sync::OnceCell;
+use thiserror::Error;
 
-pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
-    hash(password, DEFAULT_COST)
+#[derive(Debug, Error)]
+pub enum HashError {
+    #[error("bcrypt: {0}")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+
+    #[error("argon2: {0}")]
+    Argon2(String),
+
+    #[error("Unrecognized password hash format")]
+    UnknownFormat,
+}
+
+/// One password hashing scheme. Stored hashes are self-describing, so the
+/// scheme that produced a hash can be recognized from the hash alone.
+pub trait Hasher: Send + Sync {
+    fn hash(&self, password: &str) -> Result<String, HashError>;
+    fn verify(&self, password: &str, hashed_password: &str) -> Result<bool, HashError>;
+    fn recognizes(&self, hashed_password: &str) -> bool;
+    /// Whether a hash this scheme recognizes was made with outdated parameters.
+    fn needs_rehash(&self, hashed_password: &str) -> bool;
+}
+
+/// Argon2id in PHC string format, the scheme used for all new hashes.
+///
+/// Parameters come from `ARGON2_MEMORY_KIB` (default 19456),
+/// `ARGON2_ITERATIONS` (default 2) and `ARGON2_PARALLELISM` (default 1).
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    fn from_env() -> Result<Self, String> {
+        fn env_or(name: &str, default: u32) -> Result<u32, String> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value)),
+                Err(_) => Ok(default),
+            }
+        }
+
+        let params = Params::new(
+            env_or("ARGON2_MEMORY_KIB", 19_456)?,
+            env_or("ARGON2_ITERATIONS", 2)?,
+            env_or("ARGON2_PARALLELISM", 1)?,
+            None,
+        )
+        .map_err(|e| format!("Invalid Argon2 parameters: {}", e))?;
+
+        Ok(Argon2idHasher { params })
+    }
+
+    fn argon2(&self) -> Argon2<'_> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl Hasher for Argon2idHasher {
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+            .map_err(|e| HashError::Argon2(e.to_string()))?;
+
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| HashError::Argon2(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hashed_password: &str) -> Result<bool, HashError> {
+        let parsed =
+            PasswordHash::new(hashed_password).map_err(|e| HashError::Argon2(e.to_string()))?;
+
+        // Verification uses the parameters recorded in the hash itself
+        match self.argon2().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(HashError::Argon2(e.to_string())),
+        }
+    }
+
+    fn recognizes(&self, hashed_password: &str) -> bool {
+        hashed_password.starts_with("$argon2")
+    }
+
+    fn needs_rehash(&self, hashed_password: &str) -> bool {
+        let parsed = match PasswordHash::new(hashed_password) {
+            Ok(parsed) => parsed,
+            Err(_) => return true,
+        };
+        if parsed.algorithm != Algorithm::Argon2id.ident()
+            || parsed.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
 }
 
-pub fn verify_password(password: &str, hashed_password: &str) -> Result<bool, bcrypt::BcryptError> {
-    verify(password, hashed_password)
-}
\ No newline at end of file
+/// Legacy bcrypt hashes. Still verified, never produced.
+pub struct BcryptHasher;
+
+impl Hasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?)
+    }
+
+    fn verify(&self, password: &str, hashed_password: &str) -> Result<bool, HashError> {
+        Ok(bcrypt::verify(password, hashed_password)?)
+    }
+
+    fn recognizes(&self, hashed_password: &str) -> bool {
+        hashed_password.starts_with("$2")
+    }
+
+    fn needs_rehash(&self, _hashed_password: &str) -> bool {
+        true
+    }
+}
+
+static CURRENT: OnceCell<Argon2idHasher> = OnceCell::new();
+static LEGACY: BcryptHasher = BcryptHasher;
+
+/// Loads the Argon2 parameters. Call once from `main` before serving requests.
+pub fn init_hasher() -> Result<(), String> {
+    let hasher = Argon2idHasher::from_env()?;
+    CURRENT
+        .set(hasher)
+        .map_err(|_| "Password hasher is already initialized".to_string())
+}
+
+fn current() -> Result<&'static Argon2idHasher, HashError> {
+    CURRENT
+        .get()
+        .ok_or_else(|| HashError::Argon2("Password hasher is not initialized".to_string()))
+}
+
+fn hasher_for(hashed_password: &str) -> Result<&'static dyn Hasher, HashError> {
+    let hashers: [&'static dyn Hasher; 2] = [current()?, &LEGACY];
+    hashers
+        .into_iter()
+        .find(|hasher| hasher.recognizes(hashed_password))
+        .ok_or(HashError::UnknownFormat)
+}
+
+/// Hashes with the current scheme and parameters.
+pub fn hash_password(password: &str) -> Result<String, HashError> {
+    current()?.hash(password)
+}
+
+/// Verifies against whichever scheme produced `hashed_password`.
+pub fn verify_password(password: &str, hashed_password: &str) -> Result<bool, HashError> {
+    hasher_for(hashed_password)?.verify(password, hashed_password)
+}
+
+/// Whether a verified hash should be replaced with a fresh `hash_password`.
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    match hasher_for(hashed_password) {
+        Ok(hasher) => hasher.needs_rehash(hashed_password),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse battery staple";
+    // bcrypt of `PASSWORD` at cost 4, as stored before Argon2 was introduced
+    const BCRYPT_HASH: &str = "$2b$04$jCxUW.VxQog0LWlzTpp11uAhfgqe9I9yjj.kexuPdI58kKmnUnjYy";
+
+    fn init() {
+        CURRENT.get_or_init(|| Argon2idHasher::from_env().unwrap());
+    }
+
+    fn argon2_with(m_cost: u32, t_cost: u32) -> Argon2idHasher {
+        Argon2idHasher {
+            params: Params::new(m_cost, t_cost, 1, None).unwrap(),
+        }
+    }
+
+    #[test]
+    fn hashes_with_argon2id_and_verifies() {
+        init();
+        let hash = hash_password(PASSWORD).unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$"), "{}", hash);
+        assert!(verify_password(PASSWORD, &hash).unwrap());
+        assert!(!verify_password("correct horse battery stapler", &hash).unwrap());
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        init();
+        assert_ne!(
+            hash_password(PASSWORD).unwrap(),
+            hash_password(PASSWORD).unwrap()
+        );
+    }
+
+    #[test]
+    fn verifies_legacy_bcrypt_hashes() {
+        init();
+        assert!(verify_password(PASSWORD, BCRYPT_HASH).unwrap());
+        assert!(!verify_password("wrong", BCRYPT_HASH).unwrap());
+
+        // Older libraries wrote the same hash with a `$2y$` prefix
+        let bcrypt_2y = BCRYPT_HASH.replacen("$2b$", "$2y$", 1);
+        assert!(verify_password(PASSWORD, &bcrypt_2y).unwrap());
+    }
+
+    #[test]
+    fn rehashes_everything_but_current_argon2id() {
+        init();
+        assert!(needs_rehash(BCRYPT_HASH));
+        assert!(!needs_rehash(&hash_password(PASSWORD).unwrap()));
+
+        let outdated = argon2_with(8 * 1024, 1).hash(PASSWORD).unwrap();
+        assert!(verify_password(PASSWORD, &outdated).unwrap());
+        assert!(needs_rehash(&outdated));
+    }
+
+    #[test]
+    fn rejects_unknown_hash_formats() {
+        init();
+        assert!(matches!(
+            verify_password(PASSWORD, "plaintext"),
+            Err(HashError::UnknownFormat)
+        ));
+        assert!(!needs_rehash("plaintext"));
+    }
+
+    #[test]
+    fn malformed_argon2_hashes_need_rehash() {
+        assert!(argon2_with(19_456, 2).needs_rehash("$argon2id$garbage"));
+    }
+}
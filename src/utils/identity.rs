@@ -0,0 +1,95 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::utils::error::CustomError;
+
+/// Canonical form of a username or email, used for lookups and uniqueness:
+/// NFKC normalized, lowercased and trimmed. Lowercasing stands in for full
+/// case folding, which only differs for a handful of characters such as `ß`.
+pub fn normalize(identifier: &str) -> String {
+    let lowered = identifier.nfkc().collect::<String>().to_lowercase();
+    // Lowercasing can produce sequences NFKC would compose differently
+    lowered.nfkc().collect::<String>().trim().to_string()
+}
+
+/// The identifier as it is displayed: NFKC normalized and trimmed, with its
+/// case preserved.
+pub fn clean(identifier: &str) -> String {
+    identifier.nfkc().collect::<String>().trim().to_string()
+}
+
+/// Logins decide between username and email by the `@`, so usernames may
+/// not contain one.
+pub fn is_email(identifier: &str) -> bool {
+    identifier.contains('@')
+}
+
+/// The normalized fields a login identifier is looked up by, in order.
+/// Emails fall back to usernames: accounts created before `@` was rejected
+/// may still have one in their username.
+pub fn lookup_fields(identifier: &str) -> &'static [&'static str] {
+    if is_email(identifier) {
+        &["email_normalized", "username_normalized"]
+    } else {
+        &["username_normalized"]
+    }
+}
+
+pub fn validate_username(username: &str) -> Result<(), CustomError> {
+    if username.is_empty() {
+        return Err(CustomError::ValidationError(
+            "Username cannot be empty".to_string(),
+        ));
+    }
+    if is_email(username) || username.chars().any(char::is_control) {
+        return Err(CustomError::ValidationError(
+            "Username cannot contain '@' or control characters".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_ignores_case_width_and_padding() {
+        assert_eq!(normalize("  Alice "), "alice");
+        assert_eq!(normalize("Ａｌｉｃｅ"), "alice");
+        assert_eq!(normalize("Alice@Example.COM"), "alice@example.com");
+        // Precomposed and combining forms of the same letter
+        assert_eq!(normalize("Ze\u{0301}"), normalize("Z\u{00e9}"));
+    }
+
+    #[test]
+    fn normalize_lowercases_but_does_not_fold() {
+        assert_eq!(normalize("STRASSE"), "strasse");
+        assert_eq!(normalize("Straße"), "straße");
+        assert_ne!(normalize("Straße"), normalize("STRASSE"));
+    }
+
+    #[test]
+    fn clean_keeps_case() {
+        assert_eq!(clean("  Ａｌｉｃｅ\t"), "Alice");
+    }
+
+    #[test]
+    fn validates_usernames() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("alice@example.com").is_err());
+        assert!(validate_username("al\u{0007}ice").is_err());
+    }
+
+    #[test]
+    fn emails_fall_back_to_legacy_usernames() {
+        assert!(is_email("alice@example.com"));
+        assert!(!is_email("alice"));
+        assert_eq!(lookup_fields("alice"), ["username_normalized"]);
+        assert_eq!(
+            lookup_fields("alice@example.com"),
+            ["email_normalized", "username_normalized"]
+        );
+    }
+}
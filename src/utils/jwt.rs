@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::utils::error::CustomError;
+
+/// Signing and verification keys, loaded once at startup.
+///
+/// Configuration:
+/// - `JWT_ALGORITHM`: `HS256` (default), `RS256` or `EdDSA`
+/// - `JWT_SECRET`: shared secret for HS256. When set alongside an asymmetric
+///   algorithm it is only used to verify legacy tokens that carry no `kid`.
+/// - `JWT_PRIVATE_KEY_PATH`: PEM private key used to sign new tokens
+/// - `JWT_KEY_ID`: `kid` of the signing key
+/// - `JWT_JWKS_PATH`: JSON Web Key Set with every public key still accepted
+///   for verification. Keep retired keys here until their tokens expire.
+pub struct KeyStore {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verification_keys: HashMap<String, (Algorithm, DecodingKey)>,
+    legacy_key: Option<DecodingKey>,
+    jwks: JwkSet,
+}
+
+static KEY_STORE: OnceCell<KeyStore> = OnceCell::new();
+
+/// Loads the key configuration. Call once from `main` before serving requests.
+pub fn init_keys() -> Result<(), String> {
+    let store = KeyStore::from_env()?;
+    KEY_STORE
+        .set(store)
+        .map_err(|_| "JWT keys are already initialized".to_string())
+}
+
+fn keys() -> Result<&'static KeyStore, CustomError> {
+    KEY_STORE
+        .get()
+        .ok_or_else(|| CustomError::InternalServerError("JWT keys are not initialized".to_string()))
+}
+
+impl KeyStore {
+    fn from_env() -> Result<Self, String> {
+        let secret = env::var("JWT_SECRET").ok();
+        let algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+
+        match algorithm.as_str() {
+            "HS256" => {
+                let secret = secret.ok_or_else(|| "JWT_SECRET must be set".to_string())?;
+                Ok(KeyStore {
+                    kid: None,
+                    algorithm: Algorithm::HS256,
+                    encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+                    verification_keys: HashMap::new(),
+                    legacy_key: Some(DecodingKey::from_secret(secret.as_bytes())),
+                    jwks: JwkSet { keys: Vec::new() },
+                })
+            }
+            "RS256" | "EdDSA" => {
+                let kid = env::var("JWT_KEY_ID").map_err(|_| "JWT_KEY_ID must be set".to_string())?;
+                let private_key_path = env::var("JWT_PRIVATE_KEY_PATH")
+                    .map_err(|_| "JWT_PRIVATE_KEY_PATH must be set".to_string())?;
+                let jwks_path =
+                    env::var("JWT_JWKS_PATH").map_err(|_| "JWT_JWKS_PATH must be set".to_string())?;
+
+                let pem = fs::read(&private_key_path)
+                    .map_err(|e| format!("Failed to read {}: {}", private_key_path, e))?;
+                let (algorithm, encoding_key) = if algorithm == "RS256" {
+                    (Algorithm::RS256, EncodingKey::from_rsa_pem(&pem))
+                } else {
+                    (Algorithm::EdDSA, EncodingKey::from_ed_pem(&pem))
+                };
+                let encoding_key = encoding_key.map_err(|e| format!("Invalid private key: {}", e))?;
+
+                let jwks_json = fs::read_to_string(&jwks_path)
+                    .map_err(|e| format!("Failed to read {}: {}", jwks_path, e))?;
+                let jwks: JwkSet = serde_json::from_str(&jwks_json)
+                    .map_err(|e| format!("Invalid JWKS in {}: {}", jwks_path, e))?;
+
+                let mut verification_keys = HashMap::new();
+                for jwk in &jwks.keys {
+                    let key_id = jwk
+                        .common
+                        .key_id
+                        .clone()
+                        .ok_or_else(|| "Every key in the JWKS needs a kid".to_string())?;
+                    let key_algorithm = jwk_algorithm(jwk)?;
+                    let decoding_key = DecodingKey::from_jwk(jwk)
+                        .map_err(|e| format!("Invalid key {}: {}", key_id, e))?;
+                    verification_keys.insert(key_id, (key_algorithm, decoding_key));
+                }
+
+                if !verification_keys.contains_key(&kid) {
+                    return Err(format!("Signing key {} is missing from the JWKS", kid));
+                }
+
+                Ok(KeyStore {
+                    kid: Some(kid),
+                    algorithm,
+                    encoding_key,
+                    verification_keys,
+                    legacy_key: secret.map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+                    jwks,
+                })
+            }
+            other => Err(format!("Unsupported JWT_ALGORITHM: {}", other)),
+        }
+    }
+}
+
+fn jwk_algorithm(jwk: &Jwk) -> Result<Algorithm, String> {
+    if let Some(algorithm) = jwk.common.algorithm {
+        return Ok(algorithm);
+    }
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Ok(Algorithm::RS256),
+        AlgorithmParameters::OctetKeyPair(_) => Ok(Algorithm::EdDSA),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Ok(Algorithm::ES256),
+            EllipticCurve::P384 => Ok(Algorithm::ES384),
+            _ => Err("Unsupported elliptic curve in JWKS".to_string()),
+        },
+        AlgorithmParameters::OctetKey(_) => Err("Symmetric keys cannot be published".to_string()),
+    }
+}
+
+/// Signs `claims` with the active key, tagging the token with its `kid`.
+pub fn encode_token<T: Serialize>(claims: &T) -> Result<String, CustomError> {
+    let keys = keys()?;
+
+    let mut header = Header::new(keys.algorithm);
+    header.kid = keys.kid.clone();
+
+    encode(&header, claims, &keys.encoding_key)
+        .map_err(|_| CustomError::InternalServerError("Token generation failed".to_string()))
+}
+
+/// Verifies a token against the key named by its `kid` header.
+pub fn decode_token<T: DeserializeOwned>(token: &str) -> Result<T, CustomError> {
+    decode_with(token, |_| {})
+}
+
+/// Verifies a signed record that has no expiry, such as an audit checkpoint.
+/// It stays verifiable for as long as its key is in the JWKS.
+pub fn decode_record<T: DeserializeOwned>(token: &str) -> Result<T, CustomError> {
+    decode_with(token, |validation| {
+        validation.validate_exp = false;
+        validation.required_spec_claims.clear();
+    })
+}
+
+fn decode_with<T: DeserializeOwned>(
+    token: &str,
+    configure: impl FnOnce(&mut Validation),
+) -> Result<T, CustomError> {
+    let keys = keys()?;
+    let invalid = || CustomError::UnauthorizedError("Invalid token".to_string());
+
+    let header = decode_header(token).map_err(|_| invalid())?;
+
+    // The algorithm always comes from our key configuration, never the token
+    let (algorithm, key) = match &header.kid {
+        Some(kid) => {
+            let (algorithm, key) = keys.verification_keys.get(kid).ok_or_else(invalid)?;
+            (*algorithm, key)
+        }
+        None => (Algorithm::HS256, keys.legacy_key.as_ref().ok_or_else(invalid)?),
+    };
+
+    let mut validation = Validation::new(algorithm);
+    configure(&mut validation);
+
+    decode::<T>(token, key, &validation)
+        .map(|token_data| token_data.claims)
+        .map_err(|_| invalid())
+}
+
+/// Public verification keys, as published at `/.well-known/jwks.json`.
+pub fn jwks() -> Result<&'static JwkSet, CustomError> {
+    keys().map(|keys| &keys.jwks)
+}
+
+/// Installs an HS256 key for tests that sign or verify tokens.
+#[cfg(test)]
+pub fn init_test_keys() {
+    KEY_STORE.get_or_init(|| KeyStore {
+        kid: None,
+        algorithm: Algorithm::HS256,
+        encoding_key: EncodingKey::from_secret(b"test secret"),
+        verification_keys: HashMap::new(),
+        legacy_key: Some(DecodingKey::from_secret(b"test secret")),
+        jwks: JwkSet { keys: Vec::new() },
+    });
+}
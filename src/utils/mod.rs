@@ -1,4 +1,14 @@
 pub mod hashing;
 pub mod password_validation;
 pub mod error;
-pub mod model;
\ No newline at end of file
+pub mod model;
+pub mod token;
+pub mod jwt;
+pub mod email_validation;
+pub mod totp;
+pub mod request;
+pub mod hash_pool;
+pub mod session_cookie;
+pub mod identity;
+pub mod webauthn;
+pub mod dpop;
\ No newline at end of file
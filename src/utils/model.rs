@@ -2,6 +2,75 @@ use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct LoginRequests {
-    pub username: String,
+    // Username or email address
+    #[serde(alias = "username", alias = "email")]
+    pub identifier: String,
     pub password: String,
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    // Browser clients send it in the refresh cookie instead
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkLoginRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreAccountRequest {
+    // Username or email address
+    #[serde(alias = "username", alias = "email")]
+    pub identifier: String,
+    pub password: String,
+}
@@ -1,51 +1,336 @@
-use regex::Regex;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
 use crate::utils::error::CustomError;
 
-// pub fn validate_password(password: &str) -> Result<(), CustomError> {
-//     // Regex pattern to check for length and character requirements
-//     let re = Regex::new(r"^[a-zA-Z\d]{8,20}$").unwrap();
+/// Why a password was rejected. Serialized as the machine-readable reason
+/// codes returned to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordViolation {
+    TooShort,
+    TooLong,
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    TooWeak,
+    Breached,
+    ContainsUsername,
+    ContainsEmail,
+}
+
+// The reason code, exactly as serialized for clients
+impl fmt::Display for PasswordViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(code)) => f.write_str(&code),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Password rules, loaded once at startup.
+///
+/// Configuration:
+/// - `PASSWORD_MIN_LENGTH` / `PASSWORD_MAX_LENGTH`: bounds in characters
+///   (default 8 and 128)
+/// - `PASSWORD_REQUIRE_LOWERCASE`, `PASSWORD_REQUIRE_UPPERCASE`,
+///   `PASSWORD_REQUIRE_DIGIT` (default `true`) and `PASSWORD_REQUIRE_SYMBOL`
+///   (default `false`)
+/// - `PASSWORD_MIN_STRENGTH`: minimum `strength_score`, 0 to 4 (default 2)
+/// - `PASSWORD_BREACHED_LIST_PATH`: file with one known-breached password per
+///   line, compared case-insensitively
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    require_lowercase: bool,
+    require_uppercase: bool,
+    require_digit: bool,
+    require_symbol: bool,
+    min_strength: u8,
+    breached: HashSet<String>,
+}
+
+static POLICY: OnceCell<PasswordPolicy> = OnceCell::new();
+
+/// Loads the password policy. Call once from `main` before serving requests.
+pub fn init_policy() -> Result<(), String> {
+    let policy = PasswordPolicy::from_env()?;
+    POLICY
+        .set(policy)
+        .map_err(|_| "Password policy is already initialized".to_string())
+}
+
+fn policy() -> Result<&'static PasswordPolicy, CustomError> {
+    POLICY.get().ok_or_else(|| {
+        CustomError::InternalServerError("Password policy is not initialized".to_string())
+    })
+}
+
+impl PasswordPolicy {
+    fn from_env() -> Result<Self, String> {
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value)),
+                Err(_) => Ok(default),
+            }
+        }
+
+        let breached = match std::env::var("PASSWORD_BREACHED_LIST_PATH") {
+            Ok(path) => fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            Err(_) => HashSet::new(),
+        };
 
-//     // Check if password length and character requirements are met
-//     if !re.is_match(password) {
-//         return Err(CustomError::BadRequestError("Password must be between 8 and 20 characters long and include at least one letter and one number.".into()));
-//     }
+        let policy = PasswordPolicy {
+            min_length: env_or("PASSWORD_MIN_LENGTH", 8)?,
+            max_length: env_or("PASSWORD_MAX_LENGTH", 128)?,
+            require_lowercase: env_or("PASSWORD_REQUIRE_LOWERCASE", true)?,
+            require_uppercase: env_or("PASSWORD_REQUIRE_UPPERCASE", true)?,
+            require_digit: env_or("PASSWORD_REQUIRE_DIGIT", true)?,
+            require_symbol: env_or("PASSWORD_REQUIRE_SYMBOL", false)?,
+            min_strength: env_or("PASSWORD_MIN_STRENGTH", 2)?,
+            breached,
+        };
 
-//     // Additional checks for uppercase, lowercase, and digits
-//     if !password.chars().any(|c| c.is_lowercase()) {
-//         return Err(CustomError::BadRequestError("Password must contain at least one lowercase letter.".into()));
-//     }
-//     if !password.chars().any(|c| c.is_uppercase()) {
-//         return Err(CustomError::BadRequestError("Password must contain at least one uppercase letter.".into()));
-//     }
-//     if !password.chars().any(|c| c.is_digit(10)) {
-//         return Err(CustomError::BadRequestError("Password must contain at least one number.".into()));
-//     }
+        if policy.min_length > policy.max_length {
+            return Err("PASSWORD_MIN_LENGTH is greater than PASSWORD_MAX_LENGTH".to_string());
+        }
 
-//     Ok(())
-// }
+        Ok(policy)
+    }
+
+    /// Every rule `password` breaks, in a stable order.
+    pub fn violations(
+        &self,
+        password: &str,
+        username: &str,
+        email: &str,
+    ) -> Vec<PasswordViolation> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            violations.push(PasswordViolation::TooShort);
+        }
+        if length > self.max_length {
+            violations.push(PasswordViolation::TooLong);
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            violations.push(PasswordViolation::MissingLowercase);
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            violations.push(PasswordViolation::MissingUppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push(PasswordViolation::MissingDigit);
+        }
+        if self.require_symbol && !password.chars().any(is_symbol) {
+            violations.push(PasswordViolation::MissingSymbol);
+        }
+        if strength_score(password) < self.min_strength {
+            violations.push(PasswordViolation::TooWeak);
+        }
+
+        let lowered = password.to_lowercase();
+        if self.breached.contains(&lowered) {
+            violations.push(PasswordViolation::Breached);
+        }
+        if contains_identifier(&lowered, username) {
+            violations.push(PasswordViolation::ContainsUsername);
+        }
+        let local_part = email.split('@').next().unwrap_or_default();
+        if contains_identifier(&lowered, email) || contains_identifier(&lowered, local_part) {
+            violations.push(PasswordViolation::ContainsEmail);
+        }
+
+        violations
+    }
+}
+
+fn is_symbol(c: char) -> bool {
+    !c.is_alphanumeric() && !c.is_whitespace()
+}
+
+// Identifiers too short to be meaningful would reject unrelated passwords
+fn contains_identifier(lowered_password: &str, identifier: &str) -> bool {
+    let identifier = identifier.trim().to_lowercase();
+    identifier.chars().count() >= 3 && lowered_password.contains(&identifier)
+}
+
+/// Rough strength estimate from 0 (trivial) to 4 (strong).
+///
+/// Each character adds the entropy of the character classes in use, except
+/// repeats and steps of a run like `abc` or `321`, which add a single bit.
+pub fn strength_score(password: &str) -> u8 {
+    let mut pool = 0u32;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| c.is_ascii() && is_symbol(c)) {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+    if pool == 0 {
+        return 0;
+    }
 
-pub fn validate_password(password: &str) -> Result<(), CustomError> {
-    // Check password length
-    if password.len() < 8 || password.len() > 20 {
-        return Err(CustomError::BadRequestError("Password must be between 8 and 20 characters long.".into()));
+    let bits_per_char = f64::from(pool).log2();
+    let mut bits = 0.0;
+    let mut previous: Option<char> = None;
+    for c in password.chars() {
+        let predictable = previous.is_some_and(|p| (c as i64 - p as i64).abs() <= 1);
+        bits += if predictable { 1.0 } else { bits_per_char };
+        previous = Some(c);
     }
 
-    // Check for at least one lowercase letter, one uppercase letter, and one digit
-    let has_lowercase = password.chars().any(|c| c.is_lowercase());
-    let has_uppercase = password.chars().any(|c| c.is_uppercase());
-    let has_digit = password.chars().any(|c| c.is_digit(10));
+    match bits {
+        b if b < 28.0 => 0,
+        b if b < 36.0 => 1,
+        b if b < 60.0 => 2,
+        b if b < 80.0 => 3,
+        _ => 4,
+    }
+}
 
-    if !has_lowercase || !has_uppercase || !has_digit {
-        return Err(CustomError::BadRequestError("Password must include at least one uppercase letter, one lowercase letter, and one number.".into()));
+/// Checks `password` against the configured policy, reporting every
+/// violation at once.
+pub fn validate_password(password: &str, username: &str, email: &str) -> Result<(), CustomError> {
+    let violations = policy()?.violations(password, username, email);
+    if !violations.is_empty() {
+        return Err(CustomError::PasswordPolicyError(violations));
     }
 
     Ok(())
 }
 
-// pub fn validate_password(password: &str) -> Result<(), String> {
-//     let re = Regex::new(r"^(?=.*\d)(?=.*[a-z])(?=.*[A-Z]).{8,20}$").unwrap();
-//     if !re.is_match(password) {
-//         return Err(CustomError::BadRequestError("Password must contain a capital letter, number, special character & greater than 8 digits.".into()).to_string());
-//     }
-//     Ok(())
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 64,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: false,
+            min_strength: 2,
+            breached: ["Password123!".to_lowercase()].into_iter().collect(),
+        }
+    }
+
+    fn violations(password: &str) -> Vec<PasswordViolation> {
+        policy().violations(password, "alice", "alice.smith@example.com")
+    }
+
+    #[test]
+    fn accepts_a_strong_password() {
+        assert_eq!(violations("Gx7#mQ2vLp9z"), Vec::new());
+    }
+
+    #[test]
+    fn reports_every_violation_in_order() {
+        assert_eq!(
+            violations("aaa"),
+            vec![
+                PasswordViolation::TooShort,
+                PasswordViolation::MissingUppercase,
+                PasswordViolation::MissingDigit,
+                PasswordViolation::TooWeak,
+            ]
+        );
+    }
+
+    #[test]
+    fn enforces_length_bounds_in_characters() {
+        // Eight characters, but more than eight bytes
+        assert!(!violations("Gx7#mQ2é").contains(&PasswordViolation::TooShort));
+        assert!(violations(&"Gx7#mQ2v".repeat(9)).contains(&PasswordViolation::TooLong));
+    }
+
+    #[test]
+    fn symbols_are_only_required_when_configured() {
+        let mut policy = policy();
+        assert!(policy.violations("Gx7mQ2vLp9zK", "", "").is_empty());
+
+        policy.require_symbol = true;
+        assert_eq!(
+            policy.violations("Gx7mQ2vLp9zK", "", ""),
+            vec![PasswordViolation::MissingSymbol]
+        );
+    }
+
+    #[test]
+    fn rejects_breached_passwords_case_insensitively() {
+        assert!(violations("PASSWORD123!").contains(&PasswordViolation::Breached));
+    }
+
+    #[test]
+    fn rejects_passwords_containing_the_username_or_email() {
+        assert!(violations("Gx7#ALICEq2v").contains(&PasswordViolation::ContainsUsername));
+        assert!(violations("Gx7#alice.smithQ").contains(&PasswordViolation::ContainsEmail));
+    }
+
+    #[test]
+    fn ignores_identifiers_shorter_than_three_characters() {
+        let violations = policy().violations("Gx7#mQ2vLp9z", "Gx", "mQ@example.com");
+        assert!(violations.is_empty());
+    }
+
+    #[test]
+    fn strength_score_discounts_runs_and_repeats() {
+        assert_eq!(strength_score(""), 0);
+        assert_eq!(strength_score("aaaaaaaaaaaa"), 0);
+        assert_eq!(strength_score("abcdefghijkl"), 0);
+        assert!(strength_score("Gx7#mQ2vLp9z") >= 3);
+        assert!(strength_score("Gx7#mQ2vLp9z") > strength_score("Abcdefgh1234"));
+    }
+
+    #[test]
+    fn violation_codes_match_their_serialized_form() {
+        let all = [
+            PasswordViolation::TooShort,
+            PasswordViolation::TooLong,
+            PasswordViolation::MissingLowercase,
+            PasswordViolation::MissingUppercase,
+            PasswordViolation::MissingDigit,
+            PasswordViolation::MissingSymbol,
+            PasswordViolation::TooWeak,
+            PasswordViolation::Breached,
+            PasswordViolation::ContainsUsername,
+            PasswordViolation::ContainsEmail,
+        ];
+
+        for violation in all {
+            assert_eq!(
+                serde_json::to_value(violation).unwrap(),
+                serde_json::Value::String(violation.to_string())
+            );
+        }
+        assert_eq!(
+            PasswordViolation::ContainsEmail.to_string(),
+            "contains_email"
+        );
+    }
+}
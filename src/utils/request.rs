@@ -0,0 +1,121 @@
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures_util::future::{ready, Ready};
+use mongodb::bson::oid::ObjectId;
+
+/// The caller's IP address. Forwarding headers are only honoured when
+/// `TRUST_PROXY_HEADERS=true`, since clients can set them to anything.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let trust_proxy = std::env::var("TRUST_PROXY_HEADERS")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+
+    let ip = if trust_proxy {
+        req.connection_info().realip_remote_addr().map(str::to_string)
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+
+    ip.unwrap_or_else(|| "unknown".to_string())
+}
+
+/// A readable device description such as "Firefox on Linux", derived from
+/// a User-Agent header. Only the common browsers and platforms are told
+/// apart; anything else is reported as unknown.
+pub fn device_name(user_agent: Option<&str>) -> String {
+    let user_agent = match user_agent {
+        Some(user_agent) if !user_agent.trim().is_empty() => user_agent,
+        _ => return "Unknown device".to_string(),
+    };
+
+    // Order matters: Edge and Opera also claim Chrome, Chrome also claims Safari
+    let client = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("CriOS/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+    ]
+    .iter()
+    .find(|(marker, _)| user_agent.contains(marker))
+    .map(|(_, name)| *name)
+    .unwrap_or("Unknown client");
+
+    let platform = [
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Android", "Android"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ]
+    .iter()
+    .find(|(marker, _)| user_agent.contains(marker))
+    .map(|(_, name)| *name);
+
+    match platform {
+        Some(platform) => format!("{} on {}", client, platform),
+        None => client.to_string(),
+    }
+}
+
+/// Who is making a request and from where, as recorded in the audit log.
+///
+/// Extract it in a handler and attach the authenticated caller with
+/// `with_actor` before passing it to a service.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub ip: String,
+    pub user_agent: Option<String>,
+    pub actor_id: Option<ObjectId>,
+    // Thumbprint of the DPoP key that tokens issued for this request are bound to
+    pub dpop_jkt: Option<String>,
+}
+
+impl RequestContext {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        RequestContext {
+            ip: client_ip(req),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            actor_id: None,
+            dpop_jkt: None,
+        }
+    }
+
+    /// Context for work the server does on its own, such as scheduled purges.
+    pub fn system() -> Self {
+        RequestContext {
+            ip: "internal".to_string(),
+            user_agent: None,
+            actor_id: None,
+            dpop_jkt: None,
+        }
+    }
+
+    pub fn with_actor(mut self, actor_id: ObjectId) -> Self {
+        self.actor_id = Some(actor_id);
+        self
+    }
+
+    pub fn with_dpop_key(mut self, jkt: Option<String>) -> Self {
+        self.dpop_jkt = jkt;
+        self
+    }
+}
+
+impl FromRequest for RequestContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(RequestContext::from_request(req)))
+    }
+}
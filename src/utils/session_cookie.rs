@@ -0,0 +1,152 @@
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::{HttpRequest, HttpResponseBuilder};
+
+use crate::service::token_service::refresh_token_ttl;
+use crate::service::user_service::LoginResponse;
+use crate::utils::error::CustomError;
+use crate::utils::token;
+
+/// Carries the access token for browser clients.
+pub const SESSION_COOKIE: &str = "session";
+pub const REFRESH_COOKIE: &str = "refresh_session";
+/// Readable by scripts, so the front end can echo it in `CSRF_HEADER`.
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// The refresh token only has to reach the refresh and logout endpoints
+const REFRESH_COOKIE_PATH: &str = "/v1/users";
+
+/// Cookie attributes.
+///
+/// Configuration:
+/// - `SESSION_COOKIE_SECURE`: set to `false` to allow plain HTTP during local
+///   development (default `true`)
+/// - `SESSION_COOKIE_SAMESITE`: `Strict` (default), `Lax` or `None`
+/// - `SESSION_COOKIE_DOMAIN`: optional `Domain` attribute
+struct CookiePolicy {
+    secure: bool,
+    same_site: SameSite,
+    domain: Option<String>,
+}
+
+impl CookiePolicy {
+    fn from_env() -> Self {
+        let same_site = match std::env::var("SESSION_COOKIE_SAMESITE").as_deref() {
+            Ok("Lax") => SameSite::Lax,
+            Ok("None") => SameSite::None,
+            _ => SameSite::Strict,
+        };
+        let secure = std::env::var("SESSION_COOKIE_SECURE")
+            .map(|v| v != "false")
+            .unwrap_or(true);
+
+        CookiePolicy {
+            // Browsers drop SameSite=None cookies that are not Secure
+            secure: secure || same_site == SameSite::None,
+            same_site,
+            domain: std::env::var("SESSION_COOKIE_DOMAIN").ok(),
+        }
+    }
+
+    fn cookie(
+        &self,
+        name: &'static str,
+        value: String,
+        path: &'static str,
+        max_age: Duration,
+        http_only: bool,
+    ) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name, value)
+            .path(path)
+            .max_age(max_age)
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+/// Adds the session, refresh and CSRF cookies for freshly issued tokens.
+pub fn set_session_cookies(response: &mut HttpResponseBuilder, tokens: &LoginResponse) {
+    let policy = CookiePolicy::from_env();
+    let refresh_max_age = Duration::seconds(refresh_token_ttl().num_seconds());
+
+    response
+        .cookie(policy.cookie(
+            SESSION_COOKIE,
+            tokens.token.clone(),
+            "/",
+            Duration::seconds(tokens.expires_in),
+            true,
+        ))
+        .cookie(policy.cookie(
+            REFRESH_COOKIE,
+            tokens.refresh_token.clone(),
+            REFRESH_COOKIE_PATH,
+            refresh_max_age,
+            true,
+        ))
+        .cookie(policy.cookie(
+            CSRF_COOKIE,
+            tokens.csrf_token.clone(),
+            "/",
+            refresh_max_age,
+            false,
+        ));
+}
+
+/// Expires every session cookie, e.g. on logout.
+pub fn clear_session_cookies(response: &mut HttpResponseBuilder) {
+    let policy = CookiePolicy::from_env();
+
+    for (name, path, http_only) in [
+        (SESSION_COOKIE, "/", true),
+        (REFRESH_COOKIE, REFRESH_COOKIE_PATH, true),
+        (CSRF_COOKIE, "/", false),
+    ] {
+        response.cookie(policy.cookie(name, String::new(), path, Duration::ZERO, http_only));
+    }
+}
+
+/// The refresh token from the body if given, otherwise from the refresh
+/// cookie. Using the cookie requires the double-submitted CSRF token, since
+/// a cross-site request would carry the cookie as well.
+pub fn refresh_token(req: &HttpRequest, from_body: Option<String>) -> Result<String, CustomError> {
+    if let Some(refresh_token) = from_body {
+        return Ok(refresh_token);
+    }
+
+    let cookie = req
+        .cookie(REFRESH_COOKIE)
+        .ok_or_else(|| CustomError::BadRequestError("Refresh token is missing".to_string()))?;
+    let expected = req
+        .cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    check_csrf(req, expected.as_deref())?;
+
+    Ok(cookie.value().to_string())
+}
+
+/// Rejects the request unless its `CSRF_HEADER` matches `expected`.
+pub fn check_csrf(req: &HttpRequest, expected: Option<&str>) -> Result<(), CustomError> {
+    let presented = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    match (presented, expected) {
+        // Comparing digests keeps the comparison time independent of the token
+        (Some(presented), Some(expected))
+            if token::hash_token(presented) == token::hash_token(expected) =>
+        {
+            Ok(())
+        }
+        _ => Err(CustomError::ForbiddenError(
+            "CSRF token is missing or invalid".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,183 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+use crate::utils::token;
+
+// RFC 6238 defaults, which is what authenticator apps expect
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+// Accept one step either side to absorb clock drift
+const SKEW: i64 = 1;
+
+/// Generates a new 160-bit secret, base32 encoded for authenticator apps.
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Checks `code` against the steps around `unix_time` and returns the
+/// matching time step, so callers can refuse to accept the same step twice.
+pub fn verify_code(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = unix_time / PERIOD;
+    (current - SKEW..=current + SKEW).find(|step| code_at(&secret, *step) == code)
+}
+
+/// Builds the `otpauth://` URI that authenticator apps read from a QR code.
+pub fn provisioning_uri(secret: &str, account: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding::encode(issuer),
+        urlencoding::encode(account),
+        secret,
+        urlencoding::encode(issuer),
+        DIGITS,
+        PERIOD
+    )
+}
+
+/// Hashes a recovery code for storage and lookup. Codes are matched however
+/// the user typed the letters.
+pub fn hash_recovery_code(code: &str) -> String {
+    token::hash_token(&code.trim().to_ascii_lowercase())
+}
+
+/// Generates single-use recovery codes such as `4f1c-93ab-0e7d`.
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let mut bytes = [0u8; 6];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let hex = hex::encode(bytes);
+            format!("{}-{}-{}", &hex[0..4], &hex[4..8], &hex[8..12])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret from RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn secret() -> String {
+        BASE32_NOPAD.encode(RFC_SECRET)
+    }
+
+    fn code(step: i64) -> String {
+        format!("{:06}", code_at(RFC_SECRET, step))
+    }
+
+    #[test]
+    fn matches_rfc_6238_test_vectors() {
+        // The RFC lists 8 digit codes; 6 digit codes are their last 6 digits
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+
+        for (unix_time, expected) in vectors {
+            assert_eq!(
+                code_at(RFC_SECRET, unix_time / PERIOD),
+                expected,
+                "T = {}",
+                unix_time
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_either_side() {
+        let now = 1234567890;
+        let current = now / PERIOD;
+
+        for step in [current - 1, current, current + 1] {
+            assert_eq!(verify_code(&secret(), &code(step), now), Some(step));
+        }
+    }
+
+    #[test]
+    fn rejects_codes_outside_the_window() {
+        let now = 1234567890;
+        let current = now / PERIOD;
+
+        assert_eq!(verify_code(&secret(), &code(current - 2), now), None);
+        assert_eq!(verify_code(&secret(), &code(current + 2), now), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let now = 1234567890;
+        let valid = code(now / PERIOD);
+
+        assert_eq!(verify_code(&secret(), &valid[..5], now), None);
+        assert_eq!(verify_code(&secret(), &format!("{}0", valid), now), None);
+        assert_eq!(verify_code(&secret(), "12a456", now), None);
+        assert_eq!(verify_code("not base32!", &valid, now), None);
+        assert!(verify_code(&secret(), &format!(" {} ", valid), now).is_some());
+    }
+
+    #[test]
+    fn recovery_codes_are_unique_and_well_formed() {
+        let codes = generate_recovery_codes(10);
+        assert_eq!(codes.len(), 10);
+
+        for code in &codes {
+            let groups: Vec<&str> = code.split('-').collect();
+            assert_eq!(groups.len(), 3);
+            assert!(groups
+                .iter()
+                .all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_hexdigit())));
+        }
+
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), codes.len());
+    }
+
+    #[test]
+    fn recovery_code_hash_ignores_case_and_whitespace() {
+        let code = &generate_recovery_codes(1)[0];
+
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&format!("  {}\n", code.to_uppercase()))
+        );
+        assert_ne!(
+            hash_recovery_code(code),
+            hash_recovery_code("0000-0000-0000")
+        );
+    }
+}
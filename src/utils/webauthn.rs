@@ -0,0 +1,660 @@
+use ciborium::value::Value;
+use data_encoding::BASE64URL_NOPAD;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::utils::error::CustomError;
+
+/// COSE identifier of ECDSA over P-256 with SHA-256, the only algorithm
+/// offered to authenticators.
+pub const ES256: i64 = -7;
+/// How long a browser may take to complete a ceremony.
+pub const CEREMONY_TIMEOUT_SECS: i64 = 300;
+
+// Authenticator data flags, WebAuthn Level 2 section 6.1
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// COSE key parameters, RFC 8152 section 13
+const COSE_KTY: i64 = 1;
+const COSE_ALG: i64 = 3;
+const COSE_CRV: i64 = -1;
+const COSE_X: i64 = -2;
+const COSE_Y: i64 = -3;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+
+/// The relying party passkeys are bound to.
+///
+/// Configuration:
+/// - `WEBAUTHN_RP_ID`: domain the credentials are scoped to (default
+///   `localhost`)
+/// - `WEBAUTHN_RP_NAME`: name authenticators show (default `SERVICE_NAME`,
+///   then `Rust_API`)
+/// - `WEBAUTHN_ORIGIN`: origin ceremonies have to come from (default
+///   `APP_BASE_URL`)
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn from_env() -> Self {
+        RelyingParty {
+            id: std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string()),
+            name: std::env::var("WEBAUTHN_RP_NAME")
+                .or_else(|_| std::env::var("SERVICE_NAME"))
+                .unwrap_or_else(|_| "Rust_API".to_string()),
+            origin: std::env::var("WEBAUTHN_ORIGIN")
+                .or_else(|_| std::env::var("APP_BASE_URL"))
+                .unwrap_or_else(|_| "http://localhost:5001".to_string()),
+        }
+    }
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.create()`, in
+/// its JSON form with binary fields base64url encoded.
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.get()`.
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+/// A credential that passed registration.
+pub struct NewCredential {
+    pub credential_id: Vec<u8>,
+    // SEC1 encoded P-256 point
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    // Attested credential data and extensions
+    rest: &'a [u8],
+}
+
+fn invalid(reason: &str) -> CustomError {
+    CustomError::BadRequestError(format!("Invalid passkey response: {}", reason))
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    BASE64URL_NOPAD.encode(bytes)
+}
+
+/// Decodes base64url, with or without padding.
+pub fn decode(value: &str) -> Result<Vec<u8>, CustomError> {
+    BASE64URL_NOPAD
+        .decode(value.trim_end_matches('=').as_bytes())
+        .map_err(|_| invalid("malformed base64url"))
+}
+
+/// Generates a random 256-bit challenge, base64url encoded.
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    encode(&challenge)
+}
+
+/// The challenge a ceremony response claims to answer, so the caller can
+/// look up what it stored when the ceremony began. Nothing is verified yet.
+pub fn challenge_of(client_data_json: &str) -> Result<String, CustomError> {
+    let client_data: ClientData = serde_json::from_slice(&decode(client_data_json)?)
+        .map_err(|_| invalid("malformed client data"))?;
+    Ok(client_data.challenge)
+}
+
+/// Options for `navigator.credentials.create()`. Passkeys are discoverable
+/// and require user verification, so they can replace a password outright.
+pub fn creation_options(
+    rp: &RelyingParty,
+    challenge: &str,
+    user_handle: &[u8],
+    name: &str,
+    exclude_credentials: &[String],
+) -> serde_json::Value {
+    serde_json::json!({
+        "publicKey": {
+            "rp": { "id": rp.id, "name": rp.name },
+            "user": {
+                "id": encode(user_handle),
+                "name": name,
+                "displayName": name,
+            },
+            "challenge": challenge,
+            "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+            "timeout": CEREMONY_TIMEOUT_SECS * 1000,
+            "attestation": "none",
+            "authenticatorSelection": {
+                "residentKey": "required",
+                "requireResidentKey": true,
+                "userVerification": "required",
+            },
+            "excludeCredentials": exclude_credentials
+                .iter()
+                .map(|id| serde_json::json!({ "type": "public-key", "id": id }))
+                .collect::<Vec<_>>(),
+        }
+    })
+}
+
+/// Options for `navigator.credentials.get()`. No credentials are listed, so
+/// the authenticator offers whichever passkeys it holds for the site.
+pub fn request_options(rp: &RelyingParty, challenge: &str) -> serde_json::Value {
+    serde_json::json!({
+        "publicKey": {
+            "rpId": rp.id,
+            "challenge": challenge,
+            "timeout": CEREMONY_TIMEOUT_SECS * 1000,
+            "userVerification": "required",
+            "allowCredentials": [],
+        }
+    })
+}
+
+fn check_client_data(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    ceremony: &str,
+    expected_challenge: &str,
+) -> Result<(), CustomError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| invalid("malformed client data"))?;
+
+    if client_data.ceremony != ceremony {
+        return Err(invalid("wrong ceremony type"));
+    }
+    if client_data.challenge != expected_challenge {
+        return Err(invalid("challenge mismatch"));
+    }
+    if client_data.origin != rp.origin {
+        return Err(invalid("origin mismatch"));
+    }
+
+    Ok(())
+}
+
+fn parse_authenticator_data<'a>(
+    rp: &RelyingParty,
+    data: &'a [u8],
+) -> Result<AuthenticatorData<'a>, CustomError> {
+    if data.len() < 37 {
+        return Err(invalid("authenticator data too short"));
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(invalid("relying party mismatch"));
+    }
+
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(invalid("user not present"));
+    }
+    if flags & FLAG_USER_VERIFIED == 0 {
+        return Err(invalid("user not verified"));
+    }
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[37..],
+    })
+}
+
+fn cose_param(map: &[(Value, Value)], label: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(key, _)| matches!(key, Value::Integer(i) if i128::from(*i) == i128::from(label)))
+        .map(|(_, value)| value)
+}
+
+fn cose_int(map: &[(Value, Value)], label: i64) -> Option<i128> {
+    match cose_param(map, label)? {
+        Value::Integer(i) => Some(i128::from(*i)),
+        _ => None,
+    }
+}
+
+fn cose_coordinate(map: &[(Value, Value)], label: i64) -> Result<&[u8], CustomError> {
+    match cose_param(map, label) {
+        Some(Value::Bytes(bytes)) if bytes.len() == 32 => Ok(bytes),
+        _ => Err(invalid("malformed public key")),
+    }
+}
+
+/// Converts an ES256 COSE key into a SEC1 encoded point.
+fn parse_cose_key(cose_key: &[u8]) -> Result<Vec<u8>, CustomError> {
+    // Extensions may follow the key; only the first CBOR item is read
+    let value: Value =
+        ciborium::de::from_reader(cose_key).map_err(|_| invalid("malformed public key"))?;
+    let map = match &value {
+        Value::Map(map) => map,
+        _ => return Err(invalid("malformed public key")),
+    };
+
+    if cose_int(map, COSE_KTY) != Some(COSE_KTY_EC2.into())
+        || cose_int(map, COSE_ALG) != Some(ES256.into())
+        || cose_int(map, COSE_CRV) != Some(COSE_CRV_P256.into())
+    {
+        return Err(invalid("unsupported key algorithm, only ES256 is accepted"));
+    }
+
+    let mut public_key = vec![0x04];
+    public_key.extend_from_slice(cose_coordinate(map, COSE_X)?);
+    public_key.extend_from_slice(cose_coordinate(map, COSE_Y)?);
+    VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| invalid("malformed public key"))?;
+
+    Ok(public_key)
+}
+
+/// Verifies the response to a registration ceremony.
+///
+/// Attestation statements are not checked: `none` attestation is requested,
+/// so the key is trusted as the authenticator presents it.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    expected_challenge: &str,
+    credential: &RegistrationCredential,
+) -> Result<NewCredential, CustomError> {
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    check_client_data(rp, &client_data_json, "webauthn.create", expected_challenge)?;
+
+    let attestation: Value =
+        ciborium::de::from_reader(decode(&credential.response.attestation_object)?.as_slice())
+            .map_err(|_| invalid("malformed attestation object"))?;
+    let auth_data = match &attestation {
+        Value::Map(map) => map.iter().find_map(|(key, value)| match (key, value) {
+            (Value::Text(key), Value::Bytes(bytes)) if key == "authData" => Some(bytes),
+            _ => None,
+        }),
+        _ => None,
+    }
+    .ok_or_else(|| invalid("malformed attestation object"))?;
+
+    let auth_data = parse_authenticator_data(rp, auth_data)?;
+    if auth_data.flags & FLAG_ATTESTED_CREDENTIAL == 0 {
+        return Err(invalid("no credential in authenticator data"));
+    }
+
+    // AAGUID (16 bytes), credential ID length (2 bytes), credential ID, key
+    let attested = auth_data.rest;
+    if attested.len() < 18 {
+        return Err(invalid("authenticator data too short"));
+    }
+    let id_len = u16::from_be_bytes([attested[16], attested[17]]) as usize;
+    if attested.len() < 18 + id_len {
+        return Err(invalid("authenticator data too short"));
+    }
+    let credential_id = attested[18..18 + id_len].to_vec();
+    if decode(&credential.id)? != credential_id {
+        return Err(invalid("credential ID mismatch"));
+    }
+
+    Ok(NewCredential {
+        public_key: parse_cose_key(&attested[18 + id_len..])?,
+        credential_id,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Verifies the response to an authentication ceremony against the stored
+/// key and returns the authenticator's new signature counter.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    expected_challenge: &str,
+    public_key: &[u8],
+    stored_sign_count: u32,
+    credential: &AuthenticationCredential,
+) -> Result<u32, CustomError> {
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    check_client_data(rp, &client_data_json, "webauthn.get", expected_challenge)?;
+
+    let authenticator_data = decode(&credential.response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(rp, &authenticator_data)?;
+
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| invalid("stored key"))?;
+    let signature = Signature::from_der(&decode(&credential.response.signature)?)
+        .map_err(|_| invalid("malformed signature"))?;
+    // Authenticators do not normalize S, which the verifier would reject
+    let signature = signature.normalize_s().unwrap_or(signature);
+
+    let mut signed = authenticator_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    key.verify(&signed, &signature)
+        .map_err(|_| invalid("signature does not verify"))?;
+
+    // A counter that does not move forward suggests a cloned authenticator.
+    // Authenticators that keep no counter always report 0.
+    if (auth_data.sign_count != 0 || stored_sign_count != 0)
+        && auth_data.sign_count <= stored_sign_count
+    {
+        return Err(invalid("signature counter went backwards"));
+    }
+
+    Ok(auth_data.sign_count)
+}
+
+/// Test doubles for the ceremonies, shared with the controller tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+
+    fn cbor(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// An ES256 authenticator in software, answering ceremonies the way a
+    /// browser would hand them to the server.
+    pub(crate) struct SoftAuthenticator {
+        key: SigningKey,
+        pub(crate) credential_id: Vec<u8>,
+        pub(crate) sign_count: u32,
+        // Some authenticators keep no counter and always report 0
+        pub(crate) counts_signatures: bool,
+    }
+
+    impl SoftAuthenticator {
+        pub(crate) fn new() -> Self {
+            SoftAuthenticator {
+                key: SigningKey::from_slice(&[0x42; 32]).unwrap(),
+                credential_id: b"soft-credential".to_vec(),
+                sign_count: 0,
+                counts_signatures: true,
+            }
+        }
+
+        fn client_data(&self, rp: &RelyingParty, ceremony: &str, challenge: &str) -> Vec<u8> {
+            serde_json::json!({
+                "type": ceremony,
+                "challenge": challenge,
+                "origin": rp.origin,
+            })
+            .to_string()
+            .into_bytes()
+        }
+
+        fn authenticator_data(&self, rp: &RelyingParty, flags: u8) -> Vec<u8> {
+            let mut data = Sha256::digest(rp.id.as_bytes()).to_vec();
+            data.push(flags | FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            data
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let int = |i: i64| Value::Integer(i.into());
+            cbor(&Value::Map(vec![
+                (int(COSE_KTY), int(COSE_KTY_EC2)),
+                (int(COSE_ALG), int(ES256)),
+                (int(COSE_CRV), int(COSE_CRV_P256)),
+                (int(COSE_X), Value::Bytes(point.x().unwrap().to_vec())),
+                (int(COSE_Y), Value::Bytes(point.y().unwrap().to_vec())),
+            ]))
+        }
+
+        pub(crate) fn register(
+            &self,
+            rp: &RelyingParty,
+            challenge: &str,
+        ) -> RegistrationCredential {
+            let mut auth_data = self.authenticator_data(rp, FLAG_ATTESTED_CREDENTIAL);
+            auth_data.extend_from_slice(&[0; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            auth_data.extend_from_slice(&self.cose_key());
+
+            let attestation_object = cbor(&Value::Map(vec![
+                (Value::Text("fmt".into()), Value::Text("none".into())),
+                (Value::Text("attStmt".into()), Value::Map(Vec::new())),
+                (Value::Text("authData".into()), Value::Bytes(auth_data)),
+            ]));
+
+            RegistrationCredential {
+                id: encode(&self.credential_id),
+                response: AttestationResponse {
+                    client_data_json: encode(&self.client_data(rp, "webauthn.create", challenge)),
+                    attestation_object: encode(&attestation_object),
+                },
+            }
+        }
+
+        pub(crate) fn assert(
+            &mut self,
+            rp: &RelyingParty,
+            challenge: &str,
+        ) -> AuthenticationCredential {
+            if self.counts_signatures {
+                self.sign_count += 1;
+            }
+            let auth_data = self.authenticator_data(rp, 0);
+            let client_data_json = self.client_data(rp, "webauthn.get", challenge);
+
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data_json));
+            let signature: Signature = self.key.sign(&signed);
+
+            AuthenticationCredential {
+                id: encode(&self.credential_id),
+                response: AssertionResponse {
+                    client_data_json: encode(&client_data_json),
+                    authenticator_data: encode(&auth_data),
+                    signature: encode(signature.to_der().as_bytes()),
+                    user_handle: None,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::SoftAuthenticator;
+    use super::*;
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: "localhost".to_string(),
+            name: "Rust_API".to_string(),
+            origin: "http://localhost:5001".to_string(),
+        }
+    }
+
+    fn assert_rejected<T>(result: Result<T, CustomError>, reason: &str) {
+        match result {
+            Err(CustomError::BadRequestError(message)) => {
+                assert!(message.ends_with(reason), "{}", message)
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("accepted, expected: {}", reason),
+        }
+    }
+
+    #[test]
+    fn registered_passkey_can_log_in() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+
+        let challenge = generate_challenge();
+        let credential = authenticator.register(&rp, &challenge);
+        assert_eq!(
+            challenge_of(&credential.response.client_data_json).unwrap(),
+            challenge
+        );
+        let stored = verify_registration(&rp, &challenge, &credential).unwrap();
+        assert_eq!(stored.credential_id, authenticator.credential_id);
+        assert_eq!(stored.sign_count, 0);
+
+        let challenge = generate_challenge();
+        let assertion = authenticator.assert(&rp, &challenge);
+        let sign_count = verify_assertion(
+            &rp,
+            &challenge,
+            &stored.public_key,
+            stored.sign_count,
+            &assertion,
+        )
+        .unwrap();
+        assert_eq!(sign_count, 1);
+    }
+
+    #[test]
+    fn rejects_responses_to_another_challenge() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+
+        let credential = authenticator.register(&rp, &generate_challenge());
+        assert_rejected(
+            verify_registration(&rp, &generate_challenge(), &credential),
+            "challenge mismatch",
+        );
+
+        let challenge = generate_challenge();
+        let stored =
+            verify_registration(&rp, &challenge, &authenticator.register(&rp, &challenge)).unwrap();
+        let assertion = authenticator.assert(&rp, &generate_challenge());
+        assert_rejected(
+            verify_assertion(
+                &rp,
+                &generate_challenge(),
+                &stored.public_key,
+                0,
+                &assertion,
+            ),
+            "challenge mismatch",
+        );
+    }
+
+    #[test]
+    fn rejects_another_origin_or_relying_party() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+        let challenge = generate_challenge();
+        let stored =
+            verify_registration(&rp, &challenge, &authenticator.register(&rp, &challenge)).unwrap();
+
+        let phishing = RelyingParty {
+            origin: "https://example.net".to_string(),
+            ..relying_party()
+        };
+        let assertion = authenticator.assert(&phishing, &challenge);
+        assert_rejected(
+            verify_assertion(&rp, &challenge, &stored.public_key, 0, &assertion),
+            "origin mismatch",
+        );
+
+        let other_site = RelyingParty {
+            id: "example.net".to_string(),
+            ..relying_party()
+        };
+        let assertion = authenticator.assert(&other_site, &challenge);
+        assert_rejected(
+            verify_assertion(&rp, &challenge, &stored.public_key, 0, &assertion),
+            "relying party mismatch",
+        );
+    }
+
+    #[test]
+    fn rejects_a_replayed_assertion() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+        let challenge = generate_challenge();
+        let stored =
+            verify_registration(&rp, &challenge, &authenticator.register(&rp, &challenge)).unwrap();
+
+        let assertion = authenticator.assert(&rp, &challenge);
+        let sign_count =
+            verify_assertion(&rp, &challenge, &stored.public_key, 0, &assertion).unwrap();
+
+        // Even if the challenge were still outstanding, the counter has moved
+        assert_rejected(
+            verify_assertion(&rp, &challenge, &stored.public_key, sign_count, &assertion),
+            "signature counter went backwards",
+        );
+    }
+
+    #[test]
+    fn rejects_a_signature_counter_that_goes_backwards() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+        let challenge = generate_challenge();
+        let stored =
+            verify_registration(&rp, &challenge, &authenticator.register(&rp, &challenge)).unwrap();
+
+        authenticator.sign_count = 4;
+        let assertion = authenticator.assert(&rp, &challenge);
+        assert_rejected(
+            verify_assertion(&rp, &challenge, &stored.public_key, 7, &assertion),
+            "signature counter went backwards",
+        );
+    }
+
+    #[test]
+    fn accepts_authenticators_without_a_counter() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+        let challenge = generate_challenge();
+        let stored =
+            verify_registration(&rp, &challenge, &authenticator.register(&rp, &challenge)).unwrap();
+
+        authenticator.counts_signatures = false;
+        for _ in 0..2 {
+            let challenge = generate_challenge();
+            let assertion = authenticator.assert(&rp, &challenge);
+            let sign_count =
+                verify_assertion(&rp, &challenge, &stored.public_key, 0, &assertion).unwrap();
+            assert_eq!(sign_count, 0);
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_signature() {
+        let (rp, mut authenticator) = (relying_party(), SoftAuthenticator::new());
+        let challenge = generate_challenge();
+        let stored =
+            verify_registration(&rp, &challenge, &authenticator.register(&rp, &challenge)).unwrap();
+
+        let mut assertion = authenticator.assert(&rp, &challenge);
+        let mut auth_data = decode(&assertion.response.authenticator_data).unwrap();
+        auth_data[36] ^= 0x80;
+        assertion.response.authenticator_data = encode(&auth_data);
+        assert_rejected(
+            verify_assertion(&rp, &challenge, &stored.public_key, 0, &assertion),
+            "signature does not verify",
+        );
+    }
+}